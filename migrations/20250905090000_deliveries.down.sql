@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS deliveries;
+DROP TABLE IF EXISTS delivery_state;
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS delivery_state (
+    type    CHAR(16)    PRIMARY KEY NOT NULL,
+    seq     INTEGER
+);
+
+INSERT OR IGNORE INTO delivery_state(type, seq)
+VALUES  ('Pending', 1),
+        ('Transferred', 2),
+        ('Failed', 3);
+
+-- Every delivery bundle (identified by its id and version in the input server) that was picked up by a fetch
+CREATE TABLE IF NOT EXISTS deliveries (
+    bundle_id       TEXT        NOT NULL,
+    version_id      TEXT        NOT NULL,
+    state           CHAR(16)    NOT NULL DEFAULT ('Pending') REFERENCES delivery_state(type),
+    attempts        INTEGER     NOT NULL DEFAULT 0,
+    last_error      TEXT,
+    -- milliseconds since unix epoch, only set for failed deliveries
+    next_attempt    INTEGER,
+    updated         INTEGER     NOT NULL,
+    PRIMARY KEY (bundle_id, version_id)
+);
+
+CREATE INDEX idx_deliveries_retry ON deliveries (state, next_attempt);
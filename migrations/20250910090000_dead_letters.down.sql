@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS dead_letters;
+DELETE FROM delivery_state WHERE type = 'DeadLettered';
@@ -0,0 +1,16 @@
+INSERT OR IGNORE INTO delivery_state(type, seq)
+VALUES  ('DeadLettered', 4);
+
+-- Deliveries that can't be linked to a data request or patient, kept as received until an operator retries them
+CREATE TABLE IF NOT EXISTS dead_letters (
+    id              CHAR(36)    PRIMARY KEY NOT NULL,
+    bundle_id       TEXT        NOT NULL,
+    version_id      TEXT        NOT NULL,
+    data_request_id CHAR(36),
+    reason          TEXT        NOT NULL,
+    bundle          TEXT        NOT NULL,
+    -- milliseconds since unix epoch
+    created         INTEGER     NOT NULL,
+    updated         INTEGER     NOT NULL,
+    UNIQUE (bundle_id, version_id)
+);
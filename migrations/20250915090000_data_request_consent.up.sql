@@ -0,0 +1,2 @@
+-- Consent documented with a data request (as FHIR JSON), deliveries are checked against it before they are transferred
+ALTER TABLE data_requests ADD COLUMN consent TEXT;
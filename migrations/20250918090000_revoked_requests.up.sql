@@ -0,0 +1,3 @@
+-- Data requests whose consent was withdrawn, deliveries for them are refused
+INSERT OR IGNORE INTO request_status(type, seq)
+VALUES  ('Revoked', 4);
@@ -0,0 +1,3 @@
+-- Data requests whose patient couldn't be identified unambiguously by the ttp and needs manual clarification
+INSERT OR IGNORE INTO request_status(type, seq)
+VALUES  ('ClarificationRequired', 5);
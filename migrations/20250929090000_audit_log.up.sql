@@ -0,0 +1,9 @@
+-- Accesses to identifying data, e.g. resolving project pseudonyms through the ttp
+CREATE TABLE IF NOT EXISTS audit_log (
+    id          INTEGER     PRIMARY KEY AUTOINCREMENT,
+    action      TEXT        NOT NULL,
+    subject     TEXT        NOT NULL,
+    outcome     TEXT        NOT NULL,
+    -- milliseconds since unix epoch
+    created     INTEGER     NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Pseudonyms derived without an external ttp, originals are only kept as SHA-256 digest of the identifying data
+CREATE TABLE IF NOT EXISTS local_pseudonyms (
+    domain      TEXT        NOT NULL,
+    original    CHAR(64)    NOT NULL,
+    pseudonym   TEXT        NOT NULL,
+    -- milliseconds since unix epoch
+    created     INTEGER     NOT NULL,
+    PRIMARY KEY (domain, original),
+    UNIQUE (domain, pseudonym)
+);
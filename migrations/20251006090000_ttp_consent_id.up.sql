@@ -0,0 +1,2 @@
+-- Id the ttp assigned to the consent of a data request, e.g. by Mainzelliste
+ALTER TABLE data_requests ADD COLUMN ttp_consent_id TEXT;
@@ -0,0 +1,2 @@
+-- Project of a data request from the projects file, null for the project configured by the environment
+ALTER TABLE data_requests ADD COLUMN project TEXT;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+UPDATE deliveries SET state = 'Pending' WHERE state = 'Posting';
+DELETE FROM delivery_state WHERE type = 'Posting';
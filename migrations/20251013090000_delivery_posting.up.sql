@@ -0,0 +1,3 @@
+-- Deliveries are Posting while they are sent to the output server, so a transfer interrupted by a crash is retried
+INSERT OR IGNORE INTO delivery_state(type, seq)
+VALUES  ('Posting', 5);
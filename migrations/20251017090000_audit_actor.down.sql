@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE audit_log DROP COLUMN remote_address;
+ALTER TABLE audit_log DROP COLUMN actor;
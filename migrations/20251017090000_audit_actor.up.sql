@@ -0,0 +1,4 @@
+-- Who accessed the identifying data: the credential used (e.g. a fingerprint of the admin api key) and the address
+-- the request came from. Entries recorded before are left without them.
+ALTER TABLE audit_log ADD COLUMN actor TEXT;
+ALTER TABLE audit_log ADD COLUMN remote_address TEXT;
@@ -0,0 +1,162 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use axum::{extract::{ConnectInfo, FromRequestParts, Path, Request, State}, http::{header, request::Parts, HeaderMap}, middleware::Next, response::Response, Json};
+use chrono::Utc;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use subtle::ConstantTimeEq;
+use tracing::{error, info, warn};
+
+use crate::{fetch_data, projects::Project, requests::DataRequest, DicAppState, FetchSummary};
+
+#[derive(Serialize, Deserialize)]
+pub struct ResolvedPseudonym {
+    pub project_id: String,
+    pub exchange_id: String,
+    pub data_requests: Vec<DataRequest>,
+}
+
+// Guards all /admin and /deadletters routes with the admin api key, if one is configured
+pub async fn require_admin_key(
+    State(DicAppState { config, .. }): State<DicAppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, &'static str)> {
+    // compared in constant time, so the key can't be guessed from response times
+    if let Some(admin_api_key) = &config.admin_api_key
+        && !bearer_token(request.headers()).is_some_and(|token| token.as_bytes().ct_eq(admin_api_key.expose().as_bytes()).into()) {
+        warn!("Rejected unauthorized request to {}", request.uri());
+        return Err((StatusCode::UNAUTHORIZED, "Missing or invalid admin api key"));
+    }
+    Ok(next.run(request).await)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// Who accessed identifying data, recorded in the audit log
+pub struct Actor {
+    name: String,
+    remote_address: Option<String>,
+}
+
+impl FromRequestParts<DicAppState> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &DicAppState) -> Result<Self, Self::Rejection> {
+        // the admin api key was checked by require_admin_key, it's named by a fingerprint, as it may be rotated
+        let name = match bearer_token(&parts.headers) {
+            Some(token) if state.config.admin_api_key.is_some() => format!("admin api key {}", &format!("{:x}", Sha256::digest(token))[..8]),
+            _ => "anonymous".to_owned(),
+        };
+        let remote_address = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| address.to_string());
+        Ok(Self { name, remote_address })
+    }
+}
+
+// POST /admin/fetch; Runs a fetch cycle immediately instead of waiting for the next scheduled one
+pub async fn trigger_fetch(
+    State(state): State<DicAppState>
+) -> Result<Json<FetchSummary>, (StatusCode, String)> {
+    info!("Manual fetch of new data requested.");
+    match fetch_data(&state).await {
+        Ok(summary) => {
+            info!("{summary}");
+            Ok(Json(summary))
+        },
+        Err(error) => {
+            warn!("Manual fetch of new data failed: {error:#}");
+            Err((StatusCode::BAD_GATEWAY, format!("Failed to fetch new data: {error:#}")))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PseudonymPath {
+    project_id: String,
+}
+
+// GET /admin/pseudonyms/<project-id>; Resolves a project pseudonym to its exchange id and data requests through the ttp.
+// Every attempt is recorded in the audit log.
+pub async fn resolve_pseudonym(
+    State(DicAppState { database_pool, config, .. }): State<DicAppState>,
+    project: &'static Project,
+    actor: Actor,
+    Path(PseudonymPath { project_id }): Path<PseudonymPath>,
+) -> Result<Json<ResolvedPseudonym>, (StatusCode, &'static str)> {
+    if config.admin_api_key.is_none() {
+        return Err((StatusCode::FORBIDDEN, "Resolving pseudonyms requires an admin api key to be configured"));
+    }
+    let (Some(ttp), Some(domain)) = (&config.ttp, &project.domain) else {
+        return Err((StatusCode::NOT_FOUND, "No ttp configured to resolve pseudonyms with"));
+    };
+    // pseudonyms of other projects than the default one are audited with their project
+    let subject = match &project.name {
+        Some(name) => format!("{name}/{project_id}"),
+        None => project_id.clone(),
+    };
+    let exchange_id = match ttp.resolve_pseudonym(&project_id, domain, &config.exchange_id_system).await {
+        Ok(exchange_id) => exchange_id,
+        Err(e) => {
+            warn!("Unable to resolve pseudonym through ttp: {e:#}");
+            audit("resolve_pseudonym", &subject, "failed", &actor, &database_pool).await?;
+            return Err((StatusCode::BAD_GATEWAY, "Unable to resolve pseudonym through ttp"));
+        }
+    };
+    let Some(exchange_id) = exchange_id else {
+        audit("resolve_pseudonym", &subject, "unknown", &actor, &database_pool).await?;
+        return Err((StatusCode::NOT_FOUND, "Pseudonym is unknown to the ttp"));
+    };
+    let data_requests = sqlx::query_as!(
+        DataRequest,
+        r#"SELECT id, status as "status: _", message, exchange_id, project_id FROM data_requests WHERE exchange_id = $1 AND project IS $2;"#,
+        exchange_id, project.name
+    ).fetch_all(&database_pool).await.map_err(|e| {
+        error!("Unable to fetch data requests from database: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to fetch data requests from database!")
+    })?;
+    audit("resolve_pseudonym", &subject, "resolved", &actor, &database_pool).await?;
+    Ok(Json(ResolvedPseudonym { project_id, exchange_id, data_requests }))
+}
+
+// Records the access in the audit log, the access is refused if it can't be recorded
+async fn audit(action: &str, subject: &str, outcome: &str, actor: &Actor, database_pool: &Pool<Sqlite>) -> Result<(), (StatusCode, &'static str)> {
+    let Actor { name, remote_address } = actor;
+    info!(target: "audit", action, subject, outcome, actor = name, remote_address);
+    let now = Utc::now().timestamp_millis();
+    sqlx::query!(
+        "INSERT INTO audit_log (action, subject, outcome, actor, remote_address, created) VALUES ($1, $2, $3, $4, $5, $6)",
+        action, subject, outcome, name, remote_address, now
+    ).execute(database_pool).await.map_err(|e| {
+        error!("Unable to write audit log: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to write audit log")
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use crate::{app, stub_server, DicAppState, CLIENT};
+
+    #[tokio::test]
+    async fn resolving_pseudonyms_is_audited_with_actor() {
+        let state = DicAppState::for_tests(&["--admin-api-key", "admin-key", "local", "--project-id-system", "PROJECT_1_ID", "--ttp-local-key", "hmac-key"]).await;
+        let url = stub_server(app(state.clone())).await;
+        let response = CLIENT.get(format!("{url}/admin/pseudonyms/unknown")).bearer_auth("admin-key").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let entry = sqlx::query!("SELECT subject, outcome, actor, remote_address FROM audit_log").fetch_one(&state.database_pool).await.unwrap();
+        assert_eq!((entry.subject.as_str(), entry.outcome.as_str()), ("unknown", "unknown"));
+        // first digits of the sha-256 digest of "admin-key"
+        assert_eq!(entry.actor.as_deref(), Some("admin api key 69a52655"));
+        assert!(entry.remote_address.is_some_and(|address| address.starts_with("127.0.0.1:")));
+    }
+}
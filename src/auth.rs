@@ -0,0 +1,410 @@
+//! Authentication and authorization of clients of the /requests api
+use std::{path::PathBuf, str::FromStr, time::{Duration, Instant}};
+
+use anyhow::{anyhow, bail, Context};
+use axum::{extract::FromRequestParts, http::{header, request::Parts, HeaderMap}};
+use jsonwebtoken::{jwk::{Jwk, JwkSet}, Algorithm, DecodingKey, Validation};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+
+use crate::{config::Secret, projects::Project, DicAppState, CLIENT};
+
+const API_KEY_HEADER: &str = "x-api-key";
+// keys of an issuer are fetched again for unknown key ids, but not more often than this
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// and not more often than this while they can't be loaded
+const JWKS_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    Create,
+    Read,
+    Withdraw,
+}
+
+impl Scope {
+    const ALL: [Scope; 3] = [Scope::Create, Scope::Read, Scope::Withdraw];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Create => "requests.create",
+            Scope::Read => "requests.read",
+            Scope::Withdraw => "requests.withdraw",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| anyhow!("Unknown scope '{s}', expected one of requests.create, requests.read, requests.withdraw"))
+    }
+}
+
+// A scope, restricted to one project in the form "<project>/<scope>", e.g. "biobank/requests.read". Scopes without a
+// project apply to all projects, the project configured by the environment is named "default".
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectScope {
+    project: Option<String>,
+    scope: Scope,
+}
+
+impl FromStr for ProjectScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (project, scope) = match s.split_once('/') {
+            Some((project, scope)) => (Some(project.to_owned()), scope),
+            None => (None, s),
+        };
+        Ok(Self { project, scope: scope.parse()? })
+    }
+}
+
+// A client with its scopes in the form "<client>:<scope>+<scope>", e.g. "<api-key>:requests.create+biobank/requests.read"
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grant<C = String> {
+    client: C,
+    scopes: Vec<ProjectScope>,
+}
+
+impl<C: FromStr<Err: Into<anyhow::Error>>> FromStr for Grant<C> {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((client, scopes)) = s.rsplit_once(':') else {
+            bail!("Grants should be in the form of '<client>:<scope>+<scope>'");
+        };
+        if client.is_empty() {
+            bail!("Grants need a client before the scopes");
+        }
+        let scopes = scopes.split('+').map(str::parse).collect::<Result<_, _>>()?;
+        Ok(Self { client: client.parse().map_err(Into::into)?, scopes })
+    }
+}
+
+// Clients are authenticated by any of the configured methods. Without any, the api is open to everyone who can reach it.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct AuthConfig {
+    // Api keys sent in the X-API-Key header with their scopes
+    #[clap(long, env, value_delimiter = ',')]
+    pub api_keys: Vec<Grant<Secret>>,
+    // Issuer of OIDC bearer tokens, its keys are discovered via /.well-known/openid-configuration
+    #[clap(long, env)]
+    pub oidc_issuer: Option<String>,
+    // JWKS file with the keys bearer tokens are signed with, instead of discovering them from the issuer
+    #[clap(long, env)]
+    pub oidc_jwks_file: Option<PathBuf>,
+    // Audience bearer tokens must be issued for
+    #[clap(long, env)]
+    pub oidc_audience: Option<String>,
+    // Header in which the TLS terminating proxy forwards the subject of the verified client certificate.
+    // It must not be set by clients, so transFAIR has to be reachable through the proxy only.
+    #[clap(long, env)]
+    pub mtls_subject_header: Option<String>,
+    // Subjects of client certificates with their scopes, separated by ';' as subjects contain commas
+    #[clap(long, env, value_delimiter = ';')]
+    pub mtls_clients: Vec<Grant>,
+}
+
+impl AuthConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || self.oidc_enabled() || self.mtls_subject_header.is_some()
+    }
+
+    fn oidc_enabled(&self) -> bool {
+        self.oidc_issuer.is_some() || self.oidc_jwks_file.is_some()
+    }
+}
+
+// The authenticated client of a request
+#[derive(Debug)]
+pub struct Principal {
+    pub name: String,
+    scopes: Vec<ProjectScope>,
+}
+
+impl Principal {
+    // The scope has to be granted for all projects or the project of the request
+    pub fn require(&self, scope: Scope, project: &Project) -> Result<(), (StatusCode, &'static str)> {
+        let project = project.to_string();
+        let granted = |granted: &ProjectScope| granted.scope == scope && granted.project.as_ref().is_none_or(|name| *name == project);
+        if !self.scopes.iter().any(granted) {
+            warn!("{} is missing scope {} of project {project} for the request", self.name, scope.as_str());
+            return Err((StatusCode::FORBIDDEN, "Missing scope for this request"));
+        }
+        Ok(())
+    }
+}
+
+impl FromRequestParts<DicAppState> for Principal {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &DicAppState) -> Result<Self, Self::Rejection> {
+        state.authenticator.authenticate(&parts.headers).await
+    }
+}
+
+#[derive(Debug)]
+pub struct Authenticator {
+    config: &'static AuthConfig,
+    // keys of the OIDC issuer and when they may be loaded again
+    jwks: RwLock<Option<(JwkSet, Instant)>>,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    #[serde(default)]
+    scope: String,
+    // some issuers list scopes as array
+    #[serde(default)]
+    scp: Value,
+}
+
+impl Authenticator {
+    pub fn new(config: &'static AuthConfig) -> Self {
+        Self { config, jwks: RwLock::default() }
+    }
+
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, (StatusCode, &'static str)> {
+        const UNAUTHORIZED: (StatusCode, &str) = (StatusCode::UNAUTHORIZED, "Missing or invalid credentials");
+        if !self.config.is_enabled() {
+            let scopes = Scope::ALL.into_iter().map(|scope| ProjectScope { project: None, scope }).collect();
+            return Ok(Principal { name: "anonymous".into(), scopes });
+        }
+        if let Some(api_key) = headers.get(API_KEY_HEADER) {
+            // api keys are secrets, so clients are named by the position of their key and keys are compared in constant time
+            let position = self.config.api_keys.iter().position(|grant| api_key.as_bytes().ct_eq(grant.client.expose().as_bytes()).into());
+            return match position {
+                Some(position) => Ok(Principal { name: format!("api key {}", position + 1), scopes: self.config.api_keys[position].scopes.clone() }),
+                None => {
+                    warn!("Rejected request with unknown api key");
+                    Err(UNAUTHORIZED)
+                }
+            };
+        }
+        if let Some(subject) = self.config.mtls_subject_header.as_ref().and_then(|name| headers.get(name)).and_then(|subject| subject.to_str().ok()) {
+            return match self.config.mtls_clients.iter().find(|grant| grant.client == subject) {
+                Some(grant) => Ok(Principal { name: subject.to_owned(), scopes: grant.scopes.clone() }),
+                None => {
+                    warn!("Rejected request with unknown client certificate {subject}");
+                    Err(UNAUTHORIZED)
+                }
+            };
+        }
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let (Some(token), true) = (bearer, self.config.oidc_enabled()) {
+            return self.validate_token(token).await.map_err(|e| {
+                warn!("Rejected bearer token: {e:#}");
+                UNAUTHORIZED
+            });
+        }
+        Err(UNAUTHORIZED)
+    }
+
+    async fn validate_token(&self, token: &str) -> anyhow::Result<Principal> {
+        let header = jsonwebtoken::decode_header(token)?;
+        // only asymmetric algorithms, the keys are public
+        use Algorithm::*;
+        if !matches!(header.alg, RS256 | RS384 | RS512 | PS256 | PS384 | PS512 | ES256 | ES384 | EdDSA) {
+            bail!("Tokens signed with {:?} are not accepted", header.alg);
+        }
+        let jwk = self.find_key(header.kid.as_deref()).await?;
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.config.oidc_issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.config.oidc_audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims = jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_jwk(&jwk)?, &validation)?.claims;
+        let scopes = claims.scope
+            .split_whitespace()
+            .chain(claims.scp.as_array().into_iter().flatten().filter_map(Value::as_str))
+            .filter_map(|scope| scope.parse().ok())
+            .collect();
+        Ok(Principal { name: claims.sub.unwrap_or_else(|| "token without subject".into()), scopes })
+    }
+
+    async fn find_key(&self, kid: Option<&str>) -> anyhow::Result<Jwk> {
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+        if let Some((jwks, refresh_at)) = &*self.jwks.read().await {
+            if let Some(jwk) = find(jwks) {
+                return Ok(jwk);
+            }
+            if Instant::now() < *refresh_at {
+                bail!("No key {kid:?} to validate the token with");
+            }
+        }
+        // the issuer may have rotated its keys
+        let mut cached = self.jwks.write().await;
+        // requests waiting for the lock find the keys another one loaded meanwhile
+        if let Some((jwks, refresh_at)) = &*cached
+            && Instant::now() < *refresh_at {
+            return find(jwks).ok_or_else(|| anyhow!("No key {kid:?} to validate the token with"));
+        }
+        match self.load_jwks().await {
+            Ok(jwks) => {
+                let jwk = find(&jwks);
+                *cached = Some((jwks, Instant::now() + JWKS_REFRESH_INTERVAL));
+                jwk.ok_or_else(|| anyhow!("No key {kid:?} to validate the token with"))
+            }
+            Err(error) => {
+                // an unavailable issuer is not asked again for every token, the known keys are kept
+                let jwks = cached.take().map_or_else(|| JwkSet { keys: Vec::new() }, |(jwks, _)| jwks);
+                *cached = Some((jwks, Instant::now() + JWKS_RETRY_INTERVAL));
+                Err(error.context("Unable to load the keys of the OIDC issuer"))
+            }
+        }
+    }
+
+    pub async fn load_jwks(&self) -> anyhow::Result<JwkSet> {
+        if let Some(path) = &self.config.oidc_jwks_file {
+            let jwks = tokio::fs::read_to_string(path).await.with_context(|| format!("Unable to read {}", path.display()))?;
+            return Ok(serde_json::from_str(&jwks)?);
+        }
+        let Some(issuer) = &self.config.oidc_issuer else {
+            bail!("Neither an OIDC issuer nor a JWKS file is configured");
+        };
+        let discovery_url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+        debug!("Discovering keys of OIDC issuer via {discovery_url}");
+        let discovery: Value = CLIENT.get(discovery_url).send().await?.error_for_status()?.json().await?;
+        let Some(jwks_uri) = discovery.get("jwks_uri").and_then(Value::as_str) else {
+            bail!("OIDC configuration of {issuer} has no jwks_uri");
+        };
+        Ok(CLIENT.get(jwks_uri).send().await?.error_for_status()?.json().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use reqwest::StatusCode;
+    use serde_json::json;
+
+    use crate::{config::Auth, fhir::FhirServer, projects::Project};
+
+    use super::{AuthConfig, Authenticator, Grant, ProjectScope, Scope};
+
+    fn authenticator(config: AuthConfig) -> Authenticator {
+        Authenticator::new(Box::leak(Box::new(config)))
+    }
+
+    fn project(name: Option<&str>) -> Project {
+        Project {
+            name: name.map(Into::into),
+            domain: None,
+            output_server: FhirServer::new("http://localhost:8095".parse().unwrap(), Auth::None),
+            profile: "fhircopy".parse().unwrap(),
+            consent_required: false,
+            consent_purpose: None,
+        }
+    }
+
+    fn token(audience: &str) -> String {
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 300;
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("transfair-test".into());
+        let claims = json!({"sub": "project-portal", "aud": audience, "exp": exp, "scope": "openid requests.read"});
+        let key = EncodingKey::from_rsa_pem(include_bytes!("testdata/oidc_signing_key.pem")).unwrap();
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn parse_grants() {
+        let grant: Grant = "CN=dic,O=site:requests.create+biobank/requests.read".parse().unwrap();
+        let scopes = vec![
+            ProjectScope { project: None, scope: Scope::Create },
+            ProjectScope { project: Some("biobank".into()), scope: Scope::Read },
+        ];
+        assert_eq!(grant, Grant { client: "CN=dic,O=site".into(), scopes });
+        assert!("key:biobank/requests.delete".parse::<Grant>().is_err());
+        assert!("key:requests.delete".parse::<Grant>().is_err());
+        assert!("requests.read".parse::<Grant>().is_err());
+    }
+
+    #[tokio::test]
+    async fn api_keys_grant_their_scopes() {
+        let authenticator = authenticator(AuthConfig {
+            api_keys: vec!["first:requests.create".parse().unwrap(), "second:requests.read".parse().unwrap()],
+            ..Default::default()
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("second"));
+        let principal = authenticator.authenticate(&headers).await.unwrap();
+        assert_eq!(principal.name, "api key 2");
+        assert!(principal.require(Scope::Read, &project(None)).is_ok());
+        assert_eq!(principal.require(Scope::Create, &project(None)).unwrap_err().0, StatusCode::FORBIDDEN);
+
+        headers.insert("x-api-key", HeaderValue::from_static("third"));
+        assert_eq!(authenticator.authenticate(&headers).await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        assert_eq!(authenticator.authenticate(&HeaderMap::new()).await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn project_scopes_are_restricted_to_their_project() {
+        let authenticator = authenticator(AuthConfig {
+            api_keys: vec!["key:biobank/requests.read+default/requests.create".parse().unwrap()],
+            ..Default::default()
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("key"));
+        let principal = authenticator.authenticate(&headers).await.unwrap();
+        assert!(principal.require(Scope::Read, &project(Some("biobank"))).is_ok());
+        assert_eq!(principal.require(Scope::Read, &project(Some("registry"))).unwrap_err().0, StatusCode::FORBIDDEN);
+        assert_eq!(principal.require(Scope::Read, &project(None)).unwrap_err().0, StatusCode::FORBIDDEN);
+        assert!(principal.require(Scope::Create, &project(None)).is_ok());
+        assert!(principal.require(Scope::Create, &project(Some("biobank"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn bearer_tokens_are_validated_against_jwks() {
+        let authenticator = authenticator(AuthConfig {
+            oidc_jwks_file: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/oidc_jwks.json").into()),
+            oidc_audience: Some("transfair".into()),
+            ..Default::default()
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token("transfair")).parse().unwrap());
+        let principal = authenticator.authenticate(&headers).await.unwrap();
+        assert_eq!(principal.name, "project-portal");
+        assert!(principal.require(Scope::Read, &project(None)).is_ok());
+        assert!(principal.require(Scope::Create, &project(None)).is_err());
+
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token("other")).parse().unwrap());
+        assert_eq!(authenticator.authenticate(&headers).await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn failed_key_loads_are_not_retried_for_every_token() {
+        let path = std::env::temp_dir().join(format!("transfair-jwks-{}.json", std::process::id()));
+        let authenticator = authenticator(AuthConfig { oidc_jwks_file: Some(path.clone()), ..Default::default() });
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token("transfair")).parse().unwrap());
+        assert_eq!(authenticator.authenticate(&headers).await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        // the keys are only loaded again once the retry interval passed
+        std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/oidc_jwks.json"), &path).unwrap();
+        assert_eq!(authenticator.authenticate(&headers).await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+//! `transfair config check`: validates the options of the dic subcommand without starting it and prints them without
+//! their secrets. With --online, the database, fhir servers, OAuth token endpoints and the ttp are probed as well.
+use std::{any::TypeId, collections::BTreeSet, fmt::Display, process::ExitCode};
+
+use anyhow::anyhow;
+use clap::{parser::ValueSource, Arg, ArgMatches, Command};
+use sqlx::SqlitePool;
+
+use crate::{auth::{Authenticator, Grant}, config::{request_token, Auth, DicConfig, Secret}, fhir::FhirServer, projects::Projects, ttp::Ttp};
+
+const SECRET: &str = "***";
+
+#[derive(Debug, clap::Args)]
+pub struct CheckArgs {
+    /// Probe the database, fhir servers, OAuth token endpoints and the ttp as well
+    #[clap(long, default_value_t = false)]
+    pub online: bool,
+    #[clap(flatten)]
+    pub config: DicConfig,
+    // Options in effect, set once the command line is parsed
+    #[clap(skip)]
+    pub options: Vec<EffectiveOption>,
+}
+
+// Value of an option, named by its environment variable, and where it was taken from
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectiveOption {
+    pub variable: String,
+    pub value: String,
+    pub source: &'static str,
+}
+
+// Options of the parsed command line and its subcommands, from_file are the variables whose defaults the config file set
+pub fn effective_options(command: &Command, matches: &ArgMatches, from_file: &[String]) -> Vec<EffectiveOption> {
+    let mut options = Vec::new();
+    collect_options(command, matches, from_file, &mut options);
+    options
+}
+
+fn collect_options(command: &Command, matches: &ArgMatches, from_file: &[String], options: &mut Vec<EffectiveOption>) {
+    for arg in command.get_arguments() {
+        let Some(variable) = arg.get_env().map(|env| env.to_string_lossy().into_owned()) else {
+            continue;
+        };
+        let id = arg.get_id().as_str();
+        let (Some(values), Some(source)) = (matches.get_raw(id), matches.value_source(id)) else {
+            continue;
+        };
+        let source = match source {
+            ValueSource::CommandLine => "command line",
+            ValueSource::EnvVariable => "environment",
+            ValueSource::DefaultValue if from_file.contains(&variable) => "config file",
+            _ => "default",
+        };
+        let delimiter = arg.get_value_delimiter().unwrap_or(',').to_string();
+        let value = values.map(|value| redact(arg, &value.to_string_lossy())).collect::<Vec<_>>().join(&delimiter);
+        if value.is_empty() {
+            continue;
+        }
+        options.push(EffectiveOption { variable, value, source });
+    }
+    if let Some((name, matches)) = matches.subcommand()
+        && let Some(command) = command.find_subcommand(name) {
+        collect_options(command, matches, from_file, options);
+    }
+}
+
+// Secrets are recognized by the type their option is parsed to, so new options of these types are redacted as well.
+// They are replaced by asterisks, credentials keep what identifies them and references to files their path.
+fn redact(arg: &Arg, value: &str) -> String {
+    let secret = |value: &str| value.parse::<Secret>().map_or_else(|_| SECRET.into(), |secret| secret.to_string());
+    let value_type = arg.get_value_parser().type_id();
+    if value_type == TypeId::of::<Auth>() {
+        value.parse::<Auth>().map_or_else(|_| SECRET.into(), |auth| auth.to_string())
+    } else if value_type == TypeId::of::<Secret>() {
+        secret(value)
+    } else if value_type == TypeId::of::<Grant<Secret>>() {
+        // api keys are the clients of their grants
+        match value.rsplit_once(':') {
+            Some((key, scopes)) => format!("{}:{scopes}", secret(key)),
+            None => SECRET.into(),
+        }
+    } else {
+        value.into()
+    }
+}
+
+// Results of the checks, printed as they are done
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn pass(&mut self, check: impl Display, detail: impl Display) {
+        println!("[ OK ] {check}: {detail}");
+    }
+
+    fn fail(&mut self, check: impl Display, error: anyhow::Error) {
+        self.failures += 1;
+        println!("[FAIL] {check}: {error:#}");
+    }
+
+    fn record(&mut self, check: impl Display, result: anyhow::Result<String>) {
+        match result {
+            Ok(detail) => self.pass(check, detail),
+            Err(error) => self.fail(check, error),
+        }
+    }
+
+    fn finish(self) -> ExitCode {
+        println!();
+        if self.failures == 0 {
+            println!("Configuration is valid");
+            ExitCode::SUCCESS
+        } else {
+            println!("{} checks failed", self.failures);
+            ExitCode::from(1)
+        }
+    }
+}
+
+pub async fn check_main(check: CheckArgs) -> ExitCode {
+    let CheckArgs { online, config, options } = check;
+    let config: &'static DicConfig = Box::leak(Box::new(config));
+    println!("Options in effect:");
+    let width = options.iter().map(|option| option.variable.len()).max().unwrap_or_default();
+    for EffectiveOption { variable, value, source } in &options {
+        println!("  {variable:width$}  {value}  ({source})");
+    }
+    println!();
+    println!("Checks:");
+    let mut report = Report::default();
+
+    let projects = match Projects::load(config) {
+        Ok(projects) => {
+            report.pass("projects", projects.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "));
+            Some(projects)
+        }
+        Err(error) => {
+            report.fail("projects", error);
+            None
+        }
+    };
+    if !config.auth.is_enabled() {
+        println!("[WARN] authentication: none configured, everyone who can reach transFAIR may create and read data requests");
+    }
+    if config.admin_api_key.is_none() {
+        println!("[WARN] admin api key: none configured, everyone who can reach transFAIR may trigger fetches and read and retry dead letters");
+    }
+    // the keys of an issuer are discovered online
+    if config.auth.oidc_jwks_file.is_some() || (online && config.auth.oidc_issuer.is_some()) {
+        let jwks = Authenticator::new(&config.auth).load_jwks().await;
+        report.record("oidc keys", jwks.map(|jwks| format!("{} keys", jwks.keys.len())));
+    }
+    if !online {
+        return report.finish();
+    }
+
+    let database = SqlitePool::connect(config.database_url.as_str()).await;
+    match &database {
+        Ok(_) => report.pass(format_args!("database {}", config.database_url), "connected"),
+        Err(error) => report.fail(format_args!("database {}", config.database_url), anyhow!("{error}")),
+    }
+
+    let mut servers = vec![
+        ("request server".to_owned(), FhirServer::new(config.fhir_request_url.clone(), config.fhir_request_credentials.clone())),
+        ("input server".to_owned(), FhirServer::new(config.fhir_input_url.clone(), config.fhir_input_credentials.clone())),
+    ];
+    match &projects {
+        Some(projects) => servers.extend(projects.iter().map(|project| (format!("output server of project {project}"), project.output_server.clone()))),
+        None => servers.push(("output server".to_owned(), FhirServer::new(config.fhir_output_url.clone(), config.fhir_output_credentials.clone()))),
+    }
+    // token endpoints are checked first, the fhir servers can't be reached without their tokens
+    let credentials = servers.iter().map(|(_, server)| server.auth()).chain(config.ttp.as_ref().and_then(Ttp::auth));
+    let mut checked = BTreeSet::new();
+    for auth in credentials {
+        if let Auth::Oauth { client_id, client_secret, token_url } = auth
+            && checked.insert((client_id, token_url)) {
+            let token = request_token(client_id, &client_secret.expose(), token_url).await;
+            report.record(format_args!("OAuth token endpoint {token_url}"), token.map(|_| format!("token issued to {client_id}")).map_err(Into::into));
+        }
+    }
+    for (name, server) in &servers {
+        let version = server.check_metadata().await;
+        report.record(format_args!("fhir {name} {}", server.url), version.map(|version| format!("FHIR {version}")));
+    }
+
+    if let Some(ttp) = &config.ttp {
+        if let Ok(database) = &database {
+            ttp.use_database(database);
+        }
+        if !(ttp.check_availability().await) {
+            report.fail(format_args!("ttp {ttp}"), anyhow!("not available, see the log for details"));
+            return report.finish();
+        }
+        report.pass(format_args!("ttp {ttp}"), "available");
+        let project_id_systems = projects.iter().flat_map(Projects::iter).filter_map(|project| project.domain.as_ref()).map(|domain| domain.project_id_system.as_str());
+        for idtype in std::iter::once(config.exchange_id_system.as_str()).chain(project_id_systems) {
+            let available = match ttp.check_idtype_available(idtype).await {
+                true => Ok("available".to_owned()),
+                false => Err(anyhow!("not available")),
+            };
+            report.record(format_args!("id type {idtype} at the ttp"), available);
+        }
+    }
+    report.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{any::TypeId, collections::BTreeSet};
+
+    use clap::CommandFactory;
+
+    use crate::{auth::Grant, config::{file, Auth, CliArgs, Secret}};
+
+    use super::{effective_options, redact, EffectiveOption};
+
+    #[test]
+    fn options_are_printed_without_secrets() {
+        let from_file = [
+            ("PROFILE".to_owned(), "mii2bbmri".to_owned()),
+            ("FHIR_OUTPUT_URL".to_owned(), "http://file:8080".to_owned()),
+            ("TLS_DISABLE".to_owned(), "true".to_owned()),
+            ("TTP_LOCAL_KEY".to_owned(), "hmac-key".to_owned()),
+        ];
+        let command = file::with_defaults(CliArgs::command(), &from_file);
+        let matches = command.clone().get_matches_from([
+            "transfair", "config", "check",
+            "--database-url", "sqlite://transfair.db",
+            "--fhir-request-url", "http://request:8080",
+            "--fhir-input-url", "http://input:8080",
+            "--fhir-output-url", "http://output:8080",
+            "--fhir-output-credentials", "OAuth transfair secret https://auth/token",
+            "--api-keys", "first-key:requests.create,second-key:requests.read",
+            "local", "--project-id-system", "PROJECT_1_ID",
+        ]);
+        let options = effective_options(&command, &matches, &from_file.map(|(variable, _)| variable));
+        let option = |name: &str| options.iter().find(|option| option.variable == name).cloned();
+        assert_eq!(option("FHIR_OUTPUT_CREDENTIALS").unwrap().value, "OAuth transfair *** https://auth/token");
+        assert_eq!(option("API_KEYS").unwrap().value, "***:requests.create,***:requests.read");
+        // required options may be given in the config file only
+        assert_eq!(option("TTP_LOCAL_KEY"), Some(EffectiveOption { variable: "TTP_LOCAL_KEY".into(), value: "***".into(), source: "config file" }));
+        assert_eq!(option("FETCH_INTERVAL"), Some(EffectiveOption { variable: "FETCH_INTERVAL".into(), value: "60".into(), source: "default" }));
+        assert_eq!(option("PROJECT_ID_SYSTEM").unwrap().source, "command line");
+        assert_eq!(option("PROFILE"), Some(EffectiveOption { variable: "PROFILE".into(), value: "mii2bbmri".into(), source: "config file" }));
+        // the command line and the environment take precedence over the config file
+        assert_eq!(option("FHIR_OUTPUT_URL").unwrap().source, "command line");
+        assert!(matches.get_flag("tls_disable"));
+        assert_eq!(option("ADMIN_API_KEY"), None);
+    }
+
+    // Every option taking a secret is redacted, wherever it is declared
+    #[test]
+    fn all_secret_options_are_redacted() {
+        // values of the types holding secrets, whose secret is "plain-secret"
+        let samples = [
+            (TypeId::of::<Secret>(), "plain-secret"),
+            (TypeId::of::<Auth>(), "transfair:plain-secret"),
+            (TypeId::of::<Grant<Secret>>(), "plain-secret:requests.read"),
+        ];
+        let mut redacted = BTreeSet::new();
+        let mut commands = vec![CliArgs::command()];
+        while let Some(command) = commands.pop() {
+            for arg in command.get_arguments() {
+                let value_type = arg.get_value_parser().type_id();
+                let Some((_, sample)) = samples.iter().find(|(secret_type, _)| value_type == *secret_type) else {
+                    continue;
+                };
+                let variable = arg.get_env().unwrap().to_string_lossy().into_owned();
+                assert!(!redact(arg, sample).contains("plain-secret"), "{variable} is printed with its secret");
+                redacted.insert(variable);
+            }
+            commands.extend(command.get_subcommands().cloned());
+        }
+        assert_eq!(redacted.into_iter().collect::<Vec<_>>(), [
+            "ADMIN_API_KEY", "API_KEYS", "FHIR_INPUT_CREDENTIALS", "FHIR_OUTPUT_CREDENTIALS", "FHIR_REQUEST_CREDENTIALS",
+            "TTP_AUTH", "TTP_LOCAL_KEY", "TTP_ML_API_KEY",
+        ]);
+    }
+}
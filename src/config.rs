@@ -1,12 +1,19 @@
-use std::{collections::HashMap, fs, path::PathBuf, str::FromStr, sync::LazyLock, time::{Duration, Instant}};
+use std::{collections::HashMap, ffi::OsString, fmt::Display, fs, path::PathBuf, str::FromStr, sync::LazyLock, time::{Duration, Instant}};
 
-use clap::Parser;
+use chrono::{DateTime, Utc};
+use clap::{error::ErrorKind, CommandFactory, FromArgMatches, Parser};
+use croner::Cron;
 use reqwest::{Certificate, Client, Url};
 use anyhow::anyhow;
 use tokio::sync::RwLock;
 use tracing::info;
 
-use crate::{ttp::Ttp, CLIENT};
+pub(crate) mod file;
+mod secret;
+
+pub use secret::Secret;
+
+use crate::{auth::AuthConfig, check::{self, CheckArgs}, filter::{CodeFilter, ResourceFilter}, transformation::Profile, ttp::Ttp, CLIENT};
 
 #[derive(Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -20,9 +27,46 @@ pub struct CliArgs {
     /// Disable TLS verification
     #[clap(long, env, default_value_t = false)]
     pub tls_disable: bool,
+    /// Config file (TOML or YAML) for all options given neither on the command line nor in the environment
+    #[clap(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
 }
 
 impl CliArgs {
+    // Options are taken from the command line, the environment, the config file and their defaults, in this order.
+    pub fn parse_with_config_file(args: impl IntoIterator<Item = impl Into<OsString>>) -> Self {
+        let mut args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+        let mut command = Self::command();
+        let matches = command.clone().ignore_errors(true).get_matches_from(&args);
+        let mut from_file = Vec::new();
+        if let Some(path) = matches.get_one::<PathBuf>("config") {
+            let config_file = file::load(path, &command).unwrap_or_else(|e| command.error(ErrorKind::InvalidValue, format!("{e:#}")).exit());
+            // the options of the config file replace the defaults, the command line and environment take precedence
+            command = file::with_defaults(command, &config_file.variables);
+            from_file.extend(config_file.variables.into_iter().map(|(variable, _)| variable));
+            // the subcommands can't be set as defaults, so they are added to the command line
+            let options = match matches.subcommand() {
+                Some(("dic", dic)) => Some(dic),
+                Some(("config", config)) => config.subcommand_matches("check"),
+                Some(_) => None,
+                None => {
+                    args.push("dic".into());
+                    None
+                }
+            };
+            if let Some(ttp) = config_file.ttp
+                && (matches.subcommand().is_none() || options.is_some_and(|options| options.subcommand_name().is_none())) {
+                args.push(ttp.into());
+            }
+        }
+        let matches = command.clone().get_matches_from(args);
+        let mut cli = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.format(&mut command).exit());
+        if let SubCommand::Config(ConfigCommand::Check(check)) = &mut cli.subcommand {
+            check.options = check::effective_options(&command, &matches, &from_file);
+        }
+        cli
+    }
+
     pub fn build_client(&self) -> Client {
         let mut client_builder = Client::builder();
         client_builder = client_builder
@@ -30,15 +74,14 @@ impl CliArgs {
             .danger_accept_invalid_certs(self.tls_disable);
         if let Some(tls_ca_dir) = &self.tls_ca_certificates_dir {
             info!("Loading available custom ca certificates from {:?}", self.tls_ca_certificates_dir);
-            for path_res in tls_ca_dir.read_dir().expect(&format!("Unable to read {:?}", self.tls_ca_certificates_dir)) {
-                if let Ok(path_buf) = path_res {
-                    info!("Adding custom ca certificate {:?}", path_buf.path());
-                    client_builder = client_builder.add_root_certificate(
-                        Certificate::from_pem(
-                            &fs::read(path_buf.path()).expect(&format!("Unable to read file provided: {:?}", path_buf.path()))
-                        ).expect(&format!("Unable to convert {:?} to a certificate. Please verify it is a valid pem file", path_buf.path()))
-                    );
-                }
+            let dir_entries = tls_ca_dir.read_dir().unwrap_or_else(|_| panic!("Unable to read {:?}", self.tls_ca_certificates_dir));
+            for path_buf in dir_entries.flatten() {
+                info!("Adding custom ca certificate {:?}", path_buf.path());
+                client_builder = client_builder.add_root_certificate(
+                    Certificate::from_pem(
+                        &fs::read(path_buf.path()).unwrap_or_else(|_| panic!("Unable to read file provided: {:?}", path_buf.path()))
+                    ).unwrap_or_else(|_| panic!("Unable to convert {:?} to a certificate. Please verify it is a valid pem file", path_buf.path()))
+                );
             }
         }
 
@@ -48,7 +91,16 @@ impl CliArgs {
 
 #[derive(Debug, clap::Subcommand)]
 pub enum SubCommand {
-    Dic(DicConfig)
+    Dic(DicConfig),
+    /// Inspect the configuration of transFAIR
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the options of the dic subcommand and print them without secrets, optionally probing the services they configure
+    Check(CheckArgs),
 }
 
 #[derive(Parser, Clone, Debug)]
@@ -72,11 +124,57 @@ pub struct DicConfig {
     pub fhir_input_url: Url,
     #[clap(long, env, default_value = "")]
     pub fhir_input_credentials: Auth,
+    // Number of bundles requested per page when fetching new data from the input server
+    #[clap(long, env, default_value_t = 100)]
+    pub fhir_input_page_size: u32,
+    // Seconds to wait between two fetches of new data from the input server
+    #[clap(long, env, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub fetch_interval: u64,
+    // Cron expression (evaluated in UTC, e.g. "*/5 * * * *") used to schedule fetches instead of the fixed interval
+    #[clap(long, env)]
+    pub fetch_schedule: Option<Cron>,
     // Definition of the fhir server and credentials used for adding data to the project data
     #[clap(long, env)]
     pub fhir_output_url: Url,
     #[clap(long, env, default_value = "")]
     pub fhir_output_credentials: Auth,
+    // Transformation applied to delivered data before it is added to the project data
+    #[clap(long, env, default_value = "fhircopy")]
+    pub profile: Profile,
+    // Restricts which linked resources are added to the project data
+    #[clap(flatten)]
+    pub filter: ResourceFilter,
+    // Reject deliveries for data requests that were created without a Consent, otherwise they are transferred unchecked
+    #[clap(long, env, default_value_t = false)]
+    pub consent_required: bool,
+    // Purpose of use of the project data ("<system>|<code>"), consent provisions for other purposes don't apply
+    #[clap(long, env)]
+    pub consent_purpose: Option<CodeFilter>,
+    // Bearer token required by all /admin and /deadletters routes, resolving pseudonyms is disabled without it and fetches
+    // can be triggered and dead letters retried by everyone
+    #[clap(long, env)]
+    pub admin_api_key: Option<Secret>,
+    // TOML file with further projects served under /projects/<project>/requests, the project configured above stays the default
+    #[clap(long, env)]
+    pub projects_file: Option<PathBuf>,
+    // Authentication of clients of the /requests api
+    #[clap(flatten)]
+    pub auth: AuthConfig,
+}
+
+impl DicConfig {
+    // Time to wait after a fetch until the next one is due
+    pub fn next_fetch_delay(&self, now: DateTime<Utc>) -> Duration {
+        let interval = Duration::from_secs(self.fetch_interval);
+        let Some(schedule) = &self.fetch_schedule else {
+            return interval;
+        };
+        schedule
+            .find_next_occurrence(&now, false)
+            .ok()
+            .and_then(|next| (next - now).to_std().ok())
+            .unwrap_or(interval)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -84,11 +182,11 @@ pub enum Auth {
     None,
     Basic {
         user: String,
-        pw: String,
+        pw: Secret,
     },
     Oauth {
         client_id: String,
-        client_secret: String,
+        client_secret: Secret,
         token_url: Url,
     }
 }
@@ -100,16 +198,30 @@ impl FromStr for Auth {
         if s.is_empty() {
             return Ok(Self::None);
         }
+        if s.starts_with(secret::FILE_PREFIX) {
+            return Err(anyhow!("Only the password or client secret can be read from a file, e.g. '<user>:file:<path>'"));
+        }
         if s.starts_with("OAuth") {
             let mut parts = s.split(' ').skip(1);
             return Ok(Self::Oauth {
                 client_id: parts.next().ok_or(anyhow!("Missing client id"))?.into(),
-                client_secret: parts.next().ok_or(anyhow!("Missing client secret"))?.into(),
+                client_secret: parts.next().ok_or(anyhow!("Missing client secret"))?.parse()?,
                 token_url: parts.next().ok_or(anyhow!("Missing OAuth token endpoint url"))?.parse()?,
             })
         }
         let (user, pw) = s.split_once(":").ok_or(anyhow!("Credentials should be in the form of '<user>:<pw>'"))?;
-        Ok(Self::Basic { user: user.to_owned(), pw: pw.to_owned() })
+        Ok(Self::Basic { user: user.to_owned(), pw: pw.parse()? })
+    }
+}
+
+// Credentials without their secret, e.g. for printing the configuration
+impl Display for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::None => Ok(()),
+            Auth::Basic { user, pw } => write!(f, "{user}:{pw}"),
+            Auth::Oauth { client_id, client_secret, token_url } => write!(f, "OAuth {client_id} {client_secret} {token_url}"),
+        }
     }
 }
 
@@ -122,35 +234,18 @@ static OIDC_TOKENS: LazyLock<RwLock<HashMap<String, (Instant, String)>>> = LazyL
 impl ClientBuilderExt for reqwest::RequestBuilder {
     async fn add_auth(self, auth: &Auth) -> reqwest::Result<Self> {
         let res = match auth {
-            Auth::Basic { user, pw } => self.basic_auth(user, Some(pw)),
+            Auth::Basic { user, pw } => self.basic_auth(user, Some(pw.expose())),
             Auth::Oauth { client_id, client_secret, token_url } => {
                 {
                     let read_lock = OIDC_TOKENS.read().await;
-                    if let Some((ttl, token)) = read_lock.get(client_id) {
-                        if *ttl <= Instant::now() {
-                            return Ok(self.bearer_auth(token));
-                        }
+                    if let Some((ttl, token)) = read_lock.get(client_id)
+                        && *ttl <= Instant::now() {
+                        return Ok(self.bearer_auth(token));
                     }
                 }
-                #[derive(serde::Deserialize)]
-                struct TokenRes {
-                    expires_in: u64,
-                    access_token: String,
-                }
-                let TokenRes { expires_in, access_token } = CLIENT
-                    .post(token_url.clone())
-                    .form(&serde_json::json!({
-                        "grant_type": "client_credentials",
-                        "client_id": client_id,
-                        "client_secret": client_secret
-                    }))
-                    .send()
-                    .await?
-                    .error_for_status()?
-                    .json::<TokenRes>()
-                    .await?;
+                let (ttl, access_token) = request_token(client_id, &client_secret.expose(), token_url).await?;
                 let res = self.bearer_auth(&access_token);
-                OIDC_TOKENS.write().await.insert(client_id.clone(), (Instant::now() + Duration::from_secs(expires_in), access_token));
+                OIDC_TOKENS.write().await.insert(client_id.clone(), (ttl, access_token));
                 res
             }
             Auth::None => self,
@@ -158,3 +253,124 @@ impl ClientBuilderExt for reqwest::RequestBuilder {
         Ok(res)
     }
 }
+
+// Requests a new token with the client credentials grant, returns it with the time it expires
+pub async fn request_token(client_id: &str, client_secret: &str, token_url: &Url) -> reqwest::Result<(Instant, String)> {
+    #[derive(serde::Deserialize)]
+    struct TokenRes {
+        expires_in: u64,
+        access_token: String,
+    }
+    let TokenRes { expires_in, access_token } = CLIENT
+        .post(token_url.clone())
+        .form(&serde_json::json!({
+            "grant_type": "client_credentials",
+            "client_id": client_id,
+            "client_secret": client_secret
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<TokenRes>()
+        .await?;
+    Ok((Instant::now() + Duration::from_secs(expires_in), access_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use clap::Parser;
+
+    use crate::ttp::Ttp;
+
+    use super::{CliArgs, SubCommand};
+
+    fn parse_dic_config(extra_args: &[&str]) -> super::DicConfig {
+        let args = [
+            "transfair", "dic",
+            "--database-url", "sqlite://data_requests.sql",
+            "--fhir-request-url", "http://localhost:8085",
+            "--fhir-input-url", "http://localhost:8086",
+            "--fhir-output-url", "http://localhost:8095",
+        ];
+        let cli = CliArgs::try_parse_from(args.iter().chain(extra_args)).unwrap();
+        match cli.subcommand {
+            SubCommand::Dic(config) => config,
+            SubCommand::Config(_) => unreachable!("the arguments select the dic subcommand"),
+        }
+    }
+
+    #[test]
+    fn next_fetch_delay() {
+        let now = Utc.with_ymd_and_hms(2025, 9, 10, 10, 30, 0).unwrap();
+        assert_eq!(parse_dic_config(&[]).next_fetch_delay(now), Duration::from_secs(60));
+        assert_eq!(parse_dic_config(&["--fetch-interval", "300"]).next_fetch_delay(now), Duration::from_secs(300));
+        // a schedule takes precedence over the interval
+        let config = parse_dic_config(&["--fetch-interval", "300", "--fetch-schedule", "0 * * * *"]);
+        assert_eq!(config.next_fetch_delay(now), Duration::from_secs(30 * 60));
+        // fetching without pause would hammer the input server
+        assert!(CliArgs::try_parse_from(["transfair", "dic", "--fetch-interval", "0"]).is_err_and(|error| error.to_string().contains("--fetch-interval")));
+    }
+
+    #[test]
+    fn secrets_are_not_logged() {
+        let config = parse_dic_config(&[
+            "--fhir-input-credentials", "transfair:input-password",
+            "--fhir-output-credentials", "OAuth transfair output-secret https://auth/token",
+            "--admin-api-key", "admin-key",
+            "--api-keys", "client-key:requests.read",
+            "mainzelliste", "--ttp-url", "http://ml:8080", "--project-id-system", "PROJECT_1_ID", "--ttp-ml-api-key", "ml-key",
+        ]);
+        let debug = format!("{config:?}");
+        for secret in ["input-password", "output-secret", "admin-key", "client-key", "ml-key"] {
+            assert!(!debug.contains(secret), "{secret} is part of {debug}");
+        }
+        assert!(debug.contains("transfair"));
+        // only the secrets themselves can be read from files
+        assert!("file:/run/secrets/credentials".parse::<super::Auth>().is_err());
+    }
+
+    #[test]
+    fn config_file_is_below_command_line_and_environment() {
+        let path = std::env::temp_dir().join(format!("transfair-config-{}.toml", std::process::id()));
+        std::fs::write(&path, r#"
+            tls_ca_certificates_dir = "/file/certificates"
+            database_url = "sqlite://transfair.db"
+            fhir_request_url = "http://request:8080"
+            fhir_input_url = "http://input:8080"
+            fhir_output_url = "http://output:8080"
+            exchange_id_system = "FILE_ID"
+            fetch_interval = 300
+
+            [ttp]
+            type = "local"
+            project_id_system = "PROJECT_1_ID"
+            ttp_local_key = "hmac-key"
+        "#).unwrap();
+        let parse = |args: &[&str]| {
+            let cli = CliArgs::parse_with_config_file(["transfair", "--config", path.to_str().unwrap()].iter().chain(args));
+            let SubCommand::Dic(config) = cli.subcommand else {
+                unreachable!("the dic subcommand is added for the config file");
+            };
+            (cli.tls_ca_certificates_dir.unwrap(), config)
+        };
+
+        // the dic and ttp subcommands are taken from the config file
+        let (certificates, config) = parse(&[]);
+        assert_eq!(certificates.to_str(), Some("/file/certificates"));
+        assert!(matches!(config.ttp, Some(Ttp::Local(_))));
+        assert_eq!(config.fetch_interval, 300);
+        assert_eq!(config.fhir_input_page_size, 100);
+        // .cargo/config.toml sets the exchange id system in the environment of the tests
+        let exchange_id_system = std::env::var("EXCHANGE_ID_SYSTEM").unwrap_or_else(|_| "FILE_ID".into());
+        assert_eq!(config.exchange_id_system, exchange_id_system);
+
+        let (certificates, config) = parse(&["--tls-ca-certificates-dir", "/cli/certificates", "dic", "--fetch-interval", "30"]);
+        assert_eq!(certificates.to_str(), Some("/cli/certificates"));
+        assert!(matches!(config.ttp, Some(Ttp::Local(_))));
+        assert_eq!(config.fetch_interval, 30);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
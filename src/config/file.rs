@@ -0,0 +1,177 @@
+//! Config file (TOML or YAML) with the options of transFAIR. Keys are the names of the command line options, values
+//! become the defaults of the options, so they are used for every option given neither on the command line nor in the
+//! environment.
+use std::{ffi::OsStr, path::Path};
+
+use anyhow::{bail, Context};
+use clap::{Arg, Command};
+use serde_json::{Map, Value};
+
+// Options of a config file by the environment variables of their arguments, and the ttp they configure
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigFile {
+    pub variables: Vec<(String, String)>,
+    pub ttp: Option<String>,
+}
+
+pub fn load(path: &Path, command: &Command) -> anyhow::Result<ConfigFile> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Unable to read config file {}", path.display()))?;
+    let settings = match path.extension().and_then(OsStr::to_str) {
+        Some("toml") => toml::from_str(&content)?,
+        Some("yaml" | "yml") => serde_yaml::from_str(&content)?,
+        _ => bail!("Config file {} should be a .toml, .yaml or .yml file", path.display()),
+    };
+    parse(settings, command).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+// The command with the options of the config file as defaults of its arguments and those of all its subcommands
+pub fn with_defaults(mut command: Command, variables: &[(String, String)]) -> Command {
+    let defaults = command
+        .get_arguments()
+        .filter_map(|arg| {
+            let env = arg.get_env()?;
+            let (_, value) = variables.iter().find(|(variable, _)| env == variable.as_str())?;
+            Some((arg.get_id().clone(), value.clone()))
+        })
+        .collect::<Vec<_>>();
+    for (id, value) in defaults {
+        // clap doesn't count defaults for required options, and defaults of options taking a value are shown in the help,
+        // but may be secrets
+        command = command.mut_arg(id, |arg| {
+            let takes_values = arg.get_action().takes_values();
+            arg.default_value(value).required(false).hide_default_value(takes_values)
+        });
+    }
+    let subcommands = command.get_subcommands().map(|subcommand| subcommand.get_name().to_owned()).collect::<Vec<_>>();
+    for name in subcommands {
+        command = command.mut_subcommand(name, |subcommand| with_defaults(subcommand, variables));
+    }
+    command
+}
+
+fn parse(settings: Value, command: &Command) -> anyhow::Result<ConfigFile> {
+    let Value::Object(mut settings) = settings else {
+        bail!("Config file should contain a table of options");
+    };
+    let dic = command.find_subcommand("dic").expect("transFAIR has a dic subcommand");
+    let mut file = ConfigFile::default();
+    // the ttp is a subcommand on the command line, with its type as name
+    if let Some(ttp) = settings.remove("ttp") {
+        let Value::Object(mut ttp) = ttp else {
+            bail!("ttp should be a table with the type of the ttp and its options");
+        };
+        let types = dic.get_subcommands().map(Command::get_name).collect::<Vec<_>>().join(", ");
+        let Some(Value::String(ttp_type)) = ttp.remove("type") else {
+            bail!("ttp needs a type, one of {types}");
+        };
+        let Some(ttp_command) = dic.find_subcommand(&ttp_type) else {
+            bail!("Unknown ttp type '{ttp_type}', expected one of {types}");
+        };
+        file.variables.extend(variables(ttp, &[ttp_command])?);
+        file.ttp = Some(ttp_type);
+    }
+    file.variables.extend(variables(settings, &[command, dic])?);
+    Ok(file)
+}
+
+fn variables(settings: Map<String, Value>, commands: &[&Command]) -> anyhow::Result<Vec<(String, String)>> {
+    settings.into_iter().map(|(key, value)| {
+        let long = key.replace('_', "-");
+        let Some(arg) = commands.iter().flat_map(|command| command.get_arguments()).find(|arg| arg.get_long() == Some(long.as_str())) else {
+            bail!("Unknown option {key}");
+        };
+        let Some(env) = arg.get_env() else {
+            bail!("Option {key} can't be set in the config file");
+        };
+        Ok((env.to_string_lossy().into_owned(), value_of(&key, value, arg)?))
+    }).collect()
+}
+
+fn value_of(key: &str, value: Value, arg: &Arg) -> anyhow::Result<String> {
+    match value {
+        Value::String(value) => Ok(value),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        Value::Array(values) => {
+            let Some(delimiter) = arg.get_value_delimiter() else {
+                bail!("Option {key} takes a single value");
+            };
+            let values = values.into_iter().map(|value| value_of(key, value, arg)).collect::<anyhow::Result<Vec<_>>>()?;
+            Ok(values.join(&delimiter.to_string()))
+        }
+        Value::Object(credentials) => credentials_of(key, &credentials),
+        Value::Null => bail!("Option {key} has no value"),
+    }
+}
+
+// Credentials may be written as table instead of the form of their environment variable
+fn credentials_of(key: &str, credentials: &Map<String, Value>) -> anyhow::Result<String> {
+    let field = |name: &str| credentials.get(name).and_then(Value::as_str);
+    match (field("user"), field("password"), field("client_id"), field("client_secret"), field("token_url")) {
+        (Some(user), Some(password), None, None, None) if credentials.len() == 2 => Ok(format!("{user}:{password}")),
+        (None, None, Some(client_id), Some(client_secret), Some(token_url)) if credentials.len() == 3 => {
+            Ok(format!("OAuth {client_id} {client_secret} {token_url}"))
+        }
+        _ => bail!("Option {key} should be a table of either user and password or client_id, client_secret and token_url"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+    use serde_json::Value;
+
+    use crate::config::CliArgs;
+
+    use super::{parse, ConfigFile};
+
+    fn parse_toml(file: &str) -> anyhow::Result<ConfigFile> {
+        parse(toml::from_str(file).unwrap(), &CliArgs::command())
+    }
+
+    #[test]
+    fn options_are_keyed_by_their_variables() {
+        let file = parse_toml(r#"
+            tls_disable = true
+            fhir-output-url = "http://output:8080"
+            fhir_output_credentials = { client_id = "transfair", client_secret = "secret", token_url = "https://auth/token" }
+            fetch_interval = 300
+            filter_allow_resource_types = ["Patient", "Condition"]
+
+            [ttp]
+            type = "greifswald"
+            ttp_url = "https://ttp:8080"
+            project_id_system = "PROJECT_1_ID"
+            ttp_auth = { user = "transfair", password = "secret" }
+        "#).unwrap();
+        assert_eq!(file.ttp.as_deref(), Some("greifswald"));
+        let variable = |name: &str| file.variables.iter().find(|(variable, _)| variable == name).map(|(_, value)| value.as_str());
+        assert_eq!(variable("TLS_DISABLE"), Some("true"));
+        assert_eq!(variable("FHIR_OUTPUT_URL"), Some("http://output:8080"));
+        assert_eq!(variable("FHIR_OUTPUT_CREDENTIALS"), Some("OAuth transfair secret https://auth/token"));
+        assert_eq!(variable("FETCH_INTERVAL"), Some("300"));
+        assert_eq!(variable("FILTER_ALLOW_RESOURCE_TYPES"), Some("Patient,Condition"));
+        assert_eq!(variable("TTP_URL"), Some("https://ttp:8080"));
+        assert_eq!(variable("PROJECT_ID_SYSTEM"), Some("PROJECT_1_ID"));
+        assert_eq!(variable("TTP_AUTH"), Some("transfair:secret"));
+    }
+
+    #[test]
+    fn yaml_config_file() {
+        let settings: Value = serde_yaml::from_str("database_url: sqlite://transfair.db\nttp:\n  type: local\n  ttp_local_mode: random\n").unwrap();
+        let file = parse(settings, &CliArgs::command()).unwrap();
+        assert_eq!(file.ttp.as_deref(), Some("local"));
+        assert_eq!(file.variables, [
+            ("TTP_LOCAL_MODE".to_owned(), "random".to_owned()),
+            ("DATABASE_URL".to_owned(), "sqlite://transfair.db".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn reject_unknown_options() {
+        assert!(parse_toml("fhir_outptu_url = \"http://output:8080\"").is_err());
+        // options of the ttp belong into its table
+        assert!(parse_toml("ttp_url = \"https://ttp:8080\"").is_err());
+        assert!(parse_toml("[ttp]\ntype = \"epix\"").is_err());
+        assert!(parse_toml("fetch_interval = [60, 120]").is_err());
+    }
+}
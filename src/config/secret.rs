@@ -0,0 +1,120 @@
+//! Secrets given inline or as reference to a file (`file:<path>`), e.g. a Docker or Kubernetes secret. Files are read
+//! when the options are parsed and again once they are modified, so secrets can be rotated without a restart.
+use std::{fmt::{Debug, Display}, fs, path::{Path, PathBuf}, str::FromStr, sync::{Arc, RwLock}, time::SystemTime};
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+pub const FILE_PREFIX: &str = "file:";
+
+#[derive(Clone)]
+pub enum Secret {
+    Value(String),
+    File(Arc<SecretFile>),
+}
+
+pub struct SecretFile {
+    path: PathBuf,
+    // content of the file with its modification time when it was read
+    content: RwLock<(Option<SystemTime>, String)>,
+}
+
+impl Secret {
+    // The current value of the secret, its file is read again if it was modified since
+    pub fn expose(&self) -> String {
+        match self {
+            Secret::Value(value) => value.clone(),
+            Secret::File(file) => file.read(),
+        }
+    }
+}
+
+impl SecretFile {
+    fn read(&self) -> String {
+        let modified = modified(&self.path);
+        {
+            let (read_at, content) = &*self.content.read().expect("Secret lock poisoned");
+            // while the file is replaced, it may be missing for a moment
+            if modified.is_none() || modified == *read_at {
+                return content.clone();
+            }
+        }
+        let mut cached = self.content.write().expect("Secret lock poisoned");
+        match read_secret(&self.path) {
+            Ok(content) => {
+                info!("Secret {} changed, using its new value", self.path.display());
+                *cached = (modified, content.clone());
+                content
+            }
+            Err(error) => {
+                warn!("Unable to read secret {} again, keeping its previous value: {error:#}", self.path.display());
+                cached.1.clone()
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Files written by editors and `echo` end with a newline, which isn't part of the secret
+fn read_secret(path: &Path) -> anyhow::Result<String> {
+    let content = fs::read_to_string(path).with_context(|| format!("Unable to read secret {}", path.display()))?;
+    Ok(content.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+impl FromStr for Secret {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(path) = s.strip_prefix(FILE_PREFIX) else {
+            return Ok(Self::Value(s.to_owned()));
+        };
+        let path = PathBuf::from(path);
+        let content = (modified(&path), read_secret(&path)?);
+        Ok(Self::File(Arc::new(SecretFile { path, content: RwLock::new(content) })))
+    }
+}
+
+// Secrets are never printed, only the files they are read from
+impl Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Value(_) => f.write_str("***"),
+            Secret::File(file) => write!(f, "{FILE_PREFIX}{}", file.path.display()),
+        }
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::{Duration, SystemTime}};
+
+    use super::Secret;
+
+    #[test]
+    fn secrets_are_read_from_files_again_when_modified() {
+        let path = std::env::temp_dir().join(format!("transfair-secret-{}", std::process::id()));
+        fs::write(&path, "first\n").unwrap();
+        let secret: Secret = format!("file:{}", path.display()).parse().unwrap();
+        assert_eq!(secret.expose(), "first");
+        assert_eq!(format!("{secret:?}"), format!("file:{}", path.display()));
+        fs::write(&path, "second").unwrap();
+        // file systems with a coarse timestamp resolution would miss the change otherwise
+        fs::File::options().write(true).open(&path).unwrap().set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(secret.expose(), "second");
+        fs::remove_file(&path).unwrap();
+        // a missing file keeps the previous value
+        assert_eq!(secret.expose(), "second");
+        assert!(format!("file:{}", path.display()).parse::<Secret>().is_err());
+        let inline: Secret = "inline".parse().unwrap();
+        assert_eq!((inline.expose().as_str(), format!("{inline:?}").as_str()), ("inline", "***"));
+    }
+}
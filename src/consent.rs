@@ -0,0 +1,157 @@
+use chrono::{DateTime, Utc};
+use fhir_sdk::r4b::resources::{Bundle, Consent, ResourceType};
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+
+use crate::filter::{clinical_date, date_within, retain_resources, CodeFilter, ResourceCounts};
+
+const RESOURCE_TYPES_SYSTEM: &str = "http://hl7.org/fhir/resource-types";
+
+// Consent documented with the data request, if any
+pub async fn load_consent(data_request_id: &str, database_pool: &Pool<Sqlite>) -> sqlx::Result<Option<Consent>> {
+    let consent = sqlx::query_scalar!(
+        "SELECT consent FROM data_requests WHERE id = $1",
+        data_request_id
+    ).fetch_optional(database_pool).await?.flatten();
+    consent
+        .map(|consent| serde_json::from_str(&consent).map_err(|e| sqlx::Error::Decode(Box::new(e))))
+        .transpose()
+}
+
+// Removes all resources the consent does not permit to be transferred and returns their number.
+// The delivery is rejected as a whole (with the reason) if the consent is not active or not valid at the moment.
+// Provisions are evaluated as in FHIR: the base provision decides unless a nested provision applies to the resource,
+// where a provision applies if its resource types (class), purpose, period and data period match.
+// The patient itself is always kept, as the consent is about the data of the patient, and resources referencing withheld
+// ones are withheld as well.
+pub fn apply_consent(consent: &Consent, bundle: &mut Bundle, purpose: Option<&CodeFilter>, now: DateTime<Utc>) -> Result<ResourceCounts, String> {
+    let consent = serde_json::to_value(consent).expect("Resources can always be serialized");
+    let consent_id = consent.get("id").and_then(Value::as_str).unwrap_or("<no id>");
+    let status = consent.get("status").and_then(Value::as_str).unwrap_or_default();
+    if status != "active" {
+        return Err(format!("Consent {consent_id} is {status}, not active"));
+    }
+    let Some(provision) = consent.get("provision") else {
+        // a consent without provisions permits everything
+        return Ok(ResourceCounts::default());
+    };
+    let today = now.date_naive().to_string();
+    if !period_contains(provision.get("period"), &today) {
+        return Err(format!("Consent {consent_id} is not valid on {today}"));
+    }
+
+    Ok(retain_resources(bundle, |resource| {
+        let json = serde_json::to_value(resource).expect("Resources can always be serialized");
+        let date = clinical_date(&json);
+        provision_permits(provision, resource.resource_type(), date, purpose, &today)
+            && date.is_none_or(|date| period_contains(provision.get("dataPeriod"), date))
+    }))
+}
+
+// Decision of the provision or of the last of its nested provisions that applies to the resource
+fn provision_permits(provision: &Value, rt: ResourceType, date: Option<&str>, purpose: Option<&CodeFilter>, today: &str) -> bool {
+    let mut permit = provision.get("type").and_then(Value::as_str) != Some("deny");
+    for nested in provision.get("provision").and_then(Value::as_array).into_iter().flatten() {
+        if provision_applies(nested, rt, date, purpose, today) {
+            permit = provision_permits(nested, rt, date, purpose, today);
+        }
+    }
+    permit
+}
+
+fn provision_applies(provision: &Value, rt: ResourceType, date: Option<&str>, purpose: Option<&CodeFilter>, today: &str) -> bool {
+    let codings = |field: &str| provision.get(field).and_then(Value::as_array).cloned().unwrap_or_default();
+    let classes = codings("class");
+    let class_matches = classes.is_empty() || classes.iter().any(|class| {
+        class.get("system").and_then(Value::as_str).is_none_or(|system| system == RESOURCE_TYPES_SYSTEM)
+            && class.get("code").and_then(Value::as_str) == Some(rt.to_string().as_str())
+    });
+    let purposes = codings("purpose");
+    // provisions for a specific purpose only apply to projects with that purpose
+    let purpose_matches = purposes.is_empty() || purpose.is_some_and(|purpose| purposes.iter().any(|coding| purpose.matches(coding)));
+    // a provision restricted to a data period can't apply to resources without a date
+    let data_period_matches = match provision.get("dataPeriod") {
+        None => true,
+        Some(data_period) => date.is_some_and(|date| period_contains(Some(data_period), date)),
+    };
+    class_matches && purpose_matches && data_period_matches && period_contains(provision.get("period"), today)
+}
+
+fn period_contains(period: Option<&Value>, date: &str) -> bool {
+    let Some(period) = period else {
+        return true;
+    };
+    date_within(date, period.get("start").and_then(Value::as_str), period.get("end").and_then(Value::as_str))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use fhir_sdk::r4b::resources::{Bundle, Consent};
+    use serde_json::json;
+
+    use super::apply_consent;
+
+    fn bundle() -> Bundle {
+        serde_json::from_value(json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [
+                {"resource": {"resourceType": "Patient", "id": "1"}},
+                {"resource": {"resourceType": "Condition", "id": "2", "subject": {"reference": "Patient/1"}, "recordedDate": "2021-03-01"}},
+                {"resource": {"resourceType": "Specimen", "id": "3", "collection": {"collectedDateTime": "2023-11-02T10:00:00+01:00"}}},
+                {"resource": {"resourceType": "Specimen", "id": "4", "collection": {"collectedDateTime": "2019-01-15"}}}
+            ]
+        })).unwrap()
+    }
+
+    fn consent(status: &str) -> Consent {
+        serde_json::from_value(json!({
+            "resourceType": "Consent",
+            "id": "consent",
+            "status": status,
+            "scope": {"coding": [{"system": "http://terminology.hl7.org/CodeSystem/consentscope", "code": "research"}]},
+            "category": [{"coding": [{"system": "http://loinc.org", "code": "57016-8"}]}],
+            "provision": {
+                "type": "deny",
+                "period": {"start": "2020-01-01", "end": "2030-12-31"},
+                "provision": [
+                    {"type": "permit", "class": [{"system": "http://hl7.org/fhir/resource-types", "code": "Condition"}]},
+                    {
+                        "type": "permit",
+                        "class": [{"system": "http://hl7.org/fhir/resource-types", "code": "Specimen"}],
+                        "dataPeriod": {"start": "2020-01-01"}
+                    }
+                ]
+            }
+        })).unwrap()
+    }
+
+    fn ids(bundle: &Bundle) -> Vec<String> {
+        bundle.entry.iter().flatten().filter_map(|entry| entry.resource.as_ref()).map(|resource| {
+            serde_json::to_value(resource).unwrap()["id"].as_str().unwrap().to_owned()
+        }).collect()
+    }
+
+    #[test]
+    fn withhold_resources_not_permitted() {
+        let mut bundle = bundle();
+        // a permitted condition with evidence that is withheld
+        bundle.entry.push(serde_json::from_value(json!({"resource": {
+            "resourceType": "Condition", "id": "5", "subject": {"reference": "Patient/1"}, "recordedDate": "2021-03-01",
+            "evidence": [{"detail": [{"reference": "Specimen/4"}]}]
+        }})).unwrap());
+        let now = Utc.with_ymd_and_hms(2025, 9, 15, 12, 0, 0).unwrap();
+        let withheld = apply_consent(&consent("active"), &mut bundle, None, now).unwrap();
+        assert_eq!(ids(&bundle), ["1", "2", "3"]);
+        assert_eq!(withheld.to_string(), "2 resource(s) (Condition: 1, Specimen: 1)");
+    }
+
+    #[test]
+    fn reject_inactive_or_expired_consent() {
+        let now = Utc.with_ymd_and_hms(2025, 9, 15, 12, 0, 0).unwrap();
+        assert_eq!(apply_consent(&consent("inactive"), &mut bundle(), None, now).unwrap_err(), "Consent consent is inactive, not active");
+        let expired = Utc.with_ymd_and_hms(2031, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(apply_consent(&consent("active"), &mut bundle(), None, expired).unwrap_err(), "Consent consent is not valid on 2031-01-01");
+    }
+}
@@ -0,0 +1,184 @@
+use axum::{extract::{Path, State}, Json};
+use chrono::Utc;
+use fhir_sdk::r4b::resources::Bundle;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::{deliveries::{self, DeliveryOutcome}, handle_delivery, DicAppState};
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct DeadLetter {
+    pub id: String,
+    pub bundle_id: String,
+    pub version_id: String,
+    pub data_request_id: Option<String>,
+    pub reason: String,
+    pub created: i64,
+    pub updated: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeadLetterRetry {
+    pub id: String,
+    pub outcome: DeliveryOutcome,
+}
+
+// Stores a delivery as it was received. A delivery that is dead lettered again keeps its id.
+pub async fn store_dead_letter(
+    bundle_id: &str,
+    version_id: &str,
+    data_request_id: Option<&str>,
+    reason: &str,
+    bundle: &Bundle,
+    database_pool: &Pool<Sqlite>
+) -> sqlx::Result<()> {
+    let id = Uuid::new_v4().to_string();
+    let bundle = serde_json::to_string(bundle).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    let now = Utc::now().timestamp_millis();
+    sqlx::query!(
+        "INSERT INTO dead_letters (id, bundle_id, version_id, data_request_id, reason, bundle, created, updated) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (bundle_id, version_id) DO UPDATE SET data_request_id = excluded.data_request_id, reason = excluded.reason, bundle = excluded.bundle, updated = excluded.updated",
+        id, bundle_id, version_id, data_request_id, reason, bundle, now, now
+    ).execute(database_pool).await?;
+    Ok(())
+}
+
+// GET /deadletters; Lists all deliveries that could not be linked
+pub async fn list_dead_letters(
+    State(DicAppState { database_pool, .. }): State<DicAppState>
+) -> Result<Json<Vec<DeadLetter>>, (StatusCode, &'static str)> {
+    let dead_letters = sqlx::query_as!(
+        DeadLetter,
+        "SELECT id, bundle_id, version_id, data_request_id, reason, created, updated FROM dead_letters ORDER BY created;",
+    ).fetch_all(&database_pool).await.map_err(|e| {
+        error!("Unable to fetch dead letters from database: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to fetch dead letters from database!")
+    })?;
+    Ok(Json(dead_letters))
+}
+
+// POST /deadletters/<dead-letter-id>/retry; Processes the dead lettered delivery again, e.g. after the configuration was fixed
+pub async fn retry_dead_letter(
+    State(state): State<DicAppState>,
+    Path(dead_letter_id): Path<String>
+) -> Result<Json<DeadLetterRetry>, (StatusCode, &'static str)> {
+    debug!("Retry of dead letter {} requested.", dead_letter_id);
+    let dead_letter = sqlx::query!(
+        "SELECT bundle_id, version_id, bundle FROM dead_letters WHERE id = $1;",
+        dead_letter_id
+    ).fetch_optional(&state.database_pool).await.map_err(|e| {
+        error!("Unable to fetch dead letter {} from database: {}", dead_letter_id, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to fetch dead letter from database!")
+    })?;
+    let Some(dead_letter) = dead_letter else {
+        return Err((StatusCode::NOT_FOUND, "Couldn't retrieve dead letter with id"));
+    };
+    let mut bundle = serde_json::from_str::<Bundle>(&dead_letter.bundle).map_err(|e| {
+        error!("Unable to parse bundle of dead letter {}: {}", dead_letter_id, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to parse stored bundle of dead letter")
+    })?;
+
+    // the delivery must not be handled by a running fetch at the same time
+    let _fetch_guard = state.fetch_lock.lock().await;
+    let outcome = async {
+        deliveries::release_delivery(&dead_letter.bundle_id, &dead_letter.version_id, &state.database_pool).await?;
+        let outcome = handle_delivery(&mut bundle, &state).await?;
+        if outcome == DeliveryOutcome::Transferred {
+            sqlx::query!("DELETE FROM dead_letters WHERE id = $1", dead_letter_id)
+                .execute(&state.database_pool).await?;
+        }
+        Ok::<_, sqlx::Error>(outcome)
+    }.await.map_err(|e| {
+        error!("Unable to retry dead letter {}: {}", dead_letter_id, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to retry dead letter")
+    })?;
+    info!("Retried dead letter {}: {:?}", dead_letter_id, outcome);
+
+    Ok(Json(DeadLetterRetry { id: dead_letter_id, outcome }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::{Path, State}, routing::post, Router};
+    use fhir_sdk::r4b::{codes::BundleType, resources::Bundle};
+    use sqlx::SqlitePool;
+
+    use crate::{app, deliveries::{self, DeliveryOutcome}, handle_delivery, stub_server, DicAppState, CLIENT};
+
+    use super::{retry_dead_letter, store_dead_letter};
+
+    // A delivery for data request "request"; without a ttp, it is transferred without linkage
+    fn delivery(identifier_system: &str) -> Bundle {
+        serde_json::from_value(serde_json::json!({
+            "resourceType": "Bundle",
+            "id": "bundle",
+            "meta": { "versionId": "1" },
+            "type": "transaction",
+            "identifier": { "system": identifier_system, "value": "request" },
+            "entry": []
+        })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn dead_lettering_again_keeps_id() {
+        let database_pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&database_pool).await.unwrap();
+        let bundle = Bundle::builder().r#type(BundleType::Transaction).build().unwrap();
+
+        store_dead_letter("bundle", "1", None, "Bundle: Identifier for linkage didn't contain a system", &bundle, &database_pool).await.unwrap();
+        let first_id = sqlx::query_scalar!("SELECT id FROM dead_letters").fetch_one(&database_pool).await.unwrap();
+        store_dead_letter("bundle", "1", Some("request"), "Condition: Unable to link identifier to any data request", &bundle, &database_pool).await.unwrap();
+
+        let dead_letters = sqlx::query!("SELECT id, data_request_id, reason FROM dead_letters").fetch_all(&database_pool).await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].id, first_id);
+        assert_eq!(dead_letters[0].data_request_id.as_deref(), Some("request"));
+        assert_eq!(dead_letters[0].reason, "Condition: Unable to link identifier to any data request");
+    }
+
+    #[tokio::test]
+    async fn unlinkable_delivery_stays_dead_lettered_on_retry() {
+        let state = DicAppState::for_tests(&[]).await;
+        let outcome = handle_delivery(&mut delivery("WRONG_SYSTEM"), &state).await.unwrap();
+        assert_eq!(outcome, DeliveryOutcome::DeadLettered);
+        // dead lettered deliveries are not processed by fetches
+        assert_eq!(handle_delivery(&mut delivery("WRONG_SYSTEM"), &state).await.unwrap(), DeliveryOutcome::Skipped);
+
+        let id = sqlx::query_scalar!("SELECT id FROM dead_letters").fetch_one(&state.database_pool).await.unwrap();
+        let retry = retry_dead_letter(State(state.clone()), Path(id.clone())).await.unwrap();
+        assert_eq!(retry.outcome, DeliveryOutcome::DeadLettered);
+        assert_eq!(sqlx::query_scalar!("SELECT id FROM dead_letters").fetch_all(&state.database_pool).await.unwrap(), [id]);
+    }
+
+    #[tokio::test]
+    async fn released_dead_letter_is_transferred() {
+        let output_url = stub_server(Router::new().route("/fhir", post(|| async { "{}" }))).await;
+        let state = DicAppState::for_tests(&["--fhir-output-url", &output_url]).await;
+        // a delivery that was dead lettered before its data request was known
+        deliveries::claim_delivery("bundle", "1", &state.database_pool).await.unwrap();
+        store_dead_letter("bundle", "1", None, "Unable to link identifier to any data request", &delivery("DATAREQUEST_ID"), &state.database_pool).await.unwrap();
+        deliveries::mark_dead_lettered("bundle", "1", "Unable to link identifier to any data request", &state.database_pool).await.unwrap();
+
+        let id = sqlx::query_scalar!("SELECT id FROM dead_letters").fetch_one(&state.database_pool).await.unwrap();
+        let retry = retry_dead_letter(State(state.clone()), Path(id.clone())).await.unwrap();
+        assert_eq!(retry.outcome, DeliveryOutcome::Transferred);
+        assert!(sqlx::query_scalar!("SELECT id FROM dead_letters").fetch_all(&state.database_pool).await.unwrap().is_empty());
+        // the transferred delivery is neither retried nor transferred again
+        assert_eq!(handle_delivery(&mut delivery("DATAREQUEST_ID"), &state).await.unwrap(), DeliveryOutcome::Skipped);
+        assert!(matches!(retry_dead_letter(State(state), Path(id)).await, Err((reqwest::StatusCode::NOT_FOUND, _))));
+    }
+
+    #[tokio::test]
+    async fn dead_letters_require_the_admin_key() {
+        let state = DicAppState::for_tests(&["--admin-api-key", "admin-key"]).await;
+        let url = stub_server(app(state)).await;
+        let list = |token: &'static str| CLIENT.get(format!("{url}/deadletters")).bearer_auth(token).send();
+        assert_eq!(list("wrong-key").await.unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(list("admin-key").await.unwrap().status(), reqwest::StatusCode::OK);
+        let retry = CLIENT.post(format!("{url}/deadletters/unknown/retry")).send().await.unwrap();
+        assert_eq!(retry.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use fhir_sdk::r4b::{codes::HTTPVerb, resources::Bundle, types::{Coding, Meta}};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+// Failed deliveries are retried with an exponential backoff, starting at the base and capped at the maximum
+const RETRY_BACKOFF_BASE: Duration = Duration::from_secs(60);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(6 * 60 * 60);
+// Tags the resources of the project data with the delivery they were transferred with
+pub const DELIVERY_TAG_SYSTEM: &str = "https://samply.de/fhir/transfair/delivery";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+pub enum DeliveryState {
+    Pending = 1,
+    Transferred = 2,
+    Failed = 3,
+    DeadLettered = 4,
+    // Sent to the output server, a delivery still posting after a crash may or may not have been transferred
+    Posting = 5,
+}
+
+// What happened to a delivery bundle during a fetch or a manual retry
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeliveryOutcome {
+    Skipped,
+    Transferred,
+    Failed,
+    DeadLettered,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Delivery {
+    pub bundle_id: String,
+    pub version_id: String,
+    pub state: DeliveryState,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+}
+
+// Registers a delivery bundle in the ledger and decides if it should be processed now.
+// Transferred deliveries are never processed again, failed ones only once their backoff expired
+// and dead lettered ones only after they were released for a manual retry. Pending and posting deliveries were
+// interrupted, as deliveries are only processed while holding the fetch lock, and are processed again.
+pub async fn claim_delivery(bundle_id: &str, version_id: &str, database_pool: &Pool<Sqlite>) -> sqlx::Result<bool> {
+    let now = Utc::now().timestamp_millis();
+    let mut transaction = database_pool.begin().await?;
+    // the update on conflict is a no-op, it only makes the existing delivery the result
+    let delivery = sqlx::query!(
+        r#"INSERT INTO deliveries (bundle_id, version_id, state, updated) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (bundle_id, version_id) DO UPDATE SET state = deliveries.state
+        RETURNING state as "state: DeliveryState", next_attempt"#,
+        bundle_id, version_id, DeliveryState::Pending, now
+    ).fetch_one(&mut *transaction).await?;
+    let claimed = match delivery.state {
+        DeliveryState::Pending | DeliveryState::Posting => true,
+        DeliveryState::Transferred | DeliveryState::DeadLettered => false,
+        DeliveryState::Failed => delivery.next_attempt.is_none_or(|next_attempt| next_attempt <= now),
+    };
+    if claimed && delivery.state == DeliveryState::Failed {
+        sqlx::query!(
+            "UPDATE deliveries SET state = $1, updated = $2 WHERE bundle_id = $3 AND version_id = $4",
+            DeliveryState::Pending, now, bundle_id, version_id
+        ).execute(&mut *transaction).await?;
+    }
+    transaction.commit().await?;
+    Ok(claimed)
+}
+
+// Set right before the delivery is sent to the output server
+pub async fn mark_posting(bundle_id: &str, version_id: &str, database_pool: &Pool<Sqlite>) -> sqlx::Result<()> {
+    let now = Utc::now().timestamp_millis();
+    sqlx::query!(
+        "UPDATE deliveries SET state = $1, updated = $2 WHERE bundle_id = $3 AND version_id = $4",
+        DeliveryState::Posting, now, bundle_id, version_id
+    ).execute(database_pool).await?;
+    Ok(())
+}
+
+// Tags every resource with its delivery and entry and turns creates into conditional creates on this tag. A delivery
+// that is posted again, because it is unknown whether its first transfer succeeded, doesn't create its resources twice.
+pub fn make_idempotent(bundle: &mut Bundle, bundle_id: &str, version_id: &str) {
+    for (index, entry) in bundle.entry.iter_mut().flatten().enumerate() {
+        let (Some(resource), Some(request)) = (&mut entry.resource, &mut entry.request) else {
+            continue;
+        };
+        let code = format!("{bundle_id}/{version_id}/{index}");
+        let tag = Coding::builder().system(DELIVERY_TAG_SYSTEM.into()).code(code.clone()).build().expect("Coding without required fields");
+        let meta = resource.as_base_resource_mut().meta_mut().get_or_insert_with(|| Meta::builder().build().expect("Meta without required fields"));
+        meta.tag.push(Some(tag));
+        if request.method == HTTPVerb::Post && request.if_none_exist.is_none() {
+            request.if_none_exist = Some(format!("_tag={DELIVERY_TAG_SYSTEM}|{code}"));
+        }
+    }
+}
+
+pub async fn mark_transferred(bundle_id: &str, version_id: &str, database_pool: &Pool<Sqlite>) -> sqlx::Result<()> {
+    let now = Utc::now().timestamp_millis();
+    sqlx::query!(
+        "UPDATE deliveries SET state = $1, attempts = attempts + 1, last_error = NULL, next_attempt = NULL, updated = $2 WHERE bundle_id = $3 AND version_id = $4",
+        DeliveryState::Transferred, now, bundle_id, version_id
+    ).execute(database_pool).await?;
+    Ok(())
+}
+
+pub async fn mark_failed(bundle_id: &str, version_id: &str, error: &str, database_pool: &Pool<Sqlite>) -> sqlx::Result<()> {
+    let attempts = sqlx::query_scalar!(
+        "SELECT attempts FROM deliveries WHERE bundle_id = $1 AND version_id = $2",
+        bundle_id, version_id
+    ).fetch_one(database_pool).await? + 1;
+    let now = Utc::now().timestamp_millis();
+    let next_attempt = now + retry_backoff(attempts).as_millis() as i64;
+    sqlx::query!(
+        "UPDATE deliveries SET state = $1, attempts = $2, last_error = $3, next_attempt = $4, updated = $5 WHERE bundle_id = $6 AND version_id = $7",
+        DeliveryState::Failed, attempts, error, next_attempt, now, bundle_id, version_id
+    ).execute(database_pool).await?;
+    Ok(())
+}
+
+pub async fn mark_dead_lettered(bundle_id: &str, version_id: &str, error: &str, database_pool: &Pool<Sqlite>) -> sqlx::Result<()> {
+    let now = Utc::now().timestamp_millis();
+    sqlx::query!(
+        "UPDATE deliveries SET state = $1, attempts = attempts + 1, last_error = $2, next_attempt = NULL, updated = $3 WHERE bundle_id = $4 AND version_id = $5",
+        DeliveryState::DeadLettered, error, now, bundle_id, version_id
+    ).execute(database_pool).await?;
+    Ok(())
+}
+
+// Allows a dead lettered delivery to be claimed again
+pub async fn release_delivery(bundle_id: &str, version_id: &str, database_pool: &Pool<Sqlite>) -> sqlx::Result<()> {
+    let now = Utc::now().timestamp_millis();
+    sqlx::query!(
+        "UPDATE deliveries SET state = $1, updated = $2 WHERE bundle_id = $3 AND version_id = $4 AND state = $5",
+        DeliveryState::Pending, now, bundle_id, version_id, DeliveryState::DeadLettered
+    ).execute(database_pool).await?;
+    Ok(())
+}
+
+// Failed deliveries whose backoff expired and deliveries that were interrupted, e.g. by a crash while posting.
+// Must be called while holding the fetch lock, otherwise deliveries that are being processed would be interrupted.
+pub async fn due_for_retry(database_pool: &Pool<Sqlite>) -> sqlx::Result<Vec<Delivery>> {
+    let now = Utc::now().timestamp_millis();
+    sqlx::query_as!(
+        Delivery,
+        r#"SELECT bundle_id, version_id, state as "state: _", attempts, last_error FROM deliveries
+        WHERE (state = $1 AND next_attempt <= $2) OR state IN ($3, $4) ORDER BY updated;"#,
+        DeliveryState::Failed, now, DeliveryState::Pending, DeliveryState::Posting
+    ).fetch_all(database_pool).await
+}
+
+fn retry_backoff(attempts: i64) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    RETRY_BACKOFF_BASE.saturating_mul(2u32.pow(exponent)).min(RETRY_BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::SqlitePool;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn delivery_is_transferred_only_once() {
+        let database_pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&database_pool).await.unwrap();
+
+        assert!(claim_delivery("bundle", "1", &database_pool).await.unwrap());
+        mark_failed("bundle", "1", "output server unavailable", &database_pool).await.unwrap();
+        // a failed delivery waits for its backoff before it is processed again
+        assert!(!claim_delivery("bundle", "1", &database_pool).await.unwrap());
+        assert!(due_for_retry(&database_pool).await.unwrap().is_empty());
+
+        mark_transferred("bundle", "1", &database_pool).await.unwrap();
+        assert!(!claim_delivery("bundle", "1", &database_pool).await.unwrap());
+        assert!(due_for_retry(&database_pool).await.unwrap().is_empty());
+        // a new version of the same bundle is a new delivery
+        assert!(claim_delivery("bundle", "2", &database_pool).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn interrupted_delivery_is_retried() {
+        let database_pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&database_pool).await.unwrap();
+
+        assert!(claim_delivery("bundle", "1", &database_pool).await.unwrap());
+        mark_posting("bundle", "1", &database_pool).await.unwrap();
+        // transFAIR stopped before the response of the output server was recorded
+        let due = due_for_retry(&database_pool).await.unwrap();
+        assert_eq!(due.iter().map(|delivery| (delivery.bundle_id.as_str(), delivery.state)).collect::<Vec<_>>(), [("bundle", DeliveryState::Posting)]);
+        assert!(claim_delivery("bundle", "1", &database_pool).await.unwrap());
+    }
+
+    #[test]
+    fn reposted_deliveries_create_nothing_twice() {
+        let mut bundle: Bundle = serde_json::from_value(serde_json::json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [
+                { "resource": { "resourceType": "Condition", "subject": { "reference": "Patient/1" } }, "request": { "method": "POST", "url": "Condition" } },
+                { "resource": { "resourceType": "Patient", "id": "1" }, "request": { "method": "PUT", "url": "Patient/1" } }
+            ]
+        })).unwrap();
+        make_idempotent(&mut bundle, "delivery", "2");
+        let entries = bundle.entry.iter().flatten().collect::<Vec<_>>();
+        let request = entries[0].request.as_ref().unwrap();
+        assert_eq!(request.if_none_exist.as_deref(), Some("_tag=https://samply.de/fhir/transfair/delivery|delivery/2/0"));
+        // updates are idempotent already
+        assert_eq!(entries[1].request.as_ref().unwrap().if_none_exist, None);
+        let tags = &entries[1].resource.as_ref().unwrap().as_base_resource().meta().as_ref().unwrap().tag;
+        assert_eq!(tags[0].as_ref().unwrap().code.as_deref(), Some("delivery/2/1"));
+    }
+
+    #[test]
+    fn retry_backoff_doubles_until_capped() {
+        assert_eq!(retry_backoff(1), Duration::from_secs(60));
+        assert_eq!(retry_backoff(2), Duration::from_secs(120));
+        assert_eq!(retry_backoff(4), Duration::from_secs(480));
+        assert_eq!(retry_backoff(100), Duration::from_secs(6 * 60 * 60));
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeSet;
+
 use anyhow::Context;
 use chrono::NaiveDateTime;
 use fhir_sdk::r4b::{
@@ -6,9 +8,10 @@ use fhir_sdk::r4b::{
     types::Identifier,
 };
 use reqwest::{header, StatusCode, Url};
+use serde_json::json;
 use tracing::debug;
 
-use crate::{config::{Auth, ClientBuilderExt}, requests::DataRequestPayload, CLIENT};
+use crate::{config::{Auth, ClientBuilderExt}, linkage::{patient_search_parameters, references_patient}, requests::DataRequestPayload, CLIENT};
 
 #[derive(Clone, Debug)]
 pub struct FhirServer {
@@ -17,9 +20,39 @@ pub struct FhirServer {
 }
 
 impl FhirServer {
-    pub fn new(url: Url, auth: Auth) -> Self {
+    pub fn new(mut url: Url, auth: Auth) -> Self {
+        // endpoints are joined to the base url, which replaces its last path segment unless it ends with a slash
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
         Self { url, auth }
     }
+
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
+    // read the capability statement of the server, returns its fhir version
+    pub async fn check_metadata(&self) -> anyhow::Result<String> {
+        let metadata_endpoint = self.url.join("fhir/metadata").context("Unable to build metadata endpoint of fhir server")?;
+        debug!("Fetching capability statement from: {}", metadata_endpoint);
+        let response = CLIENT
+            .get(metadata_endpoint)
+            .add_auth(&self.auth)
+            .await?
+            .header(header::ACCEPT, "application/fhir+json")
+            .send()
+            .await
+            .context("Unable to reach fhir server")?;
+        if let Err(e) = response.error_for_status_ref() {
+            return Err(e).context(format!("Fhir server rejected metadata request: {}", response.text().await.unwrap_or_default()));
+        };
+        let capabilities = response
+            .json::<serde_json::Value>()
+            .await
+            .context("Unable to parse capability statement returned by fhir server")?;
+        Ok(capabilities["fhirVersion"].as_str().unwrap_or("unknown").to_owned())
+    }
     
     pub async fn post_data_request(
         &self,
@@ -52,43 +85,145 @@ impl FhirServer {
             .ok_or(anyhow::anyhow!("Fhir Server returned bundle without id."))
     }
 
-    // get data from fhir server that updated after a specified date
-    pub async fn pull_new_data(&self, last_update: NaiveDateTime) -> anyhow::Result<Bundle> {
-        let bundle_endpoint = format!("{}fhir/Bundle", self.url);
+    // get the first page of bundles from fhir server that updated after a specified date
+    pub async fn pull_new_data(&self, last_update: NaiveDateTime, page_size: u32) -> anyhow::Result<Bundle> {
+        let bundle_endpoint = self.url.join("fhir/Bundle").context("Unable to build bundle endpoint of input server")?;
         debug!("Fetching new data from: {}", bundle_endpoint);
-        let query = vec![("_lastUpdated", format!("gt{}", last_update.format("%Y-%m-%dT%H:%M:%S").to_string()))];
+        let query = vec![
+            ("_lastUpdated", format!("gt{}", last_update.format("%Y-%m-%dT%H:%M:%S"))),
+            ("_count", page_size.to_string()),
+        ];
+        self.fetch_bundle(bundle_endpoint, &query).await
+    }
+
+    // follow the next link of a search result, returns None if the given page was the last one
+    pub async fn pull_next_page(&self, page: &Bundle) -> anyhow::Result<Option<Bundle>> {
+        let Some(next_link) = page.link.iter().flatten().find(|link| link.relation == "next") else {
+            return Ok(None);
+        };
+        // servers may return relative links, so they are resolved against the base url
+        let next_url = self.url
+            .join(&next_link.url)
+            .with_context(|| format!("Fhir server returned invalid next link: {}", next_link.url))?;
+        debug!("Fetching next page from: {}", next_url);
+        self.fetch_bundle(next_url, &[]).await.map(Some)
+    }
+
+    // read a specific version of a delivery bundle, used to retry deliveries that failed before
+    pub async fn pull_bundle(&self, bundle_id: &str, version_id: &str) -> anyhow::Result<Bundle> {
+        let bundle_path = match version_id {
+            "" => format!("fhir/Bundle/{bundle_id}"),
+            version_id => format!("fhir/Bundle/{bundle_id}/_history/{version_id}"),
+        };
+        let bundle_endpoint = self.url.join(&bundle_path).context("Unable to build bundle endpoint of input server")?;
+        debug!("Fetching bundle from: {}", bundle_endpoint);
+        self.fetch_bundle(bundle_endpoint, &[]).await
+    }
+
+    async fn fetch_bundle(&self, url: Url, query: &[(&str, String)]) -> anyhow::Result<Bundle> {
         let response = CLIENT
-            .get(bundle_endpoint)
+            .get(url)
             .add_auth(&self.auth)
             .await?
-            .query(&query)
+            .query(query)
             .send()
             .await
-            .context("Unable to query data from input server")?;
-        // TODO: Account for more samples using the bundels next link
+            .context("Unable to query data from fhir server")?;
+        if let Err(e) = response.error_for_status_ref() {
+            return Err(e).context(format!("Fhir server rejected query: {}", response.text().await.unwrap_or_default()));
+        };
         response
             .json::<Bundle>()
             .await
-            .context("Unable to response from input server")
+            .context("Unable to parse response from fhir server")
     }
 
     // post a fhir bundle to a specified fhir server
     pub async fn post_data(&self, bundle: &Bundle) -> anyhow::Result<reqwest::Response> {
         let bundle_endpoint = format!("{}fhir", self.url);
         debug!("Posting data to output fhir server: {}", bundle_endpoint);
-        CLIENT
+        let response = CLIENT
             .post(bundle_endpoint)
             .add_auth(&self.auth)
             .await?
             .json(&bundle)
             .send()
             .await
-            .context("Unable to post data to output fhir server")
+            .context("Unable to post data to output fhir server")?;
+        if let Err(e) = response.error_for_status_ref() {
+            return Err(e).context(format!("Output fhir server rejected data: {}", response.text().await.unwrap_or_default()));
+        };
+        Ok(response)
+    }
+
+    // delete a patient and all resources of its compartment referencing it by id or identifier. The resources are searched
+    // by every compartment search parameter and deleted by id in one transaction, as servers reject conditional deletes
+    // matching several resources.
+    pub async fn delete_patient_data(&self, identifier_system: &str, identifier_value: &str) -> anyhow::Result<()> {
+        let identifier = format!("{identifier_system}|{identifier_value}");
+        let query = |param: &str, value: &str| form_urlencoded::Serializer::new(String::new()).append_pair(param, value).finish();
+        // servers may ignore search parameters they don't support and return all resources, so results are checked
+        let patient_ids = self.search(&format!("Patient?{}", query("identifier", &identifier))).await?
+            .into_iter()
+            .filter_map(|resource| match resource {
+                Resource::Patient(patient) if patient.get_identifier(identifier_system).is_some_and(|id| id.value.as_deref() == Some(identifier_value)) => patient.id.clone(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut resources = patient_ids.iter().map(|id| format!("Patient/{id}")).collect::<BTreeSet<_>>();
+        let patient_references = resources.iter().cloned().collect::<Vec<_>>().join(",");
+        for (rt, param) in patient_search_parameters() {
+            let mut searches = vec![query(&format!("{param}:identifier"), &identifier)];
+            if !patient_ids.is_empty() {
+                searches.push(query(param, &patient_references));
+            }
+            for search in searches {
+                for resource in self.search(&format!("{rt}?{search}")).await? {
+                    if let Some(id) = resource.as_base_resource().id()
+                        && references_patient(&resource, &patient_ids, identifier_system, identifier_value) {
+                        resources.insert(format!("{rt}/{id}"));
+                    }
+                }
+            }
+        }
+        if resources.is_empty() {
+            debug!("No data of patient {identifier} found in {}", self.url);
+            return Ok(());
+        }
+        let entries = resources.iter().map(|url| json!({"request": {"method": "DELETE", "url": url}})).collect::<Vec<_>>();
+        let bundle = json!({"resourceType": "Bundle", "type": "transaction", "entry": entries});
+
+        let bundle_endpoint = format!("{}fhir", self.url);
+        debug!("Deleting {} resources of patient {identifier} from {bundle_endpoint}", resources.len());
+        let response = CLIENT
+            .post(bundle_endpoint)
+            .add_auth(&self.auth)
+            .await?
+            .json(&bundle)
+            .send()
+            .await
+            .context("Unable to delete data from output fhir server")?;
+        if let Err(e) = response.error_for_status_ref() {
+            return Err(e).context(format!("Output fhir server rejected deletion: {}", response.text().await.unwrap_or_default()));
+        };
+        Ok(())
+    }
+
+    // resources found by a search, e.g. "Condition?subject=Patient/123", on all pages of the result
+    async fn search(&self, search: &str) -> anyhow::Result<Vec<Resource>> {
+        let search_endpoint = self.url.join(&format!("fhir/{search}")).context("Unable to build search endpoint of fhir server")?;
+        let mut page = Some(self.fetch_bundle(search_endpoint, &[]).await?);
+        let mut resources = Vec::new();
+        while let Some(bundle) = page {
+            resources.extend(bundle.entry.iter().flatten().filter_map(|entry| entry.resource.clone()));
+            page = self.pull_next_page(&bundle).await?;
+        }
+        Ok(resources)
     }
 }
 
 pub trait PatientExt: Sized {
-    fn pseudonymize(self, exchange_id_system: &str) -> axum::response::Result<Self>;
+    fn pseudonymize(self, exchange_id_system: &str) -> Result<Self, (StatusCode, String)>;
     fn add_id_request(self, id: String) -> Self;
     fn get_identifier(&self, id_system: &str) -> Option<&Identifier>;
     fn get_identifier_mut(&mut self, id_system: &str) -> Option<&mut Identifier>;
@@ -119,7 +254,7 @@ impl PatientExt for Patient {
             .find(|x| x.system.as_deref() == Some(id_system))
     }
 
-    fn pseudonymize(self, exchange_id_system: &str) -> axum::response::Result<Self> {
+    fn pseudonymize(self, exchange_id_system: &str) -> Result<Self, (StatusCode, String)> {
         let Some(exchange_identifier) = self
             .identifier
             .iter()
@@ -129,7 +264,7 @@ impl PatientExt for Patient {
                 return Err((
                     StatusCode::BAD_REQUEST,
                     format!("Request did not contain identifier of system {exchange_id_system}")
-                ).into());
+                ));
             };
         let pseudonymized_patient = Patient::builder()
             .identifier(vec![exchange_identifier.clone()])
@@ -144,10 +279,10 @@ impl PatientExt for Patient {
     }
 }
 
-impl Into<Bundle> for DataRequestPayload {
-    fn into(self) -> Bundle {
+impl From<DataRequestPayload> for Bundle {
+    fn from(payload: DataRequestPayload) -> Bundle {
         let patient_entry = BundleEntry::builder()
-            .resource(Resource::from(self.patient))
+            .resource(Resource::from(payload.patient))
             .request(
                 BundleEntryRequest::builder()
                     .method(fhir_sdk::r4b::codes::HTTPVerb::Post)
@@ -158,7 +293,7 @@ impl Into<Bundle> for DataRequestPayload {
             .build()
             .unwrap();
 
-        let consent_entry = self.consent.map(|c| {
+        let consent_entry = payload.consent.map(|c| {
             BundleEntry::builder()
                 .resource(Resource::from(c))
                 .request(
@@ -186,8 +321,8 @@ impl Into<Bundle> for DataRequestPayload {
 
 #[cfg(test)]
 mod tests {
-    use crate::fhir::PatientExt;
-    use fhir_sdk::r4b::{codes::IdentifierUse, resources::Patient};
+    use crate::{config::Auth, fhir::{FhirServer, PatientExt}};
+    use fhir_sdk::r4b::{codes::{BundleType, IdentifierUse}, resources::{Bundle, Patient}};
 
     #[test]
     fn add_id_request() {
@@ -203,4 +338,20 @@ mod tests {
         // expect the new identifier to not have a value
         assert_eq!(identifier.value, None);
     }
+
+    #[tokio::test]
+    async fn pull_next_page_stops_on_last_page() {
+        let server = FhirServer::new("http://localhost:8086".parse().unwrap(), Auth::None);
+        let last_page = Bundle::builder().r#type(BundleType::Searchset).build().unwrap();
+        // a page without next link must end the pagination without contacting the server
+        assert!(server.pull_next_page(&last_page).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn base_url_keeps_its_path() {
+        let server = FhirServer::new("http://localhost:8086/base".parse().unwrap(), Auth::None);
+        assert_eq!(server.url.join("fhir/Bundle").unwrap().as_str(), "http://localhost:8086/base/fhir/Bundle");
+        let server = FhirServer::new("http://localhost:8086/base/".parse().unwrap(), Auth::None);
+        assert_eq!(server.url.as_str(), "http://localhost:8086/base/");
+    }
 }
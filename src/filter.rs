@@ -0,0 +1,351 @@
+use std::{collections::{BTreeMap, HashSet}, fmt::Display, str::FromStr};
+
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use fhir_sdk::r4b::resources::{Bundle, Resource, ResourceType};
+use serde_json::Value;
+
+// Elements holding the clinically relevant date of a resource, the first one present is used
+const DATE_PATHS: &[&str] = &[
+    "effectiveDateTime", "effectiveInstant", "effectivePeriod.start",
+    "onsetDateTime", "onsetPeriod.start", "recordedDate",
+    "performedDateTime", "performedPeriod.start",
+    "collection.collectedDateTime", "collection.collectedPeriod.start",
+    "occurrenceDateTime", "occurrencePeriod.start",
+    "period.start", "authoredOn", "issued", "date",
+];
+
+// Decides which resources of a linked delivery are posted to the output server.
+// Empty allow lists allow everything, deny rules always take precedence.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct ResourceFilter {
+    // Resource types to transfer, e.g. "Patient,Condition,Specimen"
+    #[clap(long, env, value_delimiter = ',')]
+    pub filter_allow_resource_types: Vec<ResourceType>,
+    #[clap(long, env, value_delimiter = ',')]
+    pub filter_deny_resource_types: Vec<ResourceType>,
+    // Profile URLs (meta.profile) of which a resource needs at least one, respectively none
+    #[clap(long, env, value_delimiter = ',')]
+    pub filter_allow_profiles: Vec<String>,
+    #[clap(long, env, value_delimiter = ',')]
+    pub filter_deny_profiles: Vec<String>,
+    // Codings in the form "<system>|<code>" or "<system>" for all codes of a system, checked against code (or type) of a resource
+    #[clap(long, env, value_delimiter = ',')]
+    pub filter_allow_codes: Vec<CodeFilter>,
+    #[clap(long, env, value_delimiter = ',')]
+    pub filter_deny_codes: Vec<CodeFilter>,
+    // Inclusive range for the clinically relevant date of a resource (e.g. effective, onset, collection), resources without such a date are kept
+    #[clap(long, env)]
+    pub filter_from: Option<NaiveDate>,
+    #[clap(long, env)]
+    pub filter_until: Option<NaiveDate>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeFilter {
+    pub system: String,
+    pub code: Option<String>,
+}
+
+impl FromStr for CodeFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (system, code) = match s.split_once('|') {
+            Some((system, code)) => (system, Some(code.to_owned())),
+            None => (s, None),
+        };
+        if system.is_empty() || code.as_deref() == Some("") {
+            return Err(anyhow!("Code filters should be in the form of '<system>|<code>' or '<system>'"));
+        }
+        Ok(Self { system: system.to_owned(), code })
+    }
+}
+
+impl CodeFilter {
+    pub fn matches(&self, coding: &Value) -> bool {
+        coding.get("system").and_then(Value::as_str) == Some(self.system.as_str())
+            && self.code.as_ref().is_none_or(|code| coding.get("code").and_then(Value::as_str) == Some(code.as_str()))
+    }
+}
+
+// Number of resources by type, e.g. of those removed from a delivery
+#[derive(Debug, Default, PartialEq)]
+pub struct ResourceCounts(BTreeMap<String, usize>);
+
+impl ResourceCounts {
+    pub fn add(&mut self, rt: ResourceType) {
+        *self.0.entry(rt.to_string()).or_default() += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Display for ResourceCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.0.values().sum::<usize>();
+        let by_type = self.0.iter().map(|(rt, count)| format!("{rt}: {count}")).collect::<Vec<_>>().join(", ");
+        write!(f, "{total} resource(s) ({by_type})")
+    }
+}
+
+impl ResourceFilter {
+    // Removes all resources that are not allowed from the bundle, see retain_resources
+    pub fn apply(&self, bundle: &mut Bundle) -> ResourceCounts {
+        retain_resources(bundle, |resource| self.allows(resource))
+    }
+
+    fn allows(&self, resource: &Resource) -> bool {
+        let rt = resource.resource_type();
+        if self.filter_deny_resource_types.contains(&rt)
+            || (!self.filter_allow_resource_types.is_empty() && !self.filter_allow_resource_types.contains(&rt)) {
+            return false;
+        }
+        if self.filter_allow_profiles.is_empty() && self.filter_deny_profiles.is_empty()
+            && self.filter_allow_codes.is_empty() && self.filter_deny_codes.is_empty()
+            && self.filter_from.is_none() && self.filter_until.is_none() {
+            return true;
+        }
+
+        let json = serde_json::to_value(resource).expect("Resources can always be serialized");
+        let profiles = json.pointer("/meta/profile").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str).collect::<Vec<_>>();
+        if profiles.iter().any(|profile| self.filter_deny_profiles.iter().any(|denied| denied == profile))
+            || (!self.filter_allow_profiles.is_empty() && !profiles.iter().any(|profile| self.filter_allow_profiles.iter().any(|allowed| allowed == profile))) {
+            return false;
+        }
+
+        let codings = codings(&json);
+        if codings.iter().any(|coding| self.filter_deny_codes.iter().any(|filter| filter.matches(coding)))
+            || (!self.filter_allow_codes.is_empty() && !codings.is_empty()
+                && !codings.iter().any(|coding| self.filter_allow_codes.iter().any(|filter| filter.matches(coding)))) {
+            return false;
+        }
+
+        let Some(date) = clinical_date(&json) else {
+            return true;
+        };
+        let from = self.filter_from.map(|from| from.to_string());
+        let until = self.filter_until.map(|until| until.to_string());
+        date_within(date, from.as_deref(), until.as_deref())
+    }
+}
+
+// Removes the resources that are not to be kept from the bundle and returns their number. The patient is always kept,
+// everything else belongs to it. Resources referencing a removed one are removed as well, as the output server rejects
+// transactions with references it can't resolve, while the patient only loses such references.
+pub fn retain_resources(bundle: &mut Bundle, mut keep: impl FnMut(&Resource) -> bool) -> ResourceCounts {
+    let mut removed = ResourceCounts::default();
+    let mut dangling = HashSet::new();
+    bundle.entry.retain(|entry| {
+        let Some((full_url, resource)) = entry.as_ref().and_then(|entry| Some((entry.full_url.as_deref(), entry.resource.as_ref()?))) else {
+            return true;
+        };
+        if resource.resource_type() == ResourceType::Patient || keep(resource) {
+            return true;
+        }
+        removed.add(resource.resource_type());
+        dangling.extend(literal_references(full_url, resource));
+        false
+    });
+    // removing a resource may leave further references dangling
+    while !dangling.is_empty() {
+        let removed_references = std::mem::take(&mut dangling);
+        bundle.entry.retain_mut(|entry| {
+            let Some(entry) = entry.as_mut() else {
+                return true;
+            };
+            let Some(resource) = entry.resource.as_mut() else {
+                return true;
+            };
+            let mut json = serde_json::to_value(&*resource).expect("Resources can always be serialized");
+            if !remove_references(&mut json, &removed_references) {
+                return true;
+            }
+            if let Resource::Patient(_) = resource {
+                // a patient that would be invalid without the reference is kept as it is and rejected by the output server
+                if let Ok(patient) = serde_json::from_value(json) {
+                    *resource = patient;
+                }
+                return true;
+            }
+            removed.add(resource.resource_type());
+            dangling.extend(literal_references(entry.full_url.as_deref(), resource));
+            false
+        });
+    }
+    removed
+}
+
+// References by which other entries of the bundle may refer to the resource
+fn literal_references(full_url: Option<&str>, resource: &Resource) -> Vec<String> {
+    let id = serde_json::to_value(resource).expect("Resources can always be serialized").get("id").and_then(Value::as_str).map(|id| format!("{}/{id}", resource.resource_type()));
+    full_url.map(ToOwned::to_owned).into_iter().chain(id).collect()
+}
+
+// Removes the literal references to the given targets and the elements left empty, returns whether any was removed
+fn remove_references(value: &mut Value, targets: &HashSet<String>) -> bool {
+    let mut removed = false;
+    match value {
+        Value::Object(object) => {
+            if object.get("reference").and_then(Value::as_str).is_some_and(|reference| targets.contains(reference)) {
+                object.remove("reference");
+                removed = true;
+            }
+            for child in object.values_mut() {
+                removed |= remove_references(child, targets);
+            }
+            if removed {
+                object.retain(|_, child| !is_empty(child));
+            }
+        }
+        Value::Array(items) => {
+            for item in items.iter_mut() {
+                removed |= remove_references(item, targets);
+            }
+            if removed {
+                items.retain(|item| !is_empty(item));
+            }
+        }
+        _ => {}
+    }
+    removed
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Object(object) => object.is_empty(),
+        Value::Array(items) => items.is_empty(),
+        _ => false,
+    }
+}
+
+// Codings of the code of a resource, or of its type for resources without a code (e.g. Specimen)
+fn codings(resource: &Value) -> Vec<&Value> {
+    let concepts = match resource.get("code").or_else(|| resource.get("type")) {
+        Some(Value::Array(concepts)) => concepts.iter().collect(),
+        Some(concept) => vec![concept],
+        None => vec![],
+    };
+    concepts
+        .into_iter()
+        .filter_map(|concept| concept.get("coding").and_then(Value::as_array))
+        .flatten()
+        .collect()
+}
+
+pub fn clinical_date(resource: &Value) -> Option<&str> {
+    DATE_PATHS.iter().find_map(|path| {
+        path.split('.')
+            .try_fold(resource, |value, field| value.get(field))
+            .and_then(Value::as_str)
+    })
+}
+
+// Compares FHIR dates and dateTimes by day. Dates may be partial ("2020" or "2020-04"), so they are compared with the bounds at their precision.
+pub fn date_within(date: &str, start: Option<&str>, end: Option<&str>) -> bool {
+    let day = |date: &str| date.get(..10).unwrap_or(date).to_owned();
+    let date = day(date);
+    let compare = |bound: &str| {
+        let bound = day(bound);
+        let precision = date.len().min(bound.len());
+        date[..precision].cmp(&bound[..precision])
+    };
+    start.is_none_or(|start| compare(start).is_ge()) && end.is_none_or(|end| compare(end).is_le())
+}
+
+#[cfg(test)]
+mod tests {
+    use fhir_sdk::r4b::resources::{Bundle, ResourceType};
+    use serde_json::json;
+
+    use super::ResourceFilter;
+
+    fn bundle() -> Bundle {
+        serde_json::from_value(json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [
+                {"resource": {"resourceType": "Patient", "id": "1"}},
+                {"resource": {
+                    "resourceType": "Observation", "id": "2", "status": "final",
+                    "code": {"coding": [{"system": "http://loinc.org", "code": "29463-7"}]},
+                    "effectiveDateTime": "2023-11-02T10:00:00+01:00"
+                }},
+                {"resource": {
+                    "resourceType": "Observation", "id": "3", "status": "final",
+                    "code": {"coding": [{"system": "http://loinc.org", "code": "8302-2"}]},
+                    "effectiveDateTime": "2019-05"
+                }},
+                {"resource": {"resourceType": "Procedure", "id": "4", "status": "completed", "subject": {"reference": "Patient/1"}}},
+                {"resource": {"resourceType": "DiagnosticReport", "id": "5", "status": "final", "code": {"text": "Lipids"}, "result": [{"reference": "Observation/3"}]}}
+            ]
+        })).unwrap()
+    }
+
+    fn kept(bundle: &Bundle) -> Vec<String> {
+        bundle.entry.iter().flatten().filter_map(|entry| entry.resource.as_ref()).map(|resource| {
+            serde_json::to_value(resource).unwrap()["id"].as_str().unwrap().to_owned()
+        }).collect()
+    }
+
+    #[test]
+    fn filter_by_resource_type_and_code() {
+        let filter = ResourceFilter {
+            filter_deny_resource_types: vec![ResourceType::Procedure],
+            filter_allow_codes: vec!["http://loinc.org|29463-7".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut bundle = bundle();
+        let filtered = filter.apply(&mut bundle);
+        // the report would reference the removed observation
+        assert_eq!(kept(&bundle), ["1", "2"]);
+        assert_eq!(filtered.to_string(), "3 resource(s) (DiagnosticReport: 1, Observation: 1, Procedure: 1)");
+    }
+
+    #[test]
+    fn filter_by_date() {
+        let filter = ResourceFilter {
+            filter_from: Some("2019-05-31".parse().unwrap()),
+            filter_until: Some("2023-11-01".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut bundle = bundle();
+        let filtered = filter.apply(&mut bundle);
+        // partial dates count as within the range if they overlap it
+        assert_eq!(kept(&bundle), ["1", "3", "4", "5"]);
+        assert_eq!(filtered.to_string(), "1 resource(s) (Observation: 1)");
+    }
+
+    #[test]
+    fn filter_keeps_patient_without_dangling_references() {
+        let filter = ResourceFilter {
+            filter_allow_resource_types: vec![ResourceType::Observation],
+            ..Default::default()
+        };
+        let mut bundle: Bundle = serde_json::from_value(json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [
+                {"fullUrl": "urn:uuid:0c2a7f5e-4f2e-4c47-9b8e-2d1f6c3a9e01", "resource": {"resourceType": "Organization", "id": "org"}},
+                {"resource": {
+                    "resourceType": "Patient", "id": "1",
+                    "managingOrganization": {"reference": "urn:uuid:0c2a7f5e-4f2e-4c47-9b8e-2d1f6c3a9e01"},
+                    "generalPractitioner": [{"reference": "Organization/org", "display": "Practice"}, {"reference": "Practitioner/external"}]
+                }},
+                {"resource": {
+                    "resourceType": "Observation", "id": "2", "status": "final", "code": {"text": "Weight"},
+                    "subject": {"reference": "Patient/1"}, "performer": [{"reference": "Organization/org"}]
+                }}
+            ]
+        })).unwrap();
+        let filtered = filter.apply(&mut bundle);
+        assert_eq!(kept(&bundle), ["1"]);
+        assert_eq!(filtered.to_string(), "2 resource(s) (Observation: 1, Organization: 1)");
+        // references to resources outside of the bundle are kept
+        let patient = serde_json::to_value(bundle.entry[0].as_ref().unwrap().resource.as_ref().unwrap()).unwrap();
+        assert_eq!(patient.get("managingOrganization"), None);
+        assert_eq!(patient["generalPractitioner"], json!([{"display": "Practice"}, {"reference": "Practitioner/external"}]));
+    }
+}
@@ -0,0 +1,425 @@
+//! Replaces the exchange identifier in delivered resources with the project identifier of the data request
+use std::collections::HashMap;
+
+use fhir_sdk::r4b::{codes::HTTPVerb, resources::{Bundle, Resource, ResourceType}};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{fhir::PatientExt, ttp::ProjectDomain, DicAppState};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LinkageError {
+    #[error("entry in response did not contain a resource.")]
+    EntryWithoutResource,
+    #[error("{0}: The provided resource didn't contain a reference.")]
+    NoReference(ResourceType),
+    #[error("{0}: Can't link resource due to missing identifier")]
+    MissingIdentifier(ResourceType),
+    #[error("{0}: DataRequest didn't have identifier value for project id stored")]
+    MissingIdentifierValue(ResourceType),
+    #[error("{0}: Identifier for linkage didn't contain a system")]
+    IdentifierWithoutSystem(ResourceType),
+    #[error("{0}: Identifier for linkage was not of configured type")]
+    WrongIdentifierType(ResourceType),
+    #[error("{0}: Unable to link identifier to any data request")]
+    IdentifierNotLinkable(ResourceType)
+}
+
+/// Resource types of the FHIR R4B patient compartment together with their compartment search parameters and
+/// the elements behind them, see https://hl7.org/fhir/R4B/compartmentdefinition-patient.html.
+/// Elements are given as paths of json field names, lists are traversed implicitly.
+static PATIENT_COMPARTMENT: &[(ResourceType, &[&str], &[&str])] = &[
+    (ResourceType::Account, &["subject"], &["subject"]),
+    (ResourceType::AdverseEvent, &["subject"], &["subject"]),
+    (ResourceType::AllergyIntolerance, &["patient", "recorder", "asserter"], &["patient", "recorder", "asserter"]),
+    (ResourceType::Appointment, &["actor"], &["participant.actor"]),
+    (ResourceType::AppointmentResponse, &["actor"], &["actor"]),
+    (ResourceType::AuditEvent, &["patient"], &["agent.who", "entity.what"]),
+    (ResourceType::Basic, &["patient", "author"], &["subject", "author"]),
+    (ResourceType::BodyStructure, &["patient"], &["patient"]),
+    (ResourceType::CarePlan, &["patient", "performer"], &["subject", "activity.detail.performer"]),
+    (ResourceType::CareTeam, &["patient", "participant"], &["subject", "participant.member"]),
+    (ResourceType::ChargeItem, &["subject"], &["subject"]),
+    (ResourceType::Claim, &["patient", "payee"], &["patient", "payee.party"]),
+    (ResourceType::ClaimResponse, &["patient"], &["patient"]),
+    (ResourceType::ClinicalImpression, &["subject"], &["subject"]),
+    (ResourceType::Communication, &["subject", "sender", "recipient"], &["subject", "sender", "recipient"]),
+    (ResourceType::CommunicationRequest, &["subject", "sender", "recipient", "requester"], &["subject", "sender", "recipient", "requester"]),
+    (ResourceType::Composition, &["subject", "author", "attester"], &["subject", "author", "attester.party"]),
+    (ResourceType::Condition, &["patient", "asserter"], &["subject", "asserter"]),
+    (ResourceType::Consent, &["patient"], &["patient"]),
+    (ResourceType::Coverage, &["policy-holder", "subscriber", "beneficiary", "payor"], &["policyHolder", "subscriber", "beneficiary", "payor"]),
+    (ResourceType::CoverageEligibilityRequest, &["patient"], &["patient"]),
+    (ResourceType::CoverageEligibilityResponse, &["patient"], &["patient"]),
+    (ResourceType::DetectedIssue, &["patient"], &["patient"]),
+    (ResourceType::DeviceRequest, &["subject", "performer"], &["subject", "performer"]),
+    (ResourceType::DeviceUseStatement, &["subject"], &["subject"]),
+    (ResourceType::DiagnosticReport, &["subject"], &["subject"]),
+    (ResourceType::DocumentManifest, &["subject", "author", "recipient"], &["subject", "author", "recipient"]),
+    (ResourceType::DocumentReference, &["subject", "author"], &["subject", "author"]),
+    (ResourceType::Encounter, &["patient"], &["subject"]),
+    (ResourceType::EnrollmentRequest, &["patient"], &["candidate"]),
+    (ResourceType::EpisodeOfCare, &["patient"], &["patient"]),
+    (ResourceType::ExplanationOfBenefit, &["patient", "payee"], &["patient", "payee.party"]),
+    (ResourceType::FamilyMemberHistory, &["patient"], &["patient"]),
+    (ResourceType::Flag, &["patient"], &["subject"]),
+    (ResourceType::Goal, &["patient"], &["subject"]),
+    (ResourceType::Group, &["member"], &["member.entity"]),
+    (ResourceType::ImagingStudy, &["patient"], &["subject"]),
+    (ResourceType::Immunization, &["patient"], &["patient"]),
+    (ResourceType::ImmunizationEvaluation, &["patient"], &["patient"]),
+    (ResourceType::ImmunizationRecommendation, &["patient"], &["patient"]),
+    (ResourceType::Invoice, &["subject", "patient", "recipient"], &["subject", "recipient"]),
+    (ResourceType::List, &["subject", "source"], &["subject", "source"]),
+    (ResourceType::MeasureReport, &["patient"], &["subject"]),
+    (ResourceType::Media, &["subject"], &["subject"]),
+    (ResourceType::MedicationAdministration, &["patient", "performer", "subject"], &["subject", "performer.actor"]),
+    (ResourceType::MedicationDispense, &["subject", "patient", "receiver"], &["subject", "receiver"]),
+    (ResourceType::MedicationRequest, &["subject"], &["subject"]),
+    (ResourceType::MedicationStatement, &["subject"], &["subject"]),
+    (ResourceType::MolecularSequence, &["patient"], &["patient"]),
+    (ResourceType::NutritionOrder, &["patient"], &["patient"]),
+    (ResourceType::Observation, &["subject", "performer"], &["subject", "performer"]),
+    (ResourceType::Person, &["patient"], &["link.target"]),
+    (ResourceType::Procedure, &["patient", "performer"], &["subject", "performer.actor"]),
+    (ResourceType::Provenance, &["patient"], &["target"]),
+    (ResourceType::QuestionnaireResponse, &["subject", "author"], &["subject", "author"]),
+    (ResourceType::RelatedPerson, &["patient"], &["patient"]),
+    (ResourceType::RequestGroup, &["subject", "participant"], &["subject", "action.participant"]),
+    (ResourceType::ResearchSubject, &["individual"], &["individual"]),
+    (ResourceType::RiskAssessment, &["subject"], &["subject"]),
+    (ResourceType::Schedule, &["actor"], &["actor"]),
+    (ResourceType::ServiceRequest, &["subject", "performer"], &["subject", "performer"]),
+    (ResourceType::Specimen, &["subject"], &["subject"]),
+    (ResourceType::SupplyDelivery, &["patient"], &["patient"]),
+    (ResourceType::SupplyRequest, &["subject"], &["deliverTo"]),
+    (ResourceType::VisionPrescription, &["patient"], &["patient"]),
+];
+
+// Compartment search parameters of each resource type of the patient compartment
+pub fn patient_search_parameters() -> impl Iterator<Item = (ResourceType, &'static str)> {
+    PATIENT_COMPARTMENT.iter().flat_map(|(rt, params, _)| params.iter().map(|param| (*rt, *param)))
+}
+
+// Whether the resource references one of the patients, by literal reference to their ids or by identifier, in the elements
+// of the patient compartment
+pub fn references_patient(resource: &Resource, patient_ids: &[String], identifier_system: &str, identifier_value: &str) -> bool {
+    let rt = resource.resource_type();
+    let Some((.., paths)) = PATIENT_COMPARTMENT.iter().find(|(compartment_rt, ..)| *compartment_rt == rt) else {
+        return false;
+    };
+    let mut json = serde_json::to_value(resource).expect("Resources can always be serialized");
+    let mut references = false;
+    for path in paths.iter() {
+        let path = path.split('.').collect::<Vec<_>>();
+        for_each_element(&mut json, &path, &mut |reference| {
+            // relative or absolute references, possibly to a version of the patient
+            let literal_id = reference.get("reference").and_then(Value::as_str)
+                .and_then(|literal| literal.rfind("Patient/").map(|index| &literal[index + "Patient/".len()..]))
+                .and_then(|id| id.split('/').next());
+            let identifier = reference.get("identifier");
+            references |= literal_id.is_some_and(|id| patient_ids.iter().any(|patient_id| patient_id == id))
+                || (identifier.and_then(|identifier| identifier.get("system")).and_then(Value::as_str) == Some(identifier_system)
+                    && identifier.and_then(|identifier| identifier.get("value")).and_then(Value::as_str) == Some(identifier_value));
+        });
+    }
+    references
+}
+
+pub async fn replace_exchange_identifiers(data_request_identifier: &str, new_data: &mut Bundle, domain: &ProjectDomain, state: &DicAppState) -> sqlx::Result<Vec<Result<ResourceType, LinkageError>>> {
+    let data_request = sqlx::query!(
+        "SELECT project_id FROM data_requests WHERE id = $1",
+        data_request_identifier
+    ).fetch_optional(&state.database_pool).await?;
+    let project_id: Result<String, fn(ResourceType) -> LinkageError> = match data_request {
+        Some(data_request) => data_request.project_id.ok_or(LinkageError::MissingIdentifierValue),
+        None => Err(LinkageError::IdentifierNotLinkable),
+    };
+    let project_id = match project_id {
+        Ok(project_id) => project_id,
+        Err(error) => return Ok(new_data.entry.iter().flatten().map(|entry| match &entry.resource {
+            Some(resource) => Err(error(resource.resource_type())),
+            None => Err(LinkageError::EntryWithoutResource),
+        }).collect()),
+    };
+
+    let linkage = Linkage::for_bundle(new_data, &state.config.exchange_id_system, &domain.project_id_system, &project_id);
+    Ok(new_data.entry.iter_mut().flatten().map(|entry| {
+        let Some(resource) = &mut entry.resource else {
+            return Err(LinkageError::EntryWithoutResource)
+        };
+        linkage.link_resource(resource)
+    }).collect())
+}
+
+struct Linkage<'a> {
+    exchange_id_system: &'a str,
+    project_id_system: &'a str,
+    project_id: &'a str,
+    // literal references (fullUrl, relative and absolute url) of the delivered patient, mapped to its fullUrl in the linked bundle
+    patient_references: HashMap<String, String>,
+}
+
+impl<'a> Linkage<'a> {
+    // Collects the literal references of the delivered patient. Its entry gets a urn:uuid fullUrl, so the
+    // output server resolves all references to the patient it creates, regardless of its id in the input server.
+    // Its request becomes a conditional update by project identifier, as requests by id of the input server would
+    // address the patient by the exchange identifier or create it anew with every delivery.
+    fn for_bundle(bundle: &mut Bundle, exchange_id_system: &'a str, project_id_system: &'a str, project_id: &'a str) -> Self {
+        let mut patient_references = HashMap::new();
+        for entry in bundle.entry.iter_mut().flatten() {
+            let Some(Resource::Patient(patient)) = &mut entry.resource else {
+                continue;
+            };
+            if patient.get_identifier(exchange_id_system).is_none() {
+                continue;
+            }
+            let full_url = match &entry.full_url {
+                Some(full_url) if full_url.starts_with("urn:uuid:") => full_url.clone(),
+                _ => format!("urn:uuid:{}", Uuid::new_v4()),
+            };
+            let mut aliases = vec![full_url.clone()];
+            // the id of the input server would conflict with the patient found by the conditional update
+            if let Some(id) = patient.id.take() {
+                aliases.push(format!("Patient/{id}"));
+            }
+            if let Some(original_full_url) = entry.full_url.replace(full_url.clone()) {
+                if let Some(index) = original_full_url.rfind("Patient/") {
+                    aliases.push(original_full_url[index..].to_owned());
+                }
+                aliases.push(original_full_url);
+            }
+            for alias in aliases {
+                patient_references.insert(alias, full_url.clone());
+            }
+            if let Some(request) = &mut entry.request {
+                let query = form_urlencoded::Serializer::new(String::new())
+                    .append_pair("identifier", &format!("{project_id_system}|{project_id}"))
+                    .finish();
+                request.method = HTTPVerb::Put;
+                request.url = format!("Patient?{query}");
+                request.if_none_exist = None;
+            }
+        }
+        Self { exchange_id_system, project_id_system, project_id, patient_references }
+    }
+
+    // Replaces every reference to the patient by exchange identifier or literal reference with the project identifier
+    fn link_resource(&self, resource: &mut Resource) -> Result<ResourceType, LinkageError> {
+        let rt = resource.resource_type();
+        if let Resource::Patient(patient) = resource {
+            let Some(identifier) = patient.get_identifier_mut(self.exchange_id_system) else {
+                return Err(LinkageError::MissingIdentifier(rt))
+            };
+            identifier.system = Some(self.project_id_system.to_owned());
+            identifier.value = Some(self.project_id.to_owned());
+            return Ok(rt);
+        }
+
+        let Some((.., paths)) = PATIENT_COMPARTMENT.iter().find(|(compartment_rt, ..)| *compartment_rt == rt) else {
+            // resources outside of the patient compartment don't reference the patient and are transferred as they are
+            return Ok(rt);
+        };
+
+        let mut json = serde_json::to_value(&*resource).expect("Resources can always be serialized");
+        let mut linkage = ReferenceLinkage::NoReference;
+        for path in paths.iter() {
+            let path = path.split('.').collect::<Vec<_>>();
+            for_each_element(&mut json, &path, &mut |reference| {
+                linkage = linkage.max(self.link_reference(reference));
+            });
+        }
+
+        match linkage {
+            ReferenceLinkage::NoReference => Err(LinkageError::NoReference(rt)),
+            ReferenceLinkage::MissingIdentifier => Err(LinkageError::MissingIdentifier(rt)),
+            ReferenceLinkage::IdentifierWithoutSystem => Err(LinkageError::IdentifierWithoutSystem(rt)),
+            ReferenceLinkage::WrongIdentifierType => Err(LinkageError::WrongIdentifierType(rt)),
+            ReferenceLinkage::Linked => {
+                *resource = serde_json::from_value(json).expect("Replacing references keeps the resource valid");
+                Ok(rt)
+            }
+        }
+    }
+
+    fn link_reference(&self, reference: &mut Value) -> ReferenceLinkage {
+        let literal_target = reference
+            .get("reference")
+            .and_then(Value::as_str)
+            .and_then(|literal| self.patient_references.get(literal));
+        if let Some(target) = literal_target {
+            reference["reference"] = target.as_str().into();
+            reference["identifier"] = json!({"system": self.project_id_system, "value": self.project_id});
+            return ReferenceLinkage::Linked;
+        }
+        match reference.get_mut("identifier") {
+            None => ReferenceLinkage::MissingIdentifier,
+            Some(identifier) => match identifier.get("system").and_then(Value::as_str) {
+                None => ReferenceLinkage::IdentifierWithoutSystem,
+                Some(system) if system != self.exchange_id_system => ReferenceLinkage::WrongIdentifierType,
+                Some(_) => {
+                    identifier["system"] = self.project_id_system.into();
+                    identifier["value"] = self.project_id.into();
+                    ReferenceLinkage::Linked
+                }
+            }
+        }
+    }
+}
+
+// Outcome of linking the references of a resource, the best outcome of all references counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ReferenceLinkage {
+    NoReference,
+    MissingIdentifier,
+    IdentifierWithoutSystem,
+    WrongIdentifierType,
+    Linked,
+}
+
+fn for_each_element(value: &mut Value, path: &[&str], f: &mut impl FnMut(&mut Value)) {
+    if let Value::Array(items) = value {
+        for item in items {
+            for_each_element(item, path, f);
+        }
+        return;
+    }
+    match path.split_first() {
+        None => f(value),
+        Some((field, rest)) => {
+            if let Some(child) = value.get_mut(*field) {
+                for_each_element(child, rest, f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use fhir_sdk::r4b::resources::{Bundle, Medication, Resource, ResourceType};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::{Linkage, LinkageError};
+
+    fn resource(json: serde_json::Value) -> Resource {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn linkage() -> Linkage<'static> {
+        Linkage {
+            exchange_id_system: "SESSION_ID",
+            project_id_system: "PROJECT_1_ID",
+            project_id: "project",
+            patient_references: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn link_specimen_subject() {
+        let mut specimen = resource(json!({
+            "resourceType": "Specimen",
+            "subject": {"identifier": {"system": "SESSION_ID", "value": "exchange"}}
+        }));
+        assert_eq!(linkage().link_resource(&mut specimen).unwrap(), ResourceType::Specimen);
+        assert_eq!(
+            serde_json::to_value(&specimen).unwrap()["subject"]["identifier"],
+            json!({"system": "PROJECT_1_ID", "value": "project"})
+        );
+    }
+
+    #[test]
+    fn link_only_patient_references() {
+        let mut observation = resource(json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {"text": "test"},
+            "subject": {"identifier": {"system": "SESSION_ID", "value": "exchange"}},
+            "performer": [{"identifier": {"system": "LANR", "value": "123"}}]
+        }));
+        linkage().link_resource(&mut observation).unwrap();
+        let json = serde_json::to_value(&observation).unwrap();
+        assert_eq!(json["subject"]["identifier"]["value"], "project");
+        assert_eq!(json["performer"][0]["identifier"], json!({"system": "LANR", "value": "123"}));
+    }
+
+    #[test]
+    fn link_fails_without_exchange_identifier() {
+        let mut condition = resource(json!({
+            "resourceType": "Condition",
+            "subject": {"identifier": {"system": "OTHER_ID", "value": "exchange"}}
+        }));
+        assert!(matches!(
+            linkage().link_resource(&mut condition),
+            Err(LinkageError::WrongIdentifierType(ResourceType::Condition))
+        ));
+        let mut procedure = resource(json!({
+            "resourceType": "Procedure",
+            "status": "completed",
+            "subject": {"reference": "Patient/unknown"}
+        }));
+        assert!(matches!(
+            linkage().link_resource(&mut procedure),
+            Err(LinkageError::MissingIdentifier(ResourceType::Procedure))
+        ));
+    }
+
+    #[test]
+    fn resources_outside_compartment_are_kept() {
+        let mut medication = Resource::from(Medication::builder().build().unwrap());
+        assert_eq!(linkage().link_resource(&mut medication).unwrap(), ResourceType::Medication);
+    }
+
+    #[test]
+    fn link_literal_references_within_bundle() {
+        let mut bundle: Bundle = serde_json::from_value(json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [
+                {
+                    "fullUrl": "http://dic.example.org/fhir/Patient/abc",
+                    "resource": {
+                        "resourceType": "Patient",
+                        "id": "abc",
+                        "identifier": [{"system": "SESSION_ID", "value": "exchange"}]
+                    },
+                    "request": {"method": "PUT", "url": "Patient/abc"}
+                },
+                {
+                    "resource": {
+                        "resourceType": "Condition",
+                        "subject": {"reference": "Patient/abc"}
+                    },
+                    "request": {"method": "POST", "url": "Condition"}
+                },
+                {
+                    "resource": {
+                        "resourceType": "Specimen",
+                        "subject": {"reference": "http://dic.example.org/fhir/Patient/abc"}
+                    },
+                    "request": {"method": "POST", "url": "Specimen"}
+                }
+            ]
+        })).unwrap();
+        let linkage = Linkage::for_bundle(&mut bundle, "SESSION_ID", "PROJECT_1_ID", "project");
+        for entry in bundle.entry.iter_mut().flatten() {
+            linkage.link_resource(entry.resource.as_mut().unwrap()).unwrap();
+        }
+
+        let json = serde_json::to_value(&bundle).unwrap();
+        let patient_full_url = json["entry"][0]["fullUrl"].as_str().unwrap();
+        assert!(patient_full_url.starts_with("urn:uuid:"));
+        assert_eq!(json["entry"][0]["resource"]["identifier"][0], json!({"system": "PROJECT_1_ID", "value": "project"}));
+        // the patient is neither addressed by its id in the input server nor created twice
+        assert_eq!(json["entry"][0]["resource"].get("id"), None);
+        assert_eq!(json["entry"][0]["request"], json!({"method": "PUT", "url": "Patient?identifier=PROJECT_1_ID%7Cproject"}));
+        for entry in 1..=2 {
+            assert_eq!(
+                json["entry"][entry]["resource"]["subject"],
+                json!({"reference": patient_full_url, "identifier": {"system": "PROJECT_1_ID", "value": "project"}})
+            );
+        }
+    }
+}
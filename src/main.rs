@@ -1,25 +1,34 @@
-use std::{process::ExitCode, sync::{LazyLock, OnceLock}, time::Duration};
+use std::{fmt::Display, net::SocketAddr, process::ExitCode, sync::{Arc, LazyLock, OnceLock}, time::Duration};
 
-use axum::{routing::{get, post}, Router};
+use axum::{middleware, routing::{get, post}, Router};
 use chrono::{DateTime, Utc};
-use clap::Parser;
 use config::DicConfig;
 use fhir::FhirServer;
 use fhir_sdk::r4b::resources::{Bundle, Resource, ResourceType};
 use requests::update_data_request;
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Sqlite, SqlitePool};
-use futures_util::future::TryJoinAll;
+use tokio::sync::Mutex;
 use tracing::{debug, error, info, trace, warn, Level};
 use tracing_subscriber::{EnvFilter, util::SubscriberInitExt};
-use ttp::Ttp;
 
-use crate::{config::CliArgs, fhir::PatientExt, requests::{create_data_request, get_data_request, list_data_requests}};
+use crate::{admin::{require_admin_key, resolve_pseudonym, trigger_fetch}, auth::Authenticator, config::CliArgs, consent::{apply_consent, load_consent}, deadletters::{list_dead_letters, retry_dead_letter, store_dead_letter}, deliveries::DeliveryOutcome, filter::ResourceCounts, linkage::{replace_exchange_identifiers, LinkageError}, projects::{Project, Projects}, requests::{create_data_request, fail_data_request, get_data_request, is_revoked, list_data_requests, withdraw_data_request}, transformation::Profile};
 
+mod admin;
+mod auth;
 mod banner;
+mod check;
 mod config;
+mod consent;
+mod deadletters;
+mod deliveries;
 mod fhir;
+mod filter;
+mod linkage;
+mod projects;
 mod requests;
+mod transformation;
 mod ttp;
 
 #[cfg(not(test))]
@@ -37,12 +46,15 @@ async fn main() -> ExitCode {
         .finish()
         .init();
 
-    let args = CliArgs::parse();
+    let args = CliArgs::parse_with_config_file(std::env::args_os());
     INNER_CLIENT.set(args.build_client()).unwrap();
     match args.subcommand {
         config::SubCommand::Dic(config) => {
             dic_main(config).await
         }
+        config::SubCommand::Config(config::ConfigCommand::Check(check)) => {
+            check::check_main(check).await
+        }
     }
 }
 
@@ -51,23 +63,104 @@ pub struct DicAppState {
     pub database_pool: Pool<Sqlite>,
     pub config: &'static DicConfig,
     pub request_server: &'static FhirServer,
+    pub input_server: &'static FhirServer,
+    // projects and their output servers, deliveries are dispatched by their data request
+    pub projects: &'static Projects,
+    // ensures that scheduled fetches, manual fetches and retries never process deliveries concurrently
+    pub fetch_lock: Arc<Mutex<()>>,
+    pub authenticator: Arc<Authenticator>,
 }
 
 impl DicAppState {
-    pub fn new(database_pool: Pool<Sqlite>, config: &'static DicConfig) -> Self {
+    pub fn new(database_pool: Pool<Sqlite>, config: &'static DicConfig, projects: Projects) -> Self {
         let request_server = FhirServer::new(
             config.fhir_request_url.clone(),
             config.fhir_request_credentials.clone()
         );
         let request_server = Box::leak(Box::new(request_server));
+        let input_server = FhirServer::new(
+            config.fhir_input_url.clone(),
+            config.fhir_input_credentials.clone()
+        );
+        let input_server = Box::leak(Box::new(input_server));
+        let projects = Box::leak(Box::new(projects));
         Self {
             database_pool,
             config,
             request_server,
+            input_server,
+            projects,
+            fetch_lock: Arc::default(),
+            authenticator: Arc::new(Authenticator::new(&config.auth)),
         }
     }
 }
 
+#[cfg(test)]
+impl DicAppState {
+    // State with an in-memory database, the fhir servers are at localhost unless given in the extra arguments
+    pub async fn for_tests(extra_args: &[&str]) -> Self {
+        use clap::Parser;
+
+        let defaults = [
+            ("--database-url", "sqlite::memory:"),
+            ("--fhir-request-url", "http://localhost:8085"),
+            ("--fhir-input-url", "http://localhost:8086"),
+            ("--fhir-output-url", "http://localhost:8095"),
+        ];
+        let defaults = defaults.into_iter().filter(|(option, _)| !extra_args.contains(option)).flat_map(|(option, value)| [option, value]);
+        let args = std::iter::once("dic").chain(defaults).chain(extra_args.iter().copied());
+        let config: &'static DicConfig = Box::leak(Box::new(DicConfig::parse_from(args)));
+        let database_pool = SqlitePool::connect(config.database_url.as_str()).await.unwrap();
+        sqlx::migrate!().run(&database_pool).await.unwrap();
+        if let Some(ttp) = &config.ttp {
+            ttp.use_database(&database_pool);
+        }
+        Self::new(database_pool, config, Projects::load(config).unwrap())
+    }
+}
+
+// Serves the routes at a free port of localhost, e.g. to stand in for a fhir server in tests
+#[cfg(test)]
+pub async fn stub_server(routes: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, routes.into_make_service_with_connect_info::<SocketAddr>()).await });
+    format!("http://{address}")
+}
+
+// Routes of the api, served once the ttp is available
+fn app(state: DicAppState) -> Router {
+    // request api endpoint
+    let request_routes = Router::new()
+        .route("/", post(create_data_request))
+        .route("/", get(list_data_requests))
+        .route("/{request_id}", get(get_data_request))
+        .route("/{request_id}/withdraw", post(withdraw_data_request))
+        .with_state(state.clone());
+
+    // dead letter api endpoint
+    let dead_letter_routes = Router::new()
+        .route("/", get(list_dead_letters))
+        .route("/{dead_letter_id}/retry", post(retry_dead_letter))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin_key))
+        .with_state(state.clone());
+
+    // admin api endpoint
+    let admin_routes = Router::new()
+        .route("/fetch", post(trigger_fetch))
+        .route("/pseudonyms/{project_id}", get(resolve_pseudonym))
+        .route("/projects/{project}/pseudonyms/{project_id}", get(resolve_pseudonym))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin_key))
+        .with_state(state);
+
+    Router::new()
+        .nest("/requests", request_routes.clone())
+        .nest("/projects/{project}/requests", request_routes)
+        .nest("/deadletters", dead_letter_routes)
+        .nest("/admin", admin_routes)
+}
+
 async fn dic_main(config: DicConfig) -> ExitCode {
     banner::print_banner();
     trace!("{config:#?}");
@@ -75,12 +168,20 @@ async fn dic_main(config: DicConfig) -> ExitCode {
     let database_pool = SqlitePool::connect(config.database_url.as_str())
         .await.map_err(|e| {
             error!("Unable to connect to database file {}. Error is: {}", config.database_url.as_str(), e);
-            return
         }).unwrap();
     
     let _ = sqlx::migrate!().run(&database_pool).await;
 
+    let projects = match Projects::load(config) {
+        Ok(projects) => projects,
+        Err(error) => {
+            error!("{error:#}");
+            return ExitCode::from(1);
+        }
+    };
+
     if let Some(ttp) = &config.ttp {
+        ttp.use_database(&database_pool);
         const RETRY_COUNT: i32 = 30;
         let mut failures = 0;
         while !(ttp.check_availability().await) {
@@ -98,49 +199,41 @@ async fn dic_main(config: DicConfig) -> ExitCode {
                 failures, RETRY_COUNT
             );
         }
-        info!("Connected to ttp {}", ttp.url);
-        // verify that both, the exchange id system and project id system are configured in the ttp
-        for idtype in [&config.exchange_id_system, &ttp.project_id_system] {
-            if !(ttp.check_idtype_available(&idtype).await) {
+        info!("Connected to ttp {ttp}");
+        // verify that both, the exchange id system and the project id systems are configured in the ttp
+        let project_id_systems = projects.iter().filter_map(|project| project.domain.as_ref()).map(|domain| domain.project_id_system.as_str());
+        for idtype in std::iter::once(config.exchange_id_system.as_str()).chain(project_id_systems) {
+            if !(ttp.check_idtype_available(idtype).await) {
                 error!("Configured exchange id system '{idtype}' is not available in TTP.");
                 return ExitCode::from(1)
             }
         }
     }
-    let state = DicAppState::new(database_pool, config);
+    if !config.auth.is_enabled() {
+        warn!("No authentication configured, everyone who can reach transFAIR may create and read data requests");
+    }
+    if config.admin_api_key.is_none() {
+        warn!("No admin api key configured, everyone who can reach transFAIR may trigger fetches and read and retry dead letters");
+    }
+    let state = DicAppState::new(database_pool, config, projects);
     let state_for_fetch = state.clone();
     tokio::spawn(async move {
-        const RETRY_PERIOD: Duration = Duration::from_secs(60);
-        let input_fhir_server =  FhirServer::new(
-            config.fhir_input_url.clone(),
-            config.fhir_input_credentials.clone()
-        );
-        let output_fhir_server =  FhirServer::new(
-            config.fhir_output_url.clone(),
-            config.fhir_output_credentials.clone()
-        );
         loop {
-            // TODO: Persist the updated data in the database
-            match fetch_data(&input_fhir_server, &output_fhir_server, &state_for_fetch).await {
-                Ok(status) => info!("{}", status),
-                Err(error) => warn!("Failed to fetch project data: {error:#}. Will try again in {}s", RETRY_PERIOD.as_secs())
+            let result = fetch_data(&state_for_fetch).await;
+            let delay = config.next_fetch_delay(Utc::now());
+            match result {
+                Ok(summary) => info!("{summary}"),
+                Err(error) => warn!("Failed to fetch project data: {error:#}. Will try again in {}s", delay.as_secs())
             }
-            tokio::time::sleep(RETRY_PERIOD).await;
+            tokio::time::sleep(delay).await;
         }
     });
 
-    // request api endpoint
-    let request_routes = Router::new()
-        .route("/", post(create_data_request))
-        .route("/", get(list_data_requests))
-        .route("/{request_id}", get(get_data_request))
-        .with_state(state);
-
-    let app = Router::new()
-        .nest("/requests", request_routes);
+    let app = app(state);
 
     let listener = tokio::net::TcpListener::bind(SERVER_ADDRESS).await.unwrap();
-    axum::serve(listener, app)
+    // the remote address of requests is recorded in the audit log
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 
@@ -148,16 +241,70 @@ async fn dic_main(config: DicConfig) -> ExitCode {
 }
 
 
-// Pull data from input_fhir_server and push it to output_fhir_server
-async fn fetch_data(input_fhir_server: &FhirServer, output_fhir_server: &FhirServer, state: &DicAppState) -> anyhow::Result<String> {
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FetchSummary {
+    pub executed_at: DateTime<Utc>,
+    pub pages: usize,
+    pub bundles_seen: usize,
+    pub transferred: usize,
+    pub failed: usize,
+    pub dead_lettered: usize,
+    pub skipped: usize,
+}
+
+impl FetchSummary {
+    fn record(&mut self, outcome: DeliveryOutcome) {
+        self.bundles_seen += 1;
+        match outcome {
+            DeliveryOutcome::Skipped => self.skipped += 1,
+            DeliveryOutcome::Transferred => self.transferred += 1,
+            DeliveryOutcome::Failed => self.failed += 1,
+            DeliveryOutcome::DeadLettered => self.dead_lettered += 1,
+        }
+    }
+}
+
+impl Display for FetchSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Last fetch for new data executed at {:?} ({} pages, {} bundles seen, {} transferred, {} failed, {} dead lettered, {} skipped)",
+            self.executed_at, self.pages, self.bundles_seen, self.transferred, self.failed, self.dead_lettered, self.skipped
+        )
+    }
+}
+
+// Pull data from the input fhir server and push it to the output fhir server
+pub async fn fetch_data(state: &DicAppState) -> anyhow::Result<FetchSummary> {
+    let _fetch_guard = state.fetch_lock.lock().await;
+    let input_fhir_server = state.input_server;
     let fetch_start_date = extract_execution_time(&state.database_pool).await;
-    let mut new_data = input_fhir_server.pull_new_data(
-        fetch_start_date.naive_local().into()
-    ).await?;
+    // bundles updated while we are still paging through the results will be picked up by the next fetch
     let fetch_finish_date = chrono::prelude::Utc::now();
-    if new_data.entry.is_empty() {
-        debug!("Received empty bundle from mdat server ({}). No update necessary", input_fhir_server.url);
-    } else {
+    let mut summary = FetchSummary { executed_at: fetch_finish_date, ..Default::default() };
+
+    // deliveries that failed in an earlier fetch are not part of the new data anymore, so they are read again
+    for delivery in deliveries::due_for_retry(&state.database_pool).await? {
+        info!("Retrying delivery {} (version {}) after {} failed attempts", delivery.bundle_id, delivery.version_id, delivery.attempts);
+        match input_fhir_server.pull_bundle(&delivery.bundle_id, &delivery.version_id).await {
+            Ok(mut bundle) => summary.record(handle_delivery(&mut bundle, state).await?),
+            Err(error) => {
+                warn!("Unable to read delivery {} for retry: {error:#}", delivery.bundle_id);
+                deliveries::mark_failed(&delivery.bundle_id, &delivery.version_id, &format!("{error:#}"), &state.database_pool).await?;
+                summary.record(DeliveryOutcome::Failed);
+            }
+        }
+    }
+
+    let mut page = Some(input_fhir_server.pull_new_data(
+        fetch_start_date.naive_local(),
+        state.config.fhir_input_page_size
+    ).await?);
+    while let Some(mut new_data) = page.take() {
+        summary.pages += 1;
+        if new_data.entry.is_empty() {
+            debug!("Received empty bundle from mdat server ({}). No update necessary", input_fhir_server.url);
+        }
         for entry in new_data.entry.iter_mut().flatten() {
             let Some(resource) = &mut entry.resource else {
                 error!("Received invalid bundle for data request");
@@ -169,57 +316,204 @@ async fn fetch_data(input_fhir_server: &FhirServer, output_fhir_server: &FhirSer
                 _ => continue,
             };
 
-            let Some(bundle_id) = entry_bundle.identifier.as_ref().cloned() else {
-                error!("Received bundle without identifier. No link to data request is possible.");
-                continue;
-            };
+            summary.record(handle_delivery(entry_bundle, state).await?);
+        }
+        // only advance the watermark once every page was handled, a failing page aborts the whole fetch
+        page = input_fhir_server.pull_next_page(&new_data).await?;
+    }
+    let finish_as_timestamp = fetch_finish_date.timestamp_millis();
+    sqlx::query!(
+        "UPDATE last_request SET execution_time = $1 WHERE id = 1", finish_as_timestamp
+    ).fetch_optional(&state.database_pool).await?;
+    Ok(summary)
+}
 
-            let Some(ref bundle_id_system) = bundle_id.system else {
-                error!("Bundle identifier contains no system.");
-                continue;
-            };
+// Transfer a single delivery bundle, recording the outcome in the delivery ledger
+pub async fn handle_delivery(entry_bundle: &mut Bundle, state: &DicAppState) -> sqlx::Result<DeliveryOutcome> {
+    let Some(bundle_id) = entry_bundle.id.clone() else {
+        error!("Received delivery bundle without id. Unable to track its delivery.");
+        return Ok(DeliveryOutcome::Skipped);
+    };
+    // bundles without version can only be transferred once, as updates can't be told apart
+    let version_id = entry_bundle.meta.as_ref().and_then(|meta| meta.version_id.clone()).unwrap_or_default();
 
-            if bundle_id_system != "DATAREQUEST_ID" {
-                error!("Bundle identifier has invalid system. Please provide an identifier with system \"DATAREQUEST_ID\"");
-                continue;
-            };
+    if !deliveries::claim_delivery(&bundle_id, &version_id, &state.database_pool).await? {
+        debug!("Skipping delivery {bundle_id} (version {version_id}) as it was already handled or is waiting for retry");
+        return Ok(DeliveryOutcome::Skipped);
+    }
 
-            let Some(ref bundle_id_value) = bundle_id.value else {
-                error!("Bundle identifier has no value. Link to data request not possible");
-                continue;
-            };
+    // linkage modifies the bundle, but dead letters are stored as received
+    let received_bundle = entry_bundle.clone();
+    match transfer_delivery(entry_bundle, (&bundle_id, &version_id), state).await {
+        Ok(TransferredDelivery { data_request_id, linkage_results, withheld, filtered }) => {
+            // mark the delivery as transferred before anything else can fail, so it isn't posted again
+            deliveries::mark_transferred(&bundle_id, &version_id, &state.database_pool).await?;
+            let mut notes = Vec::new();
+            if !withheld.is_empty() {
+                notes.push(format!("Withheld by consent: {withheld}"));
+            }
+            if !filtered.is_empty() {
+                notes.push(format!("Filtered out {filtered}"));
+            }
+            update_data_request(&data_request_id, linkage_results, &notes, &state.database_pool).await?;
+            Ok(DeliveryOutcome::Transferred)
+        },
+        Err(DeliveryError::Unlinkable { data_request_id, linkage_results }) => {
+            let reason = linkage_results.iter()
+                .filter_map(|res| res.as_ref().err())
+                .map(ToString::to_string)
+                .collect::<Vec<String>>()
+                .join(",");
+            move_to_dead_letters(&bundle_id, &version_id, data_request_id.as_deref(), &reason, &received_bundle, state).await?;
+            if let Some(data_request_id) = data_request_id {
+                update_data_request(&data_request_id, Some(linkage_results), &[], &state.database_pool).await?;
+            }
+            Ok(DeliveryOutcome::DeadLettered)
+        },
+        Err(ref error @ (DeliveryError::NotPermitted { ref data_request_id, .. } | DeliveryError::Transformation { ref data_request_id, .. })) => {
+            let reason = error.to_string();
+            move_to_dead_letters(&bundle_id, &version_id, Some(data_request_id), &reason, &received_bundle, state).await?;
+            fail_data_request(data_request_id, &reason, &state.database_pool).await?;
+            Ok(DeliveryOutcome::DeadLettered)
+        },
+        Err(error) => {
+            error!("Failed to transfer delivery {bundle_id} (version {version_id}): {error:#}");
+            deliveries::mark_failed(&bundle_id, &version_id, &format!("{error:#}"), &state.database_pool).await?;
+            Ok(DeliveryOutcome::Failed)
+        }
+    }
+}
 
-            let mut linkage_results = None;
-            if let Some(ttp) = &state.config.ttp {
-                linkage_results = Some(replace_exchange_identifiers(bundle_id_value, entry_bundle, ttp, &state).await?);
-            };
+async fn move_to_dead_letters(bundle_id: &str, version_id: &str, data_request_id: Option<&str>, reason: &str, received_bundle: &Bundle, state: &DicAppState) -> sqlx::Result<()> {
+    warn!("Moving delivery {bundle_id} (version {version_id}) to dead letters: {reason}");
+    store_dead_letter(bundle_id, version_id, data_request_id, reason, received_bundle, &state.database_pool).await?;
+    deliveries::mark_dead_lettered(bundle_id, version_id, reason, &state.database_pool).await
+}
 
-            // TODO: integrate transformation using transfair-batch here
+#[derive(Debug, thiserror::Error)]
+enum DeliveryError {
+    // Retrying won't help until the configuration or the delivered data was fixed
+    #[error("Delivery can't be linked to a data request")]
+    Unlinkable {
+        data_request_id: Option<String>,
+        linkage_results: Vec<Result<ResourceType, LinkageError>>,
+    },
+    // Retrying won't help until the consent was updated
+    #[error("Consent does not permit transfer: {reason}")]
+    NotPermitted {
+        data_request_id: String,
+        reason: String,
+    },
+    // Retrying won't help until the configuration or the delivered data was fixed
+    #[error("Transformation with profile {profile} failed: {error:#}")]
+    Transformation {
+        data_request_id: String,
+        profile: Profile,
+        error: anyhow::Error,
+    },
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+    #[error(transparent)]
+    Transfer(#[from] anyhow::Error),
+}
 
-            match output_fhir_server.post_data(&entry_bundle).await{
-                Ok(response) => info!("Received a response: {}", response.text().await.as_deref().unwrap_or("<invalid text>")),
-                Err(error) => error!("Received the following error: {error:#}"),
-            };
+impl DeliveryError {
+    fn unlinkable_bundle(error: LinkageError) -> Self {
+        Self::Unlinkable { data_request_id: None, linkage_results: vec![Err(error)] }
+    }
+}
 
-            update_data_request(bundle_id_value, linkage_results, &state.database_pool).await.unwrap();
+struct TransferredDelivery {
+    data_request_id: String,
+    linkage_results: Option<Vec<Result<ResourceType, LinkageError>>>,
+    withheld: ResourceCounts,
+    filtered: ResourceCounts,
+}
 
+// Link, check consent, filter, transform and post a delivery bundle, identified in the ledger by its id and version
+async fn transfer_delivery(entry_bundle: &mut Bundle, delivery: (&str, &str), state: &DicAppState) -> Result<TransferredDelivery, DeliveryError> {
+    let Some(bundle_id) = entry_bundle.identifier.as_ref().cloned() else {
+        error!("Received bundle without identifier. No link to data request is possible.");
+        return Err(DeliveryError::unlinkable_bundle(LinkageError::MissingIdentifier(ResourceType::Bundle)));
+    };
+
+    let Some(ref bundle_id_system) = bundle_id.system else {
+        error!("Bundle identifier contains no system.");
+        return Err(DeliveryError::unlinkable_bundle(LinkageError::IdentifierWithoutSystem(ResourceType::Bundle)));
+    };
+
+    if bundle_id_system != "DATAREQUEST_ID" {
+        error!("Bundle identifier has invalid system. Please provide an identifier with system \"DATAREQUEST_ID\"");
+        return Err(DeliveryError::unlinkable_bundle(LinkageError::WrongIdentifierType(ResourceType::Bundle)));
+    };
+
+    let Some(bundle_id_value) = bundle_id.value.clone() else {
+        error!("Bundle identifier has no value. Link to data request not possible");
+        return Err(DeliveryError::unlinkable_bundle(LinkageError::IdentifierNotLinkable(ResourceType::Bundle)));
+    };
+
+    let project = project_of(&bundle_id_value, state).await?;
+    let mut linkage_results = None;
+    if let Some(domain) = &project.domain {
+        let results = replace_exchange_identifiers(&bundle_id_value, entry_bundle, domain, state).await?;
+        // posting a partially linked bundle would leak exchange identifiers into the project data
+        if results.iter().any(Result::is_err) {
+            return Err(DeliveryError::Unlinkable { data_request_id: Some(bundle_id_value), linkage_results: results });
         }
+        linkage_results = Some(results);
+    };
 
+    if is_revoked(&bundle_id_value, &state.database_pool).await? {
+        return Err(DeliveryError::NotPermitted { data_request_id: bundle_id_value, reason: "Consent was withdrawn".into() });
     }
-    let finish_as_timestamp = fetch_finish_date.timestamp_millis();
-    sqlx::query!(
-        "UPDATE last_request SET execution_time = $1 WHERE id = 1", finish_as_timestamp
-    ).fetch_optional(&state.database_pool).await?;
-    Ok(format!("Last fetch for new data executed at {:?}", fetch_finish_date))
+    let withheld = match load_consent(&bundle_id_value, &state.database_pool).await? {
+        Some(consent) => apply_consent(&consent, entry_bundle, project.consent_purpose.as_ref(), Utc::now())
+            .map_err(|reason| DeliveryError::NotPermitted { data_request_id: bundle_id_value.clone(), reason })?,
+        None if project.consent_required => {
+            return Err(DeliveryError::NotPermitted { data_request_id: bundle_id_value, reason: "Data request has no consent".into() });
+        }
+        None => ResourceCounts::default(),
+    };
+    if !withheld.is_empty() {
+        info!("Withheld {withheld} of delivery for data request {bundle_id_value} due to its consent");
+    }
+
+    let filtered = state.config.filter.apply(entry_bundle);
+    if !filtered.is_empty() {
+        info!("Filtered out {filtered} of delivery for data request {bundle_id_value}");
+    }
+
+    let profile = project.profile;
+    if let Err(error) = profile.transform(entry_bundle) {
+        return Err(DeliveryError::Transformation { data_request_id: bundle_id_value, profile, error });
+    }
+
+    let (delivery_id, version_id) = delivery;
+    deliveries::make_idempotent(entry_bundle, delivery_id, version_id);
+    deliveries::mark_posting(delivery_id, version_id, &state.database_pool).await?;
+    let response = project.output_server.post_data(entry_bundle).await?;
+    info!("Received a response from project {project}: {}", response.text().await.as_deref().unwrap_or("<invalid text>"));
+
+    Ok(TransferredDelivery { data_request_id: bundle_id_value, linkage_results, withheld, filtered })
+}
+
+// Deliveries are transferred to the project of their data request. Unknown data requests belong to the default project,
+// where linkage reports them.
+async fn project_of(data_request_id: &str, state: &DicAppState) -> Result<&'static Project, DeliveryError> {
+    let name = sqlx::query_scalar!(
+        "SELECT project FROM data_requests WHERE id = $1",
+        data_request_id
+    ).fetch_optional(&state.database_pool).await?.flatten();
+    // retried, as the project may be added to the projects file again
+    state.projects.get(name.as_deref()).ok_or_else(|| {
+        DeliveryError::Transfer(anyhow::anyhow!("Project {} of data request {data_request_id} is not configured", name.unwrap_or_default()))
+    })
 }
 
 async fn extract_execution_time(database_pool: &Pool<Sqlite>) -> DateTime<Utc> {
-    let last_request = match sqlx::query!(
+    let last_request = sqlx::query!(
         "SELECT execution_time FROM last_request"
-    ).fetch_optional(database_pool).await {
-       Ok(last_request) => last_request,
-       Err(_) => None,
-    };
+    ).fetch_optional(database_pool).await.unwrap_or_default();
 
     match last_request {
         Some(last_request) => chrono::DateTime::from_timestamp_millis(
@@ -229,103 +523,23 @@ async fn extract_execution_time(database_pool: &Pool<Sqlite>) -> DateTime<Utc> {
     }
 }
 
-    #[derive(Debug, thiserror::Error)]
-enum LinkageError {
-    #[error("entry in response did not contain a resource.")]
-    EntryWithoutResource,
-    #[error("The resource provided is of unknown type.")]
-    UnknownResource(ResourceType),
-    #[error("The provided resource didn't contain a reference.")]
-    NoReference(ResourceType),
-    #[error("Can't link resource due to missing identifier")]
-    MissingIdentifier(ResourceType),
-    #[error("DataRequest didn't have identifier value for project id stored")]
-    MissingIdentifierValue(ResourceType),
-    #[error("Identifier for linkage didn't contain a system")]
-    IdentifierWithoutSystem(ResourceType),
-    #[error("Identifier for linkage was not of configured type")]
-    WrongIdentifierType(ResourceType),
-    #[error("{0}: Unable to link identifier to any data request")]
-    IdentifierNotLinkable(ResourceType)
-}
-
-async fn replace_exchange_identifiers(data_request_identifier: &str, new_data: &mut Bundle, ttp: &Ttp, state: &DicAppState) -> sqlx::Result<Vec<Result<ResourceType, LinkageError>>> {
-    new_data.entry.iter_mut().flatten().map(|entry| {
-        let Some(resource) = &mut entry.resource else {
-            return Err(LinkageError::EntryWithoutResource)
-        };
-
-        let rt = resource.resource_type();
-        let identifier = match resource {
-            Resource::Patient(patient) => patient.get_identifier_mut(&state.config.exchange_id_system),
-            Resource::Consent(consent) => match consent.patient.as_mut() {
-                Some(patient) => patient.identifier.as_mut(),
-                None => return Err(LinkageError::NoReference(rt))
-            },
-            Resource::Condition(condition) => condition.subject.identifier.as_mut(),
-            Resource::Procedure(procedure) => procedure.subject.identifier.as_mut(),
-            Resource::Encounter(encounter) => match encounter.subject.as_mut() {
-                Some(subject) => subject.identifier.as_mut(),
-                None => return Err(LinkageError::NoReference(rt))
-            },
-            Resource::Observation(observation) => match observation.subject.as_mut() {
-                Some(subject) => subject.identifier.as_mut(),
-                None => return Err(LinkageError::NoReference(rt))
-            },
-            _ => return Err(LinkageError::UnknownResource(rt))
-        };
-
-        let Some(identifier) = identifier else {
-            return Err(LinkageError::MissingIdentifier(rt))
-        };
-
-        let Some(system) = &mut identifier.system else {
-            return Err(LinkageError::IdentifierWithoutSystem(rt))
-        };
-
-        if system != &state.config.exchange_id_system {
-            Err(LinkageError::WrongIdentifierType(rt))
-        } else {
-            Ok((identifier, rt))
-        }
-    }).map(|res| async {
-        let (ident, rt) = match res {
-            Ok(items) => items,
-            Err(e) => return Ok(Err(e)),
-        };
-        ident.system = Some(ttp.project_id_system.clone());
-        let result = sqlx::query!(
-            "SELECT project_id FROM data_requests WHERE id = $1",
-            data_request_identifier
-        ).fetch_optional(&state.database_pool).await?;
-        if let Some(patient_identifier) = result {
-            let Some(project_id) = patient_identifier.project_id else {
-                return Ok(Err(LinkageError::MissingIdentifierValue(rt)));
-            };
-            ident.value = Some(project_id);
-            Ok(Ok(rt))
-        } else {
-            Ok(Err(LinkageError::IdentifierNotLinkable(rt)))
-        }
-    }).collect::<TryJoinAll<_>>().await
-}
-
 #[cfg(test)]
 mod tests {
-    use core::time;
+    use std::{collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, Arc}};
 
+    use axum::{extract::Query, routing::get, Json, Router};
     use fhir_sdk::r4b::resources::{Bundle, Resource};
     use pretty_assertions::assert_eq;
     use reqwest::StatusCode;
 
-    use crate::requests::DataRequest;
+    use crate::{fetch_data, requests::DataRequest, stub_server, DicAppState};
 
     async fn post_data_request() -> DataRequest {
         let bytes = include_bytes!("../docs/examples/data_request.json");
         let json = &serde_json::from_slice::<serde_json::Value>(bytes).unwrap();
 
         let response = reqwest::Client::new()
-            .post(format!("http://localhost:8080/requests"))
+            .post("http://localhost:8080/requests")
             .json(json)
             .send()
             .await
@@ -357,8 +571,8 @@ mod tests {
         let json_string = String::from_utf8_lossy(bytes);
         let json = serde_json::from_str::<Bundle>(
             &json_string
-                .replace("<<data_request_id>>", &format!("{}", data_request.id.clone()))
-                .replace("<<session_id>>", &format!("{}", data_request.exchange_id))
+                .replace("<<data_request_id>>", &data_request.id)
+                .replace("<<session_id>>", &data_request.exchange_id)
         ).unwrap();
 
         // deliver data from external site
@@ -370,8 +584,13 @@ mod tests {
             .await
             .expect("POST to (/fhir/Bundle) should given a valid response");
 
-        // sleep for 70 seconds to ensure a fetch job runs during the test
-        tokio::time::sleep(time::Duration::from_secs(70)).await;
+        // run a fetch instead of waiting for the scheduled one
+        let fetch_response = reqwest::Client::new()
+            .post("http://localhost:8080/admin/fetch")
+            .send()
+            .await
+            .expect("POST endpoint (/admin/fetch) should give a valid response");
+        assert_eq!(fetch_response.status(), StatusCode::OK);
 
         let procedure_response = reqwest::Client::new()
             .get("http://localhost:8095/fhir/Procedure")
@@ -382,14 +601,14 @@ mod tests {
 
         assert!(!procedure_response.entry.is_empty());
         for entry in procedure_response.entry.iter() {
-            assert_ne!(entry.is_none(), true);
-            let ref procedure = match entry.clone().unwrap().resource.unwrap() {
+            assert!(entry.is_some());
+            let procedure = &match entry.clone().unwrap().resource.unwrap() {
                 Resource::Procedure(procedure) => procedure,
                 _ => continue,
             };
             // ensure identifier was changed to the project id
             let identifier = procedure.subject.identifier.clone().unwrap();
-            assert_eq!(identifier.system, Some(format!("PROJECT_1_ID")));
+            assert_eq!(identifier.system, Some("PROJECT_1_ID".to_string()));
             assert_ne!(identifier.value.as_ref(), Some(&data_request.exchange_id));
         };
 
@@ -403,15 +622,64 @@ mod tests {
 
         assert!(!condition_response.entry.is_empty());
         for entry in condition_response.entry.iter() {
-            assert_ne!(entry.is_none(), true);
-            let ref condition = match entry.clone().unwrap().resource.unwrap() {
+            assert!(entry.is_some());
+            let condition = &match entry.clone().unwrap().resource.unwrap() {
                 Resource::Condition(condition) => condition,
                 _ => continue,
             };
             // ensure identifier was changed to the project id
             let identifier = condition.subject.identifier.clone().unwrap();
-            assert_eq!(identifier.system, Some(format!("PROJECT_1_ID")));
+            assert_eq!(identifier.system, Some("PROJECT_1_ID".to_string()));
             assert_ne!(identifier.value.as_ref(), Some(&data_request.exchange_id));
         };
     }
+
+    // Search result page with one delivery bundle, linking to the next page if there is one
+    fn search_page(bundle_id: &str, next: Option<&str>) -> serde_json::Value {
+        let delivery = serde_json::json!({
+            "resourceType": "Bundle",
+            "id": bundle_id,
+            "meta": { "versionId": "1" },
+            "type": "transaction",
+            "identifier": { "system": "DATAREQUEST_ID", "value": "unknown-request" },
+            "entry": []
+        });
+        let link = next.map(|url| serde_json::json!({ "relation": "next", "url": url }));
+        serde_json::json!({
+            "resourceType": "Bundle",
+            "type": "searchset",
+            "link": link.into_iter().collect::<Vec<_>>(),
+            "entry": [{ "resource": delivery }]
+        })
+    }
+
+    #[tokio::test]
+    async fn fetch_follows_next_links_and_keeps_watermark_on_failed_pages() {
+        let third_page_fails = Arc::new(AtomicBool::new(true));
+        let fails = third_page_fails.clone();
+        let input_server = Router::new().route("/fhir/Bundle", get(|Query(query): Query<HashMap<String, String>>| async move {
+            let failing = fails.load(Ordering::SeqCst);
+            match query.get("page").map(String::as_str) {
+                // servers may return relative links
+                None => Ok(Json(search_page("first", Some("fhir/Bundle?page=2")))),
+                Some("2") => Ok(Json(search_page("second", failing.then_some("fhir/Bundle?page=3")))),
+                _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
+            }
+        }));
+        let input_url = stub_server(input_server).await;
+        let state = DicAppState::for_tests(&["--fhir-input-url", &input_url]).await;
+        let watermark = || sqlx::query_scalar!("SELECT execution_time FROM last_request WHERE id = 1").fetch_one(&state.database_pool);
+        let before = watermark().await.unwrap();
+
+        assert!(fetch_data(&state).await.is_err());
+        let claimed = sqlx::query_scalar!("SELECT bundle_id FROM deliveries ORDER BY bundle_id").fetch_all(&state.database_pool).await.unwrap();
+        assert_eq!(claimed, ["first", "second"]);
+        // bundles of the failed fetch are fetched again, so the watermark stays
+        assert_eq!(watermark().await.unwrap(), before);
+
+        third_page_fails.store(false, Ordering::SeqCst);
+        let summary = fetch_data(&state).await.unwrap();
+        assert_eq!((summary.pages, summary.bundles_seen, summary.skipped), (2, 2, 2));
+        assert!(watermark().await.unwrap() > before);
+    }
 }
@@ -0,0 +1,264 @@
+//! Projects served by one transFAIR instance. The project configured by the environment is served under /requests,
+//! the projects of the projects file under /projects/<project>/requests.
+use std::{collections::BTreeMap, fmt::Display};
+
+use anyhow::{bail, Context};
+use axum::extract::{FromRequestParts, RawPathParams};
+use axum::http::request::Parts;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{config::DicConfig, fhir::FhirServer, filter::CodeFilter, transformation::Profile, ttp::{ProjectDomain, Ttp}, DicAppState};
+
+// A project receiving data from the dic, with the settings for its data requests and deliveries
+#[derive(Debug)]
+pub struct Project {
+    // None for the project configured by the environment
+    pub name: Option<String>,
+    // Pseudonyms of the project at the ttp, None without a ttp
+    pub domain: Option<ProjectDomain>,
+    pub output_server: FhirServer,
+    pub profile: Profile,
+    pub consent_required: bool,
+    pub consent_purpose: Option<CodeFilter>,
+}
+
+impl Display for Project {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name.as_deref().unwrap_or("default"))
+    }
+}
+
+// Entry of the projects file, e.g.
+// [projects.biobank]
+// project_id_system = "BIOBANK_ID"
+// fhir_output_url = "http://biobank-store:8080"
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectEntry {
+    project_id_system: Option<String>,
+    gpas_domain: Option<String>,
+    fhir_output_url: String,
+    #[serde(default)]
+    fhir_output_credentials: String,
+    #[serde(default = "default_profile")]
+    profile: String,
+    #[serde(default)]
+    consent_required: bool,
+    consent_purpose: Option<String>,
+}
+
+fn default_profile() -> String {
+    "fhircopy".into()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectsFile {
+    #[serde(default)]
+    projects: BTreeMap<String, ProjectEntry>,
+}
+
+#[derive(Debug)]
+pub struct Projects {
+    default: Project,
+    named: BTreeMap<String, Project>,
+}
+
+impl Projects {
+    pub fn load(config: &DicConfig) -> anyhow::Result<Self> {
+        let default = Project {
+            name: None,
+            domain: config.ttp.as_ref().map(|ttp| ProjectDomain {
+                project_id_system: ttp.project_id_system().to_owned(),
+                gpas_domain: ttp.gpas_domain().map(ToOwned::to_owned),
+            }),
+            output_server: FhirServer::new(config.fhir_output_url.clone(), config.fhir_output_credentials.clone()),
+            profile: config.profile,
+            consent_required: config.consent_required,
+            consent_purpose: config.consent_purpose.clone(),
+        };
+        let named = match &config.projects_file {
+            Some(path) => {
+                let file = std::fs::read_to_string(path).with_context(|| format!("Unable to read projects file {}", path.display()))?;
+                parse_projects(&file, config.ttp.as_ref()).with_context(|| format!("Invalid projects file {}", path.display()))?
+            }
+            None => BTreeMap::new(),
+        };
+        let projects = Self { default, named };
+        // projects sharing a project id system or gPAS domain would share their pseudonyms
+        let mut project_id_systems = BTreeMap::new();
+        let mut gpas_domains = BTreeMap::new();
+        for project in projects.iter() {
+            let Some(domain) = &project.domain else {
+                continue;
+            };
+            if let Some(other) = project_id_systems.insert(domain.project_id_system.as_str(), project) {
+                bail!("Projects {other} and {project} have the same project id system {}", domain.project_id_system);
+            }
+            if let Some(gpas_domain) = &domain.gpas_domain
+                && let Some(other) = gpas_domains.insert(gpas_domain.as_str(), project) {
+                bail!("Projects {other} and {project} have the same gPAS domain {gpas_domain}");
+            }
+        }
+        // data requests with a consent are refused by a fhir ttp, so no data could ever be transferred
+        if let Some(Ttp::Fhir(_)) = &config.ttp
+            && let Some(project) = projects.iter().find(|project| project.consent_required) {
+            bail!("Project {project} requires consents, but the fhir ttp can't document them");
+        }
+        Ok(projects)
+    }
+
+    // The project configured by the environment for None
+    pub fn get(&self, name: Option<&str>) -> Option<&Project> {
+        match name {
+            Some(name) => self.named.get(name),
+            None => Some(&self.default),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Project> {
+        std::iter::once(&self.default).chain(self.named.values())
+    }
+}
+
+fn parse_projects(file: &str, ttp: Option<&Ttp>) -> anyhow::Result<BTreeMap<String, Project>> {
+    let ProjectsFile { projects } = toml::from_str(file)?;
+    projects.into_iter().map(|(name, entry)| {
+        // names are part of the routes of the project
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            bail!("Project name '{name}' may only contain letters, digits, '-' and '_'");
+        }
+        // scopes of the project configured by the environment are granted by this name
+        if name == "default" {
+            bail!("Project name 'default' is reserved for the project configured by the environment");
+        }
+        let domain = match (ttp, entry.project_id_system) {
+            (Some(Ttp::Greifswald(_)), Some(_)) if entry.gpas_domain.is_none() => {
+                bail!("Project {name} needs a gpas_domain of its own to be pseudonymized by the Greifswald tools")
+            }
+            (Some(_), Some(project_id_system)) => Some(ProjectDomain { project_id_system, gpas_domain: entry.gpas_domain }),
+            (Some(_), None) => bail!("Project {name} needs a project_id_system to be pseudonymized by the ttp"),
+            (None, _) => None,
+        };
+        let project = Project {
+            name: Some(name.clone()),
+            domain,
+            output_server: FhirServer::new(
+                entry.fhir_output_url.parse().with_context(|| format!("Invalid fhir_output_url of project {name}"))?,
+                entry.fhir_output_credentials.parse().with_context(|| format!("Invalid fhir_output_credentials of project {name}"))?,
+            ),
+            profile: entry.profile.parse().with_context(|| format!("Invalid profile of project {name}"))?,
+            consent_required: entry.consent_required,
+            consent_purpose: entry.consent_purpose.map(|purpose| purpose.parse()).transpose().with_context(|| format!("Invalid consent_purpose of project {name}"))?,
+        };
+        Ok((name, project))
+    }).collect()
+}
+
+// The project of a request is selected by the {project} parameter of its route, routes without one serve the default project
+impl FromRequestParts<DicAppState> for &'static Project {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &DicAppState) -> Result<Self, Self::Rejection> {
+        let params = RawPathParams::from_request_parts(parts, state)
+            .await
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid path parameters"))?;
+        let name = params.iter().find(|(key, _)| *key == "project").map(|(_, value)| value);
+        state.projects.get(name).ok_or((StatusCode::NOT_FOUND, "Unknown project"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::config::DicConfig;
+
+    use super::{parse_projects, Projects};
+
+    fn config(args: &[&str]) -> DicConfig {
+        let required = [
+            "dic",
+            "--database-url", "sqlite::memory:",
+            "--fhir-request-url", "http://localhost:8085",
+            "--fhir-input-url", "http://localhost:8086",
+            "--fhir-output-url", "http://localhost:8095",
+        ];
+        DicConfig::parse_from(required.iter().chain(args))
+    }
+
+    const PROJECTS: &str = r#"
+        [projects.biobank]
+        project_id_system = "BIOBANK_ID"
+        fhir_output_url = "http://biobank-store:8080"
+        fhir_output_credentials = "transfair:secret"
+        profile = "mii2bbmri"
+        consent_required = true
+
+        [projects.registry]
+        project_id_system = "REGISTRY_ID"
+        gpas_domain = "Registry"
+        fhir_output_url = "http://registry-store:8080"
+        consent_purpose = "http://terminology.hl7.org/CodeSystem/v3-ActReason|HRESCH"
+    "#;
+
+    #[test]
+    fn parse_projects_file() {
+        let projects = parse_projects(PROJECTS, None).unwrap();
+        assert_eq!(projects.keys().collect::<Vec<_>>(), ["biobank", "registry"]);
+        let biobank = &projects["biobank"];
+        assert_eq!(biobank.to_string(), "biobank");
+        assert_eq!(biobank.output_server.url.as_str(), "http://biobank-store:8080/");
+        assert_eq!(biobank.profile.to_string(), "mii2bbmri");
+        assert!(biobank.consent_required);
+        // without a ttp, patients are not pseudonymized
+        assert!(biobank.domain.is_none());
+        let registry = &projects["registry"];
+        assert_eq!(registry.profile.to_string(), "fhircopy");
+        assert_eq!(registry.consent_purpose.as_ref().unwrap().code.as_deref(), Some("HRESCH"));
+    }
+
+    #[test]
+    fn reject_invalid_projects() {
+        assert!(parse_projects("[projects.\"bio bank\"]\nfhir_output_url = \"http://store:8080\"", None).is_err());
+        assert!(parse_projects("[projects.biobank]\nfhir_output_url = \"http://store:8080\"\nprofile = \"unknown\"", None).is_err());
+        assert!(parse_projects("[projects.biobank]\nfhir_output_url = \"http://store:8080\"\noutput = \"typo\"", None).is_err());
+        assert!(parse_projects("[projects.default]\nfhir_output_url = \"http://store:8080\"", None).is_err());
+    }
+
+    #[test]
+    fn fhir_ttp_rejects_required_consents() {
+        let fhir_ttp = ["fhir", "--ttp-url", "http://ttp:8080/fhir/", "--project-id-system", "PROJECT_1_ID", "--ttp-fhir-original-system", "MRN"];
+        assert!(Projects::load(&config(&fhir_ttp)).is_ok());
+        let error = Projects::load(&config(&[&["--consent-required"][..], &fhir_ttp].concat())).unwrap_err();
+        assert_eq!(error.to_string(), "Project default requires consents, but the fhir ttp can't document them");
+    }
+
+    #[test]
+    fn projects_need_their_own_pseudonyms() {
+        let path = std::env::temp_dir().join(format!("transfair-projects-{}.toml", std::process::id()));
+        let greifswald = [
+            "greifswald", "--ttp-url", "http://ttp:8080/", "--project-id-system", "PROJECT_1_ID",
+            "--ttp-gw-source", "dic", "--ttp-gw-epix-domain", "MPI", "--ttp-gw-gpas-domain", "Transferstelle A",
+        ];
+        let projects_file = ["--projects-file", path.to_str().unwrap()];
+        let load = |projects: &str| {
+            std::fs::write(&path, projects).unwrap();
+            Projects::load(&config(&[&projects_file[..], &greifswald].concat())).map_err(|error| format!("{error:#}"))
+        };
+        let projects = load(PROJECTS.replace("BIOBANK_ID\"", "BIOBANK_ID\"\ngpas_domain = \"Biobank\"").as_str()).unwrap();
+        let domains = projects.iter().map(|project| project.domain.as_ref().unwrap().gpas_domain.as_deref().unwrap()).collect::<Vec<_>>();
+        assert_eq!(domains, ["Transferstelle A", "Biobank", "Registry"]);
+        assert!(load(PROJECTS).unwrap_err().ends_with("Project biobank needs a gpas_domain of its own to be pseudonymized by the Greifswald tools"));
+        assert_eq!(
+            load(PROJECTS.replace("BIOBANK_ID\"", "REGISTRY_ID\"\ngpas_domain = \"Biobank\"").as_str()).unwrap_err(),
+            "Projects biobank and registry have the same project id system REGISTRY_ID"
+        );
+        assert_eq!(
+            load(PROJECTS.replace("BIOBANK_ID\"", "BIOBANK_ID\"\ngpas_domain = \"Transferstelle A\"").as_str()).unwrap_err(),
+            "Projects default and biobank have the same gPAS domain Transferstelle A"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
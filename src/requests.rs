@@ -1,18 +1,21 @@
 use axum::{extract::{Path, State}, Json};
 
-use fhir_sdk::r4b::{resources::{Consent, Patient, ResourceType}, types::Reference};
+use fhir_sdk::r4b::{resources::{Consent, Patient, ResourceType}, types::{Identifier, Reference}};
 use reqwest::StatusCode;
 use serde::{Serialize, Deserialize};
 use sqlx::{Pool, Sqlite};
-use tracing::{trace, debug, error};
+use tracing::{trace, debug, error, info, warn};
+use uuid::Uuid;
 
-use crate::{fhir::PatientExt, DicAppState, LinkageError};
+use crate::{auth::{Principal, Scope}, consent::load_consent, fhir::PatientExt, linkage::LinkageError, projects::Project, ttp::TtpError, DicAppState};
 
 #[derive(Serialize, Deserialize, sqlx::Type)]
 pub enum RequestStatus {
     Created = 1,
     Success = 2,
     Error = 3,
+    Revoked = 4,
+    ClarificationRequired = 5,
 }
 
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
@@ -31,27 +34,41 @@ pub struct DataRequestPayload {
     pub consent: Option<Consent>
 }
 
+// Path of a single data request, its project is selected by the route as well
+#[derive(Deserialize)]
+pub struct RequestPath {
+    request_id: String,
+}
+
 // POST /requests; Creates a new Data Request
 pub async fn create_data_request(
-    State(DicAppState { database_pool, config, request_server }): State<DicAppState>,
+    State(DicAppState { database_pool, config, request_server, .. }): State<DicAppState>,
+    principal: Principal,
+    project: &'static Project,
     Json(payload): Json<DataRequestPayload>
 ) -> axum::response::Result<(StatusCode, Json<DataRequest>)> {
+    principal.require(Scope::Create, project)?;
     let consent = payload.consent;
     let mut patient = payload.patient;
 
     let mut project_identifier = None;
+    let mut ttp_consent_id = None;
 
-    if let Some(ttp) = &config.ttp {
+    if let (Some(ttp), Some(domain)) = (&config.ttp, &project.domain) {
         // pseudonymize the patient
-        patient = ttp.request_project_pseudonym(patient, &config.exchange_id_system).await?;
+        patient = match ttp.request_project_pseudonym(patient, domain, &config.exchange_id_system).await {
+            Ok(patient) => patient,
+            Err(TtpError::ClarificationRequired(message)) => return clarification_required(message, project, &database_pool).await.map_err(Into::into),
+            Err(e) => return Err(e.into()),
+        };
         // now, the patient should have project1id data (which can be stored in the DB)
         trace!("TTP Returned these patient with project pseudonym {:#?}", &patient);
         if let Some(ref consent) = consent {
-            ttp.document_patient_consent(consent, &patient).await?;
+            ttp_consent_id = ttp.document_patient_consent(consent, &patient, domain).await?;
         }
         trace!("TTP returned this consent for Patient {:?}", consent);
 
-        project_identifier = patient.get_identifier(&ttp.project_id_system).and_then(|i| i.value.clone());
+        project_identifier = patient.get_identifier(&domain.project_id_system).and_then(|i| i.value.clone());
     }
 
     // ensure that we have at least one identifier with which we can link
@@ -70,6 +87,11 @@ pub async fn create_data_request(
     patient = patient.pseudonymize(&config.exchange_id_system)?;
 
     let linked_consent = consent.map(|c| link_patient_consent(c, &patient, &config.exchange_id_system)).transpose()?;
+    // kept to check deliveries against it
+    let consent_json = linked_consent.as_ref().map(serde_json::to_string).transpose().map_err(|e| {
+        error!("Unable to serialize consent: {e}");
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to serialize consent.")
+    })?;
     // und in beiden fällen anschließend die Anfrage beim Datenintegrationszentrum abgelegt werden
     let data_request_id = request_server.post_data_request(DataRequestPayload {
         patient,
@@ -89,8 +111,8 @@ pub async fn create_data_request(
 
     // storage for associated project id
     let sqlite_query_result = sqlx::query!(
-        "INSERT INTO data_requests (id, status, message, exchange_id, project_id) VALUES ($1, $2, $3, $4, $5)",
-        data_request.id, data_request.status, data_request.message, data_request.exchange_id, data_request.project_id
+        "INSERT INTO data_requests (id, status, message, exchange_id, project_id, consent, ttp_consent_id, project) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        data_request.id, data_request.status, data_request.message, data_request.exchange_id, data_request.project_id, consent_json, ttp_consent_id, project.name
     ).execute(&database_pool).await.map_err(|e| {
         error!("Unable to persist data request to database. {}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, "Unable to persist data request to database.")
@@ -102,13 +124,39 @@ pub async fn create_data_request(
     Ok((StatusCode::CREATED, Json(data_request)))
 }
 
+// Without an unambiguous patient the request can't be sent to the dic. It is kept so the requester can see why,
+// and has to be created again once the identity was clarified in the ttp.
+async fn clarification_required(message: String, project: &Project, database_pool: &Pool<Sqlite>) -> Result<(StatusCode, Json<DataRequest>), (StatusCode, &'static str)> {
+    warn!("{message}");
+    let data_request = DataRequest {
+        id: Uuid::new_v4().to_string(),
+        status: RequestStatus::ClarificationRequired,
+        message: Some(message),
+        // no exchange identifier was created for the patient
+        exchange_id: String::new(),
+        project_id: None,
+    };
+    sqlx::query!(
+        "INSERT INTO data_requests (id, status, message, exchange_id, project_id, project) VALUES ($1, $2, $3, $4, $5, $6)",
+        data_request.id, data_request.status, data_request.message, data_request.exchange_id, data_request.project_id, project.name
+    ).execute(database_pool).await.map_err(|e| {
+        error!("Unable to persist data request to database. {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to persist data request to database.")
+    })?;
+    Ok((StatusCode::ACCEPTED, Json(data_request)))
+}
+
 // GET /requests; Lists all running Data Requests
 pub async fn list_data_requests(
-    State(DicAppState { database_pool, .. }): State<DicAppState>
+    State(DicAppState { database_pool, .. }): State<DicAppState>,
+    principal: Principal,
+    project: &'static Project
 ) -> Result<Json<Vec<DataRequest>>, (StatusCode, &'static str)> {
+    principal.require(Scope::Read, project)?;
     let data_requests = sqlx::query_as!(
         DataRequest,
-        r#"SELECT id, status as "status: _", message, exchange_id, project_id FROM data_requests;"#,
+        r#"SELECT id, status as "status: _", message, exchange_id, project_id FROM data_requests WHERE project IS $1;"#,
+        project.name
     ).fetch_all(&database_pool).await.map_err(|e| {
        error!("Unable to fetch data requests from database: {}", e); 
        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to fetch data requests from database!")
@@ -119,13 +167,16 @@ pub async fn list_data_requests(
 // GET /requests/<request-id>; Gets the Request specified by id in Path
 pub async fn get_data_request(
     State(DicAppState { database_pool, .. }): State<DicAppState>,
-    Path(request_id): Path<String>
+    principal: Principal,
+    project: &'static Project,
+    Path(RequestPath { request_id }): Path<RequestPath>
 ) -> Result<Json<DataRequest>, (StatusCode, &'static str)> {
+    principal.require(Scope::Read, project)?;
     debug!("Information on data request {} requested.", request_id);
     let data_request = sqlx::query_as!(
         DataRequest,
-        r#"SELECT id, status as "status: _", message, exchange_id, project_id FROM data_requests WHERE id = $1;"#,
-        request_id
+        r#"SELECT id, status as "status: _", message, exchange_id, project_id FROM data_requests WHERE id = $1 AND project IS $2;"#,
+        request_id, project.name
     ).fetch_optional(&database_pool).await.map_err(|e| {
         error!("Unable to fetch data request {} from database: {}", request_id, e);
         (StatusCode::INTERNAL_SERVER_ERROR, format!("Unable to fetch data request with id {}", request_id))
@@ -136,6 +187,95 @@ pub async fn get_data_request(
     }
 }
 
+// POST /requests/<request-id>/withdraw; Revokes the Data Request after the patient withdrew consent and deletes the transferred data
+pub async fn withdraw_data_request(
+    State(DicAppState { database_pool, config, fetch_lock, .. }): State<DicAppState>,
+    principal: Principal,
+    project: &'static Project,
+    Path(RequestPath { request_id }): Path<RequestPath>
+) -> Result<Json<DataRequest>, (StatusCode, &'static str)> {
+    principal.require(Scope::Withdraw, project)?;
+    debug!("Withdrawal of data request {} requested.", request_id);
+    // deliveries must not be transferred while the data is deleted
+    let _fetch_guard = fetch_lock.lock().await;
+    let data_request = sqlx::query_as!(
+        DataRequest,
+        r#"SELECT id, status as "status: _", message, exchange_id, project_id FROM data_requests WHERE id = $1 AND project IS $2;"#,
+        request_id, project.name
+    ).fetch_optional(&database_pool).await.map_err(|e| {
+        error!("Unable to fetch data request {} from database: {}", request_id, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to fetch data request from database")
+    })?;
+    let Some(data_request) = data_request else {
+        return Err((StatusCode::NOT_FOUND, "Couldn't retrieve data request with id"));
+    };
+
+    // refuse deliveries from now on, even if the deletion below fails
+    set_revoked(&request_id, "Consent withdrawn, deleting transferred data", &database_pool).await?;
+
+    // the transferred data carries the project pseudonym, or the exchange identifier if no ttp is used
+    let (identifier_system, identifier_value) = match (&project.domain, &data_request.project_id) {
+        (Some(domain), Some(project_id)) => (domain.project_id_system.as_str(), project_id.as_str()),
+        _ => (config.exchange_id_system.as_str(), data_request.exchange_id.as_str()),
+    };
+    // the request stays revoked if anything below fails, its message tells what is left to do and withdrawing again retries it
+    if let Err(e) = project.output_server.delete_patient_data(identifier_system, identifier_value).await {
+        error!("Unable to delete data of data request {} from output server: {e:#}", request_id);
+        set_revoked(&request_id, "Consent withdrawn, deleting transferred data failed", &database_pool).await?;
+        return Err((StatusCode::BAD_GATEWAY, "Unable to delete transferred data from output fhir server"));
+    }
+
+    let documented = async {
+        let (Some(ttp), Some(domain)) = (&config.ttp, &project.domain) else {
+            return Ok(());
+        };
+        let consent = load_consent(&request_id, &database_pool).await.map_err(|e| {
+            error!("Unable to load consent of data request {} from database: {}", request_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Unable to load consent from database")
+        })?;
+        let Some(consent) = consent else {
+            return Ok(());
+        };
+        let patient = Patient::builder()
+            .identifier(vec![
+                Some(Identifier::builder().system(config.exchange_id_system.clone()).value(data_request.exchange_id.clone()).build().expect("Identifier without required fields")),
+                Some(Identifier::builder().system(identifier_system.to_owned()).value(identifier_value.to_owned()).build().expect("Identifier without required fields")),
+            ])
+            .build()
+            .expect("Patient without required fields");
+        ttp.document_consent_revocation(&consent, &patient, domain).await
+    }.await;
+    if let Err(error) = documented {
+        set_revoked(&request_id, "Consent withdrawn, transferred data deleted, documenting the revocation in the ttp failed", &database_pool).await?;
+        return Err(error);
+    }
+
+    let message = "Consent withdrawn, transferred data deleted";
+    set_revoked(&request_id, message, &database_pool).await?;
+    info!("Withdrew data request {}", request_id);
+
+    Ok(Json(DataRequest { status: RequestStatus::Revoked, message: Some(message.to_owned()), ..data_request }))
+}
+
+async fn set_revoked(request_id: &str, message: &str, database_pool: &Pool<Sqlite>) -> Result<(), (StatusCode, &'static str)> {
+    sqlx::query!(
+        "UPDATE data_requests SET status = $1, message = $2 WHERE id = $3",
+        RequestStatus::Revoked, message, request_id
+    ).execute(database_pool).await.map_err(|e| {
+        error!("Unable to revoke data request {}: {}", request_id, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to revoke data request")
+    })?;
+    Ok(())
+}
+
+pub async fn is_revoked(request_id: &str, database_pool: &Pool<Sqlite>) -> sqlx::Result<bool> {
+    let status = sqlx::query_scalar!(
+        r#"SELECT status as "status: RequestStatus" FROM data_requests WHERE id = $1"#,
+        request_id
+    ).fetch_optional(database_pool).await?;
+    Ok(matches!(status, Some(RequestStatus::Revoked)))
+}
+
 fn link_patient_consent(mut consent: Consent, patient: &Patient, exchange_id_system: &str) -> Result<Consent, (StatusCode, &'static str)> {
     let exchange_identifier= patient.get_identifier(exchange_id_system);
     let Some(exchange_identifier) = exchange_identifier else {
@@ -145,19 +285,21 @@ fn link_patient_consent(mut consent: Consent, patient: &Patient, exchange_id_sys
     Ok(consent)
 }
 
-pub async fn update_data_request(bundle_identifier: &str, linkage_results: Option<Vec<Result<ResourceType, LinkageError>>>, database_pool: &Pool<Sqlite>) -> sqlx::Result<()> {
+// Notes (e.g. about filtered resources) are appended to the message
+pub async fn update_data_request(bundle_identifier: &str, linkage_results: Option<Vec<Result<ResourceType, LinkageError>>>, notes: &[String], database_pool: &Pool<Sqlite>) -> sqlx::Result<()> {
+    let with_notes = |message: String| std::iter::once(message).chain(notes.iter().cloned()).collect::<Vec<_>>().join("; ");
     let Some(linkage_results) = linkage_results else {
-        let message_success_without_linkage = format!("Transferred data from input to output FHIR server without linkage.");
+        let message_success_without_linkage = with_notes("Transferred data from input to output FHIR server without linkage.".to_owned());
         let _ = sqlx::query!(
-            "UPDATE data_requests SET status = $1, message = $2 WHERE id=$3",
-            RequestStatus::Success, message_success_without_linkage, bundle_identifier
+            "UPDATE data_requests SET status = $1, message = $2 WHERE id=$3 AND status != $4",
+            RequestStatus::Success, message_success_without_linkage, bundle_identifier, RequestStatus::Revoked
         ).execute(database_pool).await?;
         return Ok(());
     };
-    let result_summary = linkage_results.iter().map(|res| match res {
+    let result_summary = with_notes(linkage_results.iter().map(|res| match res {
         Ok(rt) => format!("{rt}()"),
         Err(error) => format!("{error}"),
-    }).collect::<Vec<String>>().join(",");
+    }).collect::<Vec<String>>().join(","));
     debug!("{}", result_summary);
 
     let result_status = match linkage_results.iter().any(Result::is_err) {
@@ -166,8 +308,108 @@ pub async fn update_data_request(bundle_identifier: &str, linkage_results: Optio
     };
 
     let _ = sqlx::query!(
-        "UPDATE data_requests SET status = $1, message = $2 WHERE id=$3",
-        result_status, result_summary, bundle_identifier
+        "UPDATE data_requests SET status = $1, message = $2 WHERE id=$3 AND status != $4",
+        result_status, result_summary, bundle_identifier, RequestStatus::Revoked
     ).execute(database_pool).await?;
     Ok(())
 }
+
+pub async fn fail_data_request(bundle_identifier: &str, message: &str, database_pool: &Pool<Sqlite>) -> sqlx::Result<()> {
+    let _ = sqlx::query!(
+        "UPDATE data_requests SET status = $1, message = $2 WHERE id=$3 AND status != $4",
+        RequestStatus::Error, message, bundle_identifier, RequestStatus::Revoked
+    ).execute(database_pool).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::{Path, RawQuery}, routing::{get, post}, Json, Router};
+    use reqwest::StatusCode;
+    use serde_json::{json, Value};
+    use sqlx::SqlitePool;
+
+    use crate::{app, stub_server, DicAppState, CLIENT};
+
+    use super::{fail_data_request, is_revoked, set_revoked, DataRequest, RequestStatus};
+
+    // Output server with a patient, a condition referencing it by id, an observation referencing it by identifier and a
+    // specimen of another patient that is returned for every search, like servers ignoring unknown search parameters do
+    fn output_server(transactions: Arc<Mutex<Vec<Value>>>, status: StatusCode) -> Router {
+        let search = |Path(rt): Path<String>, RawQuery(query): RawQuery| async move {
+            let query = query.unwrap_or_default();
+            let resource = match (rt.as_str(), query.as_str()) {
+                ("Patient", "identifier=TOKEN%7Cexchange") => json!({"resourceType": "Patient", "id": "p1", "identifier": [{"system": "TOKEN", "value": "exchange"}]}),
+                ("Condition", "patient=Patient%2Fp1") => json!({"resourceType": "Condition", "id": "c1", "subject": {"reference": "Patient/p1"}}),
+                ("Observation", "subject%3Aidentifier=TOKEN%7Cexchange") => json!({
+                    "resourceType": "Observation", "id": "o1", "status": "final", "code": {"text": "Weight"},
+                    "subject": {"identifier": {"system": "TOKEN", "value": "exchange"}}
+                }),
+                ("Specimen", _) => json!({"resourceType": "Specimen", "id": "s1", "subject": {"reference": "Patient/other"}}),
+                _ => return Json(json!({"resourceType": "Bundle", "type": "searchset"})),
+            };
+            Json(json!({"resourceType": "Bundle", "type": "searchset", "entry": [{"resource": resource}]}))
+        };
+        let transaction = move |Json(bundle): Json<Value>| async move {
+            transactions.lock().unwrap().push(bundle);
+            (status, Json(json!({"resourceType": "Bundle", "type": "transaction-response"})))
+        };
+        Router::new().route("/fhir/{rt}", get(search)).route("/fhir", post(transaction))
+    }
+
+    async fn withdraw(status: StatusCode) -> (reqwest::Response, DicAppState, Vec<Value>) {
+        let transactions = Arc::default();
+        let output_url = stub_server(output_server(Arc::clone(&transactions), status)).await;
+        let state = DicAppState::for_tests(&["--fhir-output-url", &output_url, "--exchange-id-system", "TOKEN"]).await;
+        sqlx::query("INSERT INTO data_requests (id, exchange_id) VALUES ('request', 'exchange')")
+            .execute(&state.database_pool).await.unwrap();
+        let url = stub_server(app(state.clone())).await;
+        let response = CLIENT.post(format!("{url}/requests/request/withdraw")).send().await.unwrap();
+        let transactions = transactions.lock().unwrap().clone();
+        (response, state, transactions)
+    }
+
+    #[tokio::test]
+    async fn withdrawal_deletes_transferred_data_by_id() {
+        let (response, state, transactions) = withdraw(StatusCode::OK).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let data_request = response.json::<DataRequest>().await.unwrap();
+        assert!(matches!(data_request.status, RequestStatus::Revoked));
+        assert_eq!(data_request.message.as_deref(), Some("Consent withdrawn, transferred data deleted"));
+        assert!(is_revoked("request", &state.database_pool).await.unwrap());
+        // only the resources referencing the patient are deleted, each by its id
+        assert_eq!(transactions, [json!({"resourceType": "Bundle", "type": "transaction", "entry": [
+            {"request": {"method": "DELETE", "url": "Condition/c1"}},
+            {"request": {"method": "DELETE", "url": "Observation/o1"}},
+            {"request": {"method": "DELETE", "url": "Patient/p1"}}
+        ]})]);
+    }
+
+    #[tokio::test]
+    async fn failed_withdrawal_stays_revoked() {
+        let (response, state, _) = withdraw(StatusCode::CONFLICT).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let message = sqlx::query_scalar!("SELECT message FROM data_requests WHERE id = 'request'").fetch_one(&state.database_pool).await.unwrap();
+        assert_eq!(message.as_deref(), Some("Consent withdrawn, deleting transferred data failed"));
+        assert!(is_revoked("request", &state.database_pool).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn revoked_request_keeps_status() {
+        let database_pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&database_pool).await.unwrap();
+        sqlx::query!("INSERT INTO data_requests (id, exchange_id) VALUES ('request', 'exchange')")
+            .execute(&database_pool).await.unwrap();
+        assert!(!is_revoked("request", &database_pool).await.unwrap());
+
+        set_revoked("request", "Consent withdrawn", &database_pool).await.unwrap();
+        // a delivery that is refused afterwards must not overwrite the revocation
+        fail_data_request("request", "Consent does not permit transfer: Consent was withdrawn", &database_pool).await.unwrap();
+
+        assert!(is_revoked("request", &database_pool).await.unwrap());
+        let message = sqlx::query_scalar!("SELECT message FROM data_requests WHERE id = 'request'").fetch_one(&database_pool).await.unwrap();
+        assert_eq!(message.as_deref(), Some("Consent withdrawn"));
+    }
+}
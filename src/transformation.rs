@@ -0,0 +1,122 @@
+//! Transformations applied to delivered data after linkage, before it is posted to the output server
+mod bbmri2mii;
+mod fhircopy;
+mod mii2bbmri;
+
+use std::{fmt::{Debug, Display}, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use fhir_sdk::r4b::resources::Bundle;
+use serde_json::{json, Value};
+
+pub trait Transformation: Sync {
+    // Transforms the linked delivery bundle in place
+    fn transform(&self, bundle: &mut Bundle) -> anyhow::Result<()>;
+}
+
+// All built-in transformations by profile name. New mappings are added as a module and registered here.
+static PROFILES: &[(&str, &dyn Transformation)] = &[
+    ("fhircopy", &fhircopy::FhirCopy),
+    ("mii2bbmri", &mii2bbmri::Mii2Bbmri),
+    ("bbmri2mii", &bbmri2mii::Bbmri2Mii),
+];
+
+// Transformation selected for a deployment
+#[derive(Clone, Copy)]
+pub struct Profile {
+    name: &'static str,
+    transformation: &'static dyn Transformation,
+}
+
+impl Profile {
+    pub fn transform(&self, bundle: &mut Bundle) -> anyhow::Result<()> {
+        self.transformation.transform(bundle)
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PROFILES
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|&(name, transformation)| Self { name, transformation })
+            .ok_or_else(|| anyhow!(
+                "Unknown profile '{s}', available profiles are: {}",
+                PROFILES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+            ))
+    }
+}
+
+// Mappings between profiles are easier to express on the JSON representation than on the typed resources
+fn edit_as_json(bundle: &mut Bundle, edit: impl FnOnce(&mut Value) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let mut json = serde_json::to_value(&*bundle).context("Unable to serialize bundle")?;
+    edit(&mut json)?;
+    *bundle = serde_json::from_value(json).context("Transformed bundle is no valid FHIR bundle")?;
+    Ok(())
+}
+
+fn resources_mut(bundle: &mut Value) -> impl Iterator<Item = &mut Value> {
+    bundle
+        .get_mut("entry")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.get_mut("resource"))
+}
+
+fn resource_type(resource: &Value) -> &str {
+    resource.get("resourceType").and_then(Value::as_str).unwrap_or_default()
+}
+
+// Used in error messages, e.g. "Specimen/123"
+fn resource_name(resource: &Value) -> String {
+    let id = resource.get("id").and_then(Value::as_str).unwrap_or("<no id>");
+    format!("{}/{id}", resource_type(resource))
+}
+
+fn set_profile(resource: &mut Value, profile: &str) {
+    resource["meta"]["profile"] = json!([profile]);
+}
+
+// All codings of a CodeableConcept
+fn codings(codeable_concept: Option<&Value>) -> impl Iterator<Item = &Value> {
+    codeable_concept
+        .and_then(|concept| concept.get("coding"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+}
+
+// Code of the first coding from one of the given systems
+fn find_code<'a>(codeable_concept: Option<&'a Value>, systems: &[&str]) -> Option<&'a str> {
+    codings(codeable_concept)
+        .find(|coding| coding.get("system").and_then(Value::as_str).is_some_and(|system| systems.contains(&system)))
+        .and_then(|coding| coding.get("code"))
+        .and_then(Value::as_str)
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl Debug for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Profile({})", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profile;
+
+    #[test]
+    fn parse_profile() {
+        assert_eq!("fhircopy".parse::<Profile>().unwrap().to_string(), "fhircopy");
+        let error = "unknown".parse::<Profile>().unwrap_err();
+        assert_eq!(error.to_string(), "Unknown profile 'unknown', available profiles are: fhircopy, mii2bbmri, bbmri2mii");
+    }
+}
@@ -0,0 +1,142 @@
+//! Maps resources of a BBMRI-ERIC Bridgehead onto the profiles of the MII Core Dataset and its biobank module
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
+use fhir_sdk::r4b::resources::Bundle;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use super::{
+    edit_as_json, find_code, mii2bbmri::{BBMRI_SAMPLE_DIAGNOSIS, ICD_10_GM_SYSTEMS, ICD_10_SYSTEM, MII_SPECIMEN_DIAGNOSIS, OBSERVATION_PROFILES, SAMPLE_MATERIAL_TYPES, SAMPLE_MATERIAL_TYPE_SYSTEM, SNOMED_SYSTEM},
+    resource_name, resource_type, resources_mut, set_profile, Transformation
+};
+
+const MII_PATIENT: &str = "https://www.medizininformatik-initiative.de/fhir/core/modul-person/StructureDefinition/Patient";
+const MII_CONDITION: &str = "https://www.medizininformatik-initiative.de/fhir/core/modul-diagnose/StructureDefinition/Diagnose";
+const MII_SPECIMEN: &str = "https://www.medizininformatik-initiative.de/fhir/ext/modul-biobank/StructureDefinition/Specimen";
+
+pub struct Bbmri2Mii;
+
+impl Transformation for Bbmri2Mii {
+    fn transform(&self, bundle: &mut Bundle) -> anyhow::Result<()> {
+        edit_as_json(bundle, |bundle| {
+            let conditions = conditions_by_code(bundle);
+            for resource in resources_mut(bundle) {
+                match resource_type(resource) {
+                    "Patient" => set_profile(resource, MII_PATIENT),
+                    "Condition" => map_condition(resource)?,
+                    "Specimen" => map_specimen(resource, &conditions)?,
+                    "Observation" => map_observation(resource),
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+// References to the conditions of the bundle by their ICD-10 code, the MII biobank module references the diagnosis of a sample
+fn conditions_by_code(bundle: &Value) -> HashMap<String, String> {
+    bundle
+        .get("entry")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let resource = entry.get("resource").filter(|resource| resource_type(resource) == "Condition")?;
+            let code = find_code(resource.get("code"), &[ICD_10_SYSTEM])?;
+            let reference = match entry.get("fullUrl").and_then(Value::as_str) {
+                Some(full_url) => full_url.to_owned(),
+                None => resource_name(resource),
+            };
+            Some((code.to_owned(), reference))
+        })
+        .collect()
+}
+
+// ICD-10 WHO categories are valid ICD-10-GM codes, so only the code system changes
+fn map_condition(condition: &mut Value) -> anyhow::Result<()> {
+    if find_code(condition.get("code"), ICD_10_GM_SYSTEMS).is_none() {
+        let Some(icd_10) = find_code(condition.get("code"), &[ICD_10_SYSTEM]).map(ToOwned::to_owned) else {
+            bail!("{}: Diagnosis is not coded in ICD-10", resource_name(condition));
+        };
+        let text = condition["code"].get("text").cloned();
+        condition["code"] = json!({"coding": [{"system": ICD_10_GM_SYSTEMS[0], "code": icd_10}]});
+        if let Some(text) = text {
+            condition["code"]["text"] = text;
+        }
+    }
+    set_profile(condition, MII_CONDITION);
+    Ok(())
+}
+
+fn map_specimen(specimen: &mut Value, conditions: &HashMap<String, String>) -> anyhow::Result<()> {
+    let snomed = match find_code(specimen.get("type"), &[SNOMED_SYSTEM]) {
+        Some(snomed) => snomed,
+        None => {
+            let Some(material_type) = find_code(specimen.get("type"), &[SAMPLE_MATERIAL_TYPE_SYSTEM]) else {
+                bail!("{}: Specimen type is not a BBMRI-ERIC sample material type", resource_name(specimen));
+            };
+            SAMPLE_MATERIAL_TYPES
+                .iter()
+                .find(|(_, code)| *code == material_type)
+                .map(|(snomed, _)| *snomed)
+                .ok_or_else(|| anyhow!("{}: No SNOMED CT code for sample material type {material_type}", resource_name(specimen)))?
+        }
+    };
+    specimen["type"] = json!({"coding": [{"system": SNOMED_SYSTEM, "code": snomed}]});
+
+    if let Some(extensions) = specimen.get_mut("extension").and_then(Value::as_array_mut) {
+        let mut mapped = Vec::with_capacity(extensions.len());
+        for extension in extensions.drain(..) {
+            if extension.get("url").and_then(Value::as_str) != Some(BBMRI_SAMPLE_DIAGNOSIS) {
+                mapped.push(extension);
+                continue;
+            }
+            let icd_10 = find_code(extension.get("valueCodeableConcept"), &[ICD_10_SYSTEM]).unwrap_or_default();
+            match conditions.get(icd_10) {
+                Some(reference) => mapped.push(json!({
+                    "url": MII_SPECIMEN_DIAGNOSIS,
+                    "valueReference": {"reference": reference}
+                })),
+                // the biobank module can only reference a diagnosis, not code it on the sample
+                None => warn!("Dropping diagnosis of specimen, no condition with code '{icd_10}' is part of the delivery"),
+            }
+        }
+        *extensions = mapped;
+    }
+    set_profile(specimen, MII_SPECIMEN);
+    Ok(())
+}
+
+// The MII Core Dataset has no profiles for these observations, so they must not claim the BBMRI-ERIC ones anymore
+fn map_observation(observation: &mut Value) {
+    let Some(profiles) = observation.pointer_mut("/meta/profile").and_then(Value::as_array_mut) else {
+        return;
+    };
+    profiles.retain(|profile| !OBSERVATION_PROFILES.iter().any(|(_, bbmri_profile)| profile.as_str() == Some(*bbmri_profile)));
+    if profiles.is_empty() {
+        let meta = observation["meta"].as_object_mut().expect("meta with profiles is an object");
+        meta.remove("profile");
+        if meta.is_empty() {
+            observation.as_object_mut().expect("resources are objects").remove("meta");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fhir_sdk::r4b::resources::Bundle;
+
+    use crate::transformation::Transformation;
+
+    use super::Bbmri2Mii;
+
+    #[test]
+    fn transform_example_data() {
+        let mut bundle: Bundle = serde_json::from_str(include_str!("../../docs/examples/mii2bbmri_output_data.json")).unwrap();
+        let expected: Bundle = serde_json::from_str(include_str!("../../docs/examples/bbmri2mii_output_data.json")).unwrap();
+        Bbmri2Mii.transform(&mut bundle).unwrap();
+        assert_eq!(serde_json::to_value(&bundle).unwrap(), serde_json::to_value(&expected).unwrap());
+    }
+}
@@ -0,0 +1,12 @@
+//! Transfers the delivered resources unchanged
+use fhir_sdk::r4b::resources::Bundle;
+
+use super::Transformation;
+
+pub struct FhirCopy;
+
+impl Transformation for FhirCopy {
+    fn transform(&self, _bundle: &mut Bundle) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+//! Maps resources of the MII Core Dataset onto the BBMRI-ERIC profiles of the Bridgehead
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
+use fhir_sdk::r4b::resources::Bundle;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use super::{codings, edit_as_json, find_code, resource_name, resource_type, resources_mut, set_profile, Transformation};
+
+pub(super) const ICD_10_GM_SYSTEMS: &[&str] = &["http://fhir.de/CodeSystem/bfarm/icd-10-gm", "http://fhir.de/CodeSystem/dimdi/icd-10-gm"];
+pub(super) const ICD_10_SYSTEM: &str = "http://hl7.org/fhir/sid/icd-10";
+pub(super) const SNOMED_SYSTEM: &str = "http://snomed.info/sct";
+pub(super) const LOINC_SYSTEM: &str = "http://loinc.org";
+pub(super) const SAMPLE_MATERIAL_TYPE_SYSTEM: &str = "https://fhir.bbmri.de/CodeSystem/SampleMaterialType";
+
+pub(super) const BBMRI_PATIENT: &str = "https://fhir.bbmri.de/StructureDefinition/Patient";
+pub(super) const BBMRI_CONDITION: &str = "https://fhir.bbmri.de/StructureDefinition/Condition";
+pub(super) const BBMRI_SPECIMEN: &str = "https://fhir.bbmri.de/StructureDefinition/Specimen";
+pub(super) const BBMRI_SAMPLE_DIAGNOSIS: &str = "https://fhir.bbmri.de/StructureDefinition/SampleDiagnosis";
+pub(super) const MII_SPECIMEN_DIAGNOSIS: &str = "https://www.medizininformatik-initiative.de/fhir/ext/modul-biobank/StructureDefinition/Diagnose";
+
+// SNOMED CT specimen types of the MII biobank module and their BBMRI-ERIC sample material type.
+// For the reverse mapping the first SNOMED CT code of a sample material type is used.
+pub(super) const SAMPLE_MATERIAL_TYPES: &[(&str, &str)] = &[
+    ("258580003", "whole-blood"),
+    ("119297000", "whole-blood"),
+    ("119364003", "blood-serum"),
+    ("119361006", "blood-plasma"),
+    ("258587000", "buffy-coat"),
+    ("119359002", "bone-marrow"),
+    ("441652008", "tissue-ffpe"),
+    ("119376003", "tissue-other"),
+    ("448789008", "dna"),
+    ("441673008", "rna"),
+    ("122575003", "urine"),
+    ("119342007", "saliva"),
+    ("258450006", "liquor"),
+    ("119339001", "stool-faeces"),
+];
+
+// Observations with a BBMRI-ERIC profile by their LOINC code
+pub(super) const OBSERVATION_PROFILES: &[(&str, &str)] = &[
+    ("29463-7", "https://fhir.bbmri.de/StructureDefinition/BodyWeight"),
+    ("8302-2", "https://fhir.bbmri.de/StructureDefinition/BodyHeight"),
+    ("39156-5", "https://fhir.bbmri.de/StructureDefinition/Bmi"),
+    ("72166-2", "https://fhir.bbmri.de/StructureDefinition/TobaccoUse"),
+];
+
+pub struct Mii2Bbmri;
+
+impl Transformation for Mii2Bbmri {
+    fn transform(&self, bundle: &mut Bundle) -> anyhow::Result<()> {
+        edit_as_json(bundle, |bundle| {
+            let condition_aliases = condition_aliases(bundle);
+            // sample diagnoses are taken from the already mapped conditions
+            let mut diagnoses = HashMap::new();
+            for resource in resources_mut(bundle).filter(|resource| resource_type(resource) == "Condition") {
+                let icd_10 = map_condition(resource)?;
+                if let Some(id) = resource.get("id").and_then(Value::as_str) {
+                    diagnoses.insert(format!("Condition/{id}"), icd_10);
+                }
+            }
+            for (alias, reference) in condition_aliases {
+                if let Some(icd_10) = diagnoses.get(&reference).cloned() {
+                    diagnoses.insert(alias, icd_10);
+                }
+            }
+            for resource in resources_mut(bundle) {
+                match resource_type(resource) {
+                    "Patient" => set_profile(resource, BBMRI_PATIENT),
+                    "Specimen" => map_specimen(resource, &diagnoses)?,
+                    "Observation" => map_observation(resource),
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+// fullUrls under which conditions of the bundle are referenced
+fn condition_aliases(bundle: &Value) -> Vec<(String, String)> {
+    bundle
+        .get("entry")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let full_url = entry.get("fullUrl")?.as_str()?;
+            let resource = entry.get("resource")?;
+            (resource_type(resource) == "Condition").then(|| (full_url.to_owned(), resource_name(resource)))
+        })
+        .collect()
+}
+
+// Replaces the ICD-10-GM diagnosis by its ICD-10 WHO category and returns the new code
+fn map_condition(condition: &mut Value) -> anyhow::Result<String> {
+    let icd_10 = match find_code(condition.get("code"), ICD_10_GM_SYSTEMS) {
+        Some(icd_10_gm) => icd_10_gm_to_who(icd_10_gm)
+            .ok_or_else(|| anyhow!("{}: '{icd_10_gm}' is no valid ICD-10-GM code", resource_name(condition)))?,
+        None => find_code(condition.get("code"), &[ICD_10_SYSTEM])
+            .ok_or_else(|| anyhow!("{}: Diagnosis is not coded in ICD-10-GM or ICD-10", resource_name(condition)))?
+            .to_owned(),
+    };
+    let text = condition["code"].get("text").cloned();
+    condition["code"] = json!({"coding": [{"system": ICD_10_SYSTEM, "code": icd_10}]});
+    if let Some(text) = text {
+        condition["code"]["text"] = text;
+    }
+    set_profile(condition, BBMRI_CONDITION);
+    Ok(icd_10)
+}
+
+// ICD-10-GM extends the WHO classification by a fifth character and marks secondary codes (e.g. "E11.90", "G63.2*")
+fn icd_10_gm_to_who(code: &str) -> Option<String> {
+    let code = code.trim().trim_end_matches(['†', '*', '!', '+']);
+    let (category, subcategory) = code.split_once('.').unwrap_or((code, ""));
+    let mut category_chars = category.chars();
+    let valid_category = category.len() == 3
+        && category_chars.next().is_some_and(|c| c.is_ascii_uppercase())
+        && category_chars.all(|c| c.is_ascii_digit());
+    if !valid_category {
+        return None;
+    }
+    Some(match subcategory.chars().next() {
+        Some(digit) if digit.is_ascii_digit() => format!("{category}.{digit}"),
+        Some(_) => return None,
+        None => category.to_owned(),
+    })
+}
+
+fn map_specimen(specimen: &mut Value, diagnoses: &HashMap<String, String>) -> anyhow::Result<()> {
+    let material_type = match find_code(specimen.get("type"), &[SAMPLE_MATERIAL_TYPE_SYSTEM]) {
+        Some(material_type) => material_type,
+        None => {
+            let Some(snomed) = find_code(specimen.get("type"), &[SNOMED_SYSTEM]) else {
+                bail!("{}: Specimen type is not coded in SNOMED CT", resource_name(specimen));
+            };
+            SAMPLE_MATERIAL_TYPES
+                .iter()
+                .find(|(code, _)| *code == snomed)
+                .map(|(_, material_type)| *material_type)
+                .ok_or_else(|| anyhow!("{}: No BBMRI-ERIC sample material type for SNOMED CT code {snomed}", resource_name(specimen)))?
+        }
+    };
+    specimen["type"] = json!({"coding": [{"system": SAMPLE_MATERIAL_TYPE_SYSTEM, "code": material_type}]});
+
+    if let Some(extensions) = specimen.get_mut("extension").and_then(Value::as_array_mut) {
+        let mut mapped = Vec::with_capacity(extensions.len());
+        for extension in extensions.drain(..) {
+            if extension.get("url").and_then(Value::as_str) != Some(MII_SPECIMEN_DIAGNOSIS) {
+                mapped.push(extension);
+                continue;
+            }
+            let reference = extension.pointer("/valueReference/reference").and_then(Value::as_str).unwrap_or_default();
+            match diagnoses.get(reference) {
+                Some(icd_10) => mapped.push(json!({
+                    "url": BBMRI_SAMPLE_DIAGNOSIS,
+                    "valueCodeableConcept": {"coding": [{"system": ICD_10_SYSTEM, "code": icd_10}]}
+                })),
+                // the condition may have been delivered earlier, the sample is still transferred without its diagnosis
+                None => warn!("Dropping diagnosis of specimen, referenced condition '{reference}' is not part of the delivery"),
+            }
+        }
+        *extensions = mapped;
+    }
+    set_profile(specimen, BBMRI_SPECIMEN);
+    Ok(())
+}
+
+fn map_observation(observation: &mut Value) {
+    let profile = codings(observation.get("code"))
+        .filter(|coding| coding.get("system").and_then(Value::as_str) == Some(LOINC_SYSTEM))
+        .filter_map(|coding| coding.get("code").and_then(Value::as_str))
+        .find_map(|code| OBSERVATION_PROFILES.iter().find(|(loinc, _)| *loinc == code))
+        .map(|(_, profile)| *profile);
+    if let Some(profile) = profile {
+        set_profile(observation, profile);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fhir_sdk::r4b::resources::Bundle;
+
+    use crate::transformation::Transformation;
+
+    use super::{icd_10_gm_to_who, Mii2Bbmri};
+
+    #[test]
+    fn icd_10_gm_codes_are_reduced_to_who_categories() {
+        assert_eq!(icd_10_gm_to_who("C50.9").as_deref(), Some("C50.9"));
+        assert_eq!(icd_10_gm_to_who("E11.90").as_deref(), Some("E11.9"));
+        assert_eq!(icd_10_gm_to_who("G63.2*").as_deref(), Some("G63.2"));
+        assert_eq!(icd_10_gm_to_who("I10").as_deref(), Some("I10"));
+        assert_eq!(icd_10_gm_to_who("not a code"), None);
+    }
+
+    #[test]
+    fn transform_example_data() {
+        let mut bundle: Bundle = serde_json::from_str(include_str!("../../docs/examples/mii_input_data.json")).unwrap();
+        let expected: Bundle = serde_json::from_str(include_str!("../../docs/examples/mii2bbmri_output_data.json")).unwrap();
+        Mii2Bbmri.transform(&mut bundle).unwrap();
+        assert_eq!(serde_json::to_value(&bundle).unwrap(), serde_json::to_value(&expected).unwrap());
+    }
+}
@@ -1,11 +1,15 @@
 pub(crate) mod mainzelliste;
 pub mod greifswald;
+pub mod fhir;
+pub mod local;
+mod soap;
 
-use std::ops::Deref;
+use std::fmt::Display;
 
 use axum::response::IntoResponse;
-use fhir_sdk::r4b::resources::{Consent, Patient};
+use fhir_sdk::r4b::{codes::ConsentState, resources::{Consent, Patient}};
 use reqwest::{StatusCode, Url};
+use sqlx::{Pool, Sqlite};
 use thiserror::Error;
 
 use crate::config::Auth;
@@ -30,28 +34,77 @@ pub struct TtpInner {
     pub ttp_auth: Auth,
 }
 
+// Where the pseudonyms of a project are kept: they are stored with the project id system in the project data and
+// created in the domain of the same name, except for the Greifswald tools which name their gPAS domains independently
+#[derive(Debug, Clone)]
+pub struct ProjectDomain {
+    pub project_id_system: String,
+    pub gpas_domain: Option<String>,
+}
+
+// Parsed once at startup, so the size of the variants doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, clap::Subcommand)]
 pub enum Ttp {
     Mainzelliste(mainzelliste::MlConfig),
-    Greifswald(greifswald::GreifswaldConfig)
+    Greifswald(greifswald::GreifswaldConfig),
+    Fhir(fhir::FhirTtpConfig),
+    Local(local::LocalConfig),
 }
 
-impl Deref for Ttp {
-    type Target = TtpInner;
-
-    fn deref(&self) -> &Self::Target {
+impl Display for Ttp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Ttp::Mainzelliste(config) => config,
-            Ttp::Greifswald(config) => config,
+            Ttp::Mainzelliste(config) => write!(f, "{}", config.url),
+            Ttp::Greifswald(config) => write!(f, "{}", config.url),
+            Ttp::Fhir(config) => write!(f, "{}", config.url),
+            Ttp::Local(_) => f.write_str("local pseudonymization"),
         }
     }
 }
 
 impl Ttp {
+    // defines the identifier to safe in the project database of the project configured by the environment
+    pub fn project_id_system(&self) -> &str {
+        match self {
+            Ttp::Mainzelliste(config) => &config.project_id_system,
+            Ttp::Greifswald(config) => &config.project_id_system,
+            Ttp::Fhir(config) => &config.project_id_system,
+            Ttp::Local(config) => &config.project_id_system,
+        }
+    }
+
+    // gPAS domain of the project configured by the environment, only the Greifswald tools have one
+    pub fn gpas_domain(&self) -> Option<&str> {
+        match self {
+            Ttp::Greifswald(config) => Some(&config.gpas_domain),
+            _ => None,
+        }
+    }
+
+    // Credentials for the ttp, the local ttp has none
+    pub fn auth(&self) -> Option<&Auth> {
+        match self {
+            Ttp::Mainzelliste(config) => Some(&config.ttp_auth),
+            Ttp::Greifswald(config) => Some(&config.ttp_auth),
+            Ttp::Fhir(config) => Some(&config.ttp_auth),
+            Ttp::Local(_) => None,
+        }
+    }
+
+    // The local ttp keeps its pseudonyms in the database of transFAIR
+    pub fn use_database(&self, database_pool: &Pool<Sqlite>) {
+        if let Ttp::Local(config) = self {
+            config.use_database(database_pool);
+        }
+    }
+
     pub async fn check_availability(&self) -> bool {
         match self {
             Ttp::Mainzelliste(config) => config.check_availability().await,
             Ttp::Greifswald(config) => config.check_availability().await,
+            Ttp::Fhir(config) => config.check_availability().await,
+            Ttp::Local(config) => config.check_availability().await,
         }
     }
 
@@ -59,37 +112,83 @@ impl Ttp {
         match self {
             Ttp::Mainzelliste(config) => config.check_idtype_available(idtype).await,
             Ttp::Greifswald(config) => config.check_idtype_available(idtype).await,
+            Ttp::Fhir(config) => config.check_idtype_available(idtype).await,
+            Ttp::Local(config) => config.check_idtype_available(idtype).await,
         }
     }
 
+    // Returns the id of the consent at the ttp, if the ttp assigns one
     pub async fn document_patient_consent(
         &self,
         consent: &Consent,
         patient: &Patient,
-    ) -> Result<(), (StatusCode, &'static str)> {
+        domain: &ProjectDomain,
+    ) -> Result<Option<String>, (StatusCode, &'static str)> {
         match self {
             Ttp::Mainzelliste(config) => config.document_patient_consent(consent, patient).await,
-            Ttp::Greifswald(..) => Err((StatusCode::NOT_IMPLEMENTED, "Documenting patient consent with Greifswald tools is not yet implemented")),
+            Ttp::Greifswald(config) => config.document_patient_consent(consent, patient, domain).await
+                .map(|()| None)
+                .map_err(|e| consent_error(e, "Unable to document consent in gICS")),
+            Ttp::Fhir(config) => config.document_patient_consent(consent, patient).await,
+            Ttp::Local(config) => config.document_patient_consent(consent, patient).await,
+        }
+    }
+
+    // Documents the withdrawal of a consent as the same consent with status inactive
+    pub async fn document_consent_revocation(
+        &self,
+        consent: &Consent,
+        patient: &Patient,
+        domain: &ProjectDomain,
+    ) -> Result<(), (StatusCode, &'static str)> {
+        let mut revocation = consent.clone();
+        revocation.status = ConsentState::Inactive;
+        // the ttp links the consent to the patient itself
+        revocation.patient = None;
+        self.document_patient_consent(&revocation, patient, domain).await.map(|_| ())
+    }
+
+    // Exchange id of the patient with the project pseudonym, if the ttp knows the pseudonym
+    pub async fn resolve_pseudonym(
+        &self,
+        project_pseudonym: &str,
+        domain: &ProjectDomain,
+        exchange_id_system: &str,
+    ) -> Result<Option<String>, TtpError> {
+        match self {
+            Ttp::Mainzelliste(config) => config.resolve_pseudonym(project_pseudonym, domain, exchange_id_system).await,
+            Ttp::Greifswald(config) => config.resolve_pseudonym(project_pseudonym, domain).await,
+            Ttp::Fhir(config) => config.resolve_pseudonym(project_pseudonym, domain, exchange_id_system).await,
+            Ttp::Local(config) => config.resolve_pseudonym(project_pseudonym, domain, exchange_id_system).await,
         }
     }
 
     pub async fn request_project_pseudonym(
         &self,
         patient: Patient,
+        domain: &ProjectDomain,
         exchange_id_system: &str,
-    ) -> axum::response::Result<Patient> {
+    ) -> Result<Patient, TtpError> {
         match self {
-            Ttp::Mainzelliste(config) => config.request_project_pseudonym(patient, exchange_id_system).await.map_err(Into::into),
-            Ttp::Greifswald(config) => config.request_project_pseudonym(patient, exchange_id_system).await.map_err(Into::into),
+            Ttp::Mainzelliste(config) => config.request_project_pseudonym(patient, domain, exchange_id_system).await,
+            Ttp::Greifswald(config) => config.request_project_pseudonym(patient, domain, exchange_id_system).await,
+            Ttp::Fhir(config) => config.request_project_pseudonym(patient, domain, exchange_id_system).await,
+            Ttp::Local(config) => config.request_project_pseudonym(patient, domain, exchange_id_system).await,
         }
     }
 }
 
 
 #[derive(Debug, Error)]
-enum TtpError {
+pub enum TtpError {
     #[error("Failed to request Ttp: {0:#}")]
     RequestError(#[from] reqwest::Error),
+    // The ttp couldn't decide on the identity of the patient, e.g. a possible match in E-PIX
+    #[error("Manual clarification required: {0}")]
+    ClarificationRequired(String),
+    // The web service of the ttp reported an error, e.g. an unknown domain in gPAS
+    #[error("Ttp responded with fault {code}: {message}")]
+    SoapFault { code: String, message: String, exception: Option<String> },
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -101,11 +200,22 @@ impl IntoResponse for TtpError {
             TtpError::RequestError(..) => {
                 (StatusCode::SERVICE_UNAVAILABLE, "Failed to connect to ttp").into_response()
             },
+            TtpError::ClarificationRequired(message) => (StatusCode::CONFLICT, message).into_response(),
+            TtpError::SoapFault { .. } => (StatusCode::BAD_GATEWAY, "Ttp rejected the request").into_response(),
             TtpError::Other(..) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
 }
 
+// A data request fails if its consent can't be documented, the status tells whether the ttp was unreachable or refused it
+fn consent_error(error: TtpError, message: &'static str) -> (StatusCode, &'static str) {
+    tracing::warn!("{message}: {error:#}");
+    match error {
+        TtpError::RequestError(..) => (StatusCode::SERVICE_UNAVAILABLE, "Failed to connect to ttp"),
+        TtpError::ClarificationRequired(..) | TtpError::SoapFault { .. } | TtpError::Other(..) => (StatusCode::BAD_GATEWAY, message),
+    }
+}
+
 #[macro_export]
 macro_rules! ttp_bail {
     ($($tokens:tt)*) => {
@@ -0,0 +1,178 @@
+//! Client for TTPs offering a FHIR operation in the style of $pseudonymize, which pseudonymizes an original value in a domain
+use fhir_sdk::r4b::resources::{Consent, Parameters, ParametersParameter, ParametersParameterValue, Patient};
+use fhir_sdk::r4b::types::Identifier;
+use reqwest::{StatusCode, Url};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::config::ClientBuilderExt;
+use crate::fhir::PatientExt;
+use crate::{ttp_bail, CLIENT};
+
+use super::{ProjectDomain, TtpError};
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct FhirTtpConfig {
+    #[clap(flatten)]
+    pub base: super::TtpInner,
+
+    // Identifier of the patient of a data request whose value is pseudonymized
+    #[clap(long = "ttp-fhir-original-system", env = "TTP_FHIR_ORIGINAL_SYSTEM")]
+    original_system: String,
+
+    // Operation creating (or returning) the pseudonym of an original value, relative to the ttp url
+    #[clap(long = "ttp-fhir-pseudonymize-operation", env = "TTP_FHIR_PSEUDONYMIZE_OPERATION", default_value = "$pseudonymize")]
+    pseudonymize_operation: String,
+
+    // Operation returning the original value of a pseudonym, pseudonyms can't be resolved without it
+    #[clap(long = "ttp-fhir-resolve-operation", env = "TTP_FHIR_RESOLVE_OPERATION")]
+    resolve_operation: Option<String>,
+
+    // Names of the parameters for the domain (the project or exchange id system), the original value and the pseudonym.
+    // The original value and the pseudonym are read from parameters or their parts, as valueIdentifier or valueString.
+    #[clap(long = "ttp-fhir-domain-parameter", env = "TTP_FHIR_DOMAIN_PARAMETER", default_value = "target")]
+    domain_parameter: String,
+    #[clap(long = "ttp-fhir-original-parameter", env = "TTP_FHIR_ORIGINAL_PARAMETER", default_value = "original")]
+    original_parameter: String,
+    #[clap(long = "ttp-fhir-pseudonym-parameter", env = "TTP_FHIR_PSEUDONYM_PARAMETER", default_value = "pseudonym")]
+    pseudonym_parameter: String,
+}
+
+impl std::ops::Deref for FhirTtpConfig {
+    type Target = super::TtpInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
+}
+
+impl FhirTtpConfig {
+    pub(super) async fn check_availability(&self) -> bool {
+        let request = match CLIENT.get(self.url.join("metadata").unwrap()).add_auth(&self.ttp_auth).await {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Unable to authenticate at the ttp: {e}");
+                return false;
+            }
+        };
+        request.send().await.is_ok_and(|res| res.status().is_success())
+    }
+
+    // Domains are created in the ttp by its operators, so every id system is accepted
+    pub(super) async fn check_idtype_available(&self, _idtype: &str) -> bool {
+        true
+    }
+
+    pub(super) async fn document_patient_consent(
+        &self,
+        _consent: &Consent,
+        _patient: &Patient,
+    ) -> Result<Option<String>, (StatusCode, &'static str)> {
+        // the consent must not be taken as documented, e.g. to be revoked there later
+        warn!("Refused to document consent, the ttp has no operation for consents");
+        Err((StatusCode::NOT_IMPLEMENTED, "The ttp can't document consents, data requests with a consent are not supported"))
+    }
+
+    pub(super) async fn request_project_pseudonym(
+        &self,
+        patient: Patient,
+        domain: &ProjectDomain,
+        exchange_id_system: &str,
+    ) -> Result<Patient, TtpError> {
+        let Some(original) = patient.get_identifier(&self.original_system).and_then(|i| i.value.clone()) else {
+            ttp_bail!("Patient has no identifier {} to pseudonymize", self.original_system);
+        };
+        let mut identifiers = Vec::new();
+        for system in [domain.project_id_system.as_str(), exchange_id_system] {
+            let params = self.parameters(system, &self.original_parameter, &original);
+            let response = self.call(&self.pseudonymize_operation, &params).await?;
+            let Some(pseudonym) = find_value(&response, &self.pseudonym_parameter) else {
+                ttp_bail!("Response did not contain a parameter {}: {response}", self.pseudonym_parameter);
+            };
+            identifiers.push(Some(Identifier::builder().system(system.to_owned()).value(pseudonym).build().unwrap()));
+        }
+        Ok(Patient::builder().identifier(identifiers).build().unwrap())
+    }
+
+    // The original value is the same for both domains, so the exchange id is the pseudonym of the original value of the project pseudonym
+    pub(super) async fn resolve_pseudonym(
+        &self,
+        project_pseudonym: &str,
+        domain: &ProjectDomain,
+        exchange_id_system: &str,
+    ) -> Result<Option<String>, TtpError> {
+        let Some(resolve_operation) = &self.resolve_operation else {
+            ttp_bail!("Resolving pseudonyms requires --ttp-fhir-resolve-operation");
+        };
+        let params = self.parameters(&domain.project_id_system, &self.pseudonym_parameter, project_pseudonym);
+        let response = self.call(resolve_operation, &params).await?;
+        let Some(original) = find_value(&response, &self.original_parameter) else {
+            return Ok(None);
+        };
+        let params = self.parameters(exchange_id_system, &self.original_parameter, &original);
+        let response = self.call(&self.pseudonymize_operation, &params).await?;
+        Ok(find_value(&response, &self.pseudonym_parameter))
+    }
+
+    fn parameters(&self, domain: &str, name: &str, value: &str) -> Parameters {
+        let parameter = |name: &str, value: &str| ParametersParameter::builder()
+            .name(name.into())
+            .value(ParametersParameterValue::String(value.into()))
+            .build()
+            .ok();
+        Parameters::builder()
+            .parameter(vec![parameter(&self.domain_parameter, domain), parameter(name, value)])
+            .build()
+            .unwrap()
+    }
+
+    async fn call(&self, operation: &str, params: &Parameters) -> Result<Value, TtpError> {
+        let url: Url = self.url.join(operation).map_err(|e| anyhow::anyhow!("Invalid operation {operation}: {e}"))?;
+        let res = CLIENT
+            .post(url)
+            .json(params)
+            .add_auth(&self.ttp_auth)
+            .await?
+            .send()
+            .await?;
+        if let Err(e) = res.error_for_status_ref() {
+            ttp_bail!("Error while calling {operation}: {e:#}\nBody was: {}", res.text().await.unwrap_or_else(|e| e.to_string()));
+        }
+        Ok(res.json().await?)
+    }
+}
+
+// Value of the first parameter with the name, searching the parts of parameters as well
+fn find_value(parameters: &Value, name: &str) -> Option<String> {
+    let parameters = parameters.get("parameter").or_else(|| parameters.get("part"))?.as_array()?;
+    parameters.iter().find_map(|parameter| {
+        let value = (parameter.get("name").and_then(Value::as_str) == Some(name))
+            .then(|| parameter.pointer("/valueIdentifier/value").or_else(|| parameter.get("valueString")))
+            .flatten()
+            .and_then(Value::as_str);
+        match value {
+            Some(value) => Some(value.to_owned()),
+            None => find_value(parameter, name),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::find_value;
+
+    #[test]
+    fn pseudonym_of_flat_and_nested_responses() {
+        let flat = json!({
+            "resourceType": "Parameters",
+            "parameter": [{"name": "pseudonym", "valueString": "PSN-1"}]
+        });
+        assert_eq!(find_value(&flat, "pseudonym").as_deref(), Some("PSN-1"));
+        let nested: Value = serde_json::from_str(include_str!("testdata/gateway_pseudonymize_allow_create_response.json")).unwrap();
+        assert_eq!(find_value(&nested, "pseudonym").as_deref(), Some("TA_8XTM2K4P"));
+        assert_eq!(find_value(&nested, "original").as_deref(), Some("1001000000022"));
+        assert_eq!(find_value(&nested, "psn"), None);
+    }
+}
@@ -1,19 +1,22 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::str::FromStr;
 
-use fhir_sdk::r4b::codes::AdministrativeGender;
-use fhir_sdk::r4b::resources::{Bundle, ParametersParameterValue, Resource};
+use fhir_sdk::r4b::codes::{AdministrativeGender, ConsentProvisionType, ConsentState};
+use fhir_sdk::r4b::resources::{ConsentProvision, ParametersParameterValue, QuestionnaireResponseItem, QuestionnaireResponseItemAnswer, QuestionnaireResponseItemAnswerValue};
 use fhir_sdk::r4b::resources::{
     Consent, Parameters, ParametersParameter, Patient, QuestionnaireResponse,
 };
-use fhir_sdk::r4b::types::Identifier;
+use fhir_sdk::r4b::types::{Coding, Identifier};
 use reqwest::Url;
+use serde_json::Value;
 
 use crate::config::ClientBuilderExt;
 use crate::{ttp_bail, CLIENT};
 use crate::fhir::PatientExt;
 
-use super::TtpError;
+use super::soap::{self, Element, Response};
+use super::{ProjectDomain, TtpError};
 
 #[derive(Debug, clap::Args, Clone)]
 pub struct GreifswaldConfig {
@@ -27,12 +30,47 @@ pub struct GreifswaldConfig {
     epix_domain: String,
 
     #[clap(long = "ttp-gw-gpas-domain", env = "TTP_GW_GPAS_DOMAIN")]
-    gpas_domain: String,
+    pub(super) gpas_domain: String,
 
+    // Whether E-PIX and gPAS are called via their SOAP web services or the operations of the TTP-FHIR gateway at the ttp url
+    #[clap(long = "ttp-gw-mode", env = "TTP_GW_MODE", value_enum, default_value = "soap")]
+    mode: GreifswaldMode,
+
+    // gPAS server for the SOAP mode, defaults to the ttp url
     #[clap(long = "ttp-gw-gpas-url", env = "TTP_GW_GPAS_URL")]
-    gpas_url: Url,
+    gpas_url: Option<Url>,
+
+    // TTP-FHIR gateway used for gICS, defaults to the ttp url
+    #[clap(long = "ttp-gw-gics-url", env = "TTP_GW_GICS_URL")]
+    gics_url: Option<Url>,
+
+    // gICS domain consents are documented in, consents can't be documented without it
+    #[clap(long = "ttp-gw-gics-domain", env = "TTP_GW_GICS_DOMAIN")]
+    gics_domain: Option<String>,
+
+    // Canonical url of the questionnaire of the gICS consent template, e.g. "https://ths-greifswald.de/fhir/gics/Questionnaire/MII|1.6.d"
+    #[clap(long = "ttp-gw-gics-template", env = "TTP_GW_GICS_TEMPLATE")]
+    gics_template: Option<String>,
+
+    // Code system of the consent policies in provisions that are modules of the consent template
+    #[clap(long = "ttp-gw-gics-policy-system", env = "TTP_GW_GICS_POLICY_SYSTEM", default_value = "urn:oid:2.16.840.1.113883.3.1937.777.24.5.3")]
+    gics_policy_system: String,
+
+    // gICS signer id type the project pseudonym is documented as
+    #[clap(long = "ttp-gw-gics-signer-id-type", env = "TTP_GW_GICS_SIGNER_ID_TYPE", default_value = "Pseudonym")]
+    gics_signer_id_type: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum GreifswaldMode {
+    Soap,
+    Fhir,
+}
+
+const EPIX_NAMESPACE: &str = "http://service.epix.ttp.icmvc.emau.org/";
+const GPAS_NAMESPACE: &str = "http://psn.ttp.ganimed.icmvc.emau.org/";
+const GICS_CONSENT_STATUS_SYSTEM: &str = "https://ths-greifswald.de/fhir/CodeSystem/gics/ConsentStatus";
+
 impl std::ops::Deref for GreifswaldConfig {
     type Target = super::TtpInner;
 
@@ -53,133 +91,138 @@ impl GreifswaldConfig {
     // https://www.ths-greifswald.de/wp-content/uploads/tools/fhirgw/ig/2024-3-0/ImplementationGuide-markdown-Einwilligungsmanagement-Operations-addConsent.html
     pub(super) async fn document_patient_consent(
         &self,
-        _consent: Consent,
+        consent: &Consent,
         patient: &Patient,
-    ) -> Result<Consent, TtpError> {
-        let url = self.url.join("/ttp-fhir/fhir/gics/$addConsent").unwrap();
-        let params = Parameters::builder()
+        domain: &ProjectDomain,
+    ) -> Result<(), TtpError> {
+        let url = self.gics_url.as_ref().unwrap_or(&self.url).join("ttp-fhir/fhir/gics/$addConsent").unwrap();
+        let params = self.add_consent_parameters(consent, patient, &domain.project_id_system)?;
+        let res = CLIENT
+            .post(url)
+            .json(&params)
+            .add_auth(&self.ttp_auth)
+            .await?
+            .send()
+            .await?;
+        if let Err(e) = res.error_for_status_ref() {
+            ttp_bail!("Error while sending consent: {e:#}\nBody was: {}", res.text().await.unwrap_or_else(|e| e.to_string()));
+        }
+        Ok(())
+    }
+
+    // The consent is documented as answers to the questionnaire of the gICS consent template. Every policy coded in a
+    // provision is a module of the template, which is accepted if the provision permits and declined otherwise.
+    fn add_consent_parameters(&self, consent: &Consent, patient: &Patient, project_id_system: &str) -> Result<Parameters, TtpError> {
+        let (Some(domain), Some(template)) = (&self.gics_domain, &self.gics_template) else {
+            ttp_bail!("Documenting consents in gICS requires --ttp-gw-gics-domain and --ttp-gw-gics-template");
+        };
+        let Some(signer_id) = patient.get_identifier(project_id_system).and_then(|i| i.value.clone()) else {
+            ttp_bail!("Patient has no identifier {project_id_system} to sign the consent with");
+        };
+
+        let mut modules = BTreeMap::new();
+        if let Some(provision) = &consent.provision {
+            // a withdrawn or otherwise inactive consent declines all modules
+            let active = consent.status == ConsentState::Active;
+            collect_policy_modules(provision, &self.gics_policy_system, active, &mut modules);
+        }
+        if modules.is_empty() {
+            ttp_bail!("Consent does not contain any policy of system {}", self.gics_policy_system);
+        }
+        let items = modules
+            .into_iter()
+            .map(|(policy, accepted)| {
+                let answer = Coding::builder()
+                    .system(GICS_CONSENT_STATUS_SYSTEM.into())
+                    .code(if accepted { "accepted" } else { "declined" }.into())
+                    .build()
+                    .unwrap();
+                QuestionnaireResponseItem::builder()
+                    .link_id(policy)
+                    .answer(vec![Some(
+                        QuestionnaireResponseItemAnswer::builder()
+                            .value(QuestionnaireResponseItemAnswerValue::Coding(answer))
+                            .build()
+                            .unwrap(),
+                    )])
+                    .build()
+                    .map(Some)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow::anyhow!("Unable to build consent questionnaire response: {e}"))?;
+        let mut questionnaire_response = QuestionnaireResponse::builder()
+            .status(fhir_sdk::r4b::codes::QuestionnaireResponseStatus::Completed)
+            .questionnaire(template.clone())
+            .item(items)
+            .build()
+            .unwrap();
+        questionnaire_response.authored = consent.date_time.clone();
+
+        let signer = Patient::builder()
+            .identifier(vec![Some(
+                Identifier::builder()
+                    .system(format!("https://ths-greifswald.de/fhir/gics/identifiers/{}", self.gics_signer_id_type))
+                    .value(signer_id)
+                    .build()
+                    .unwrap(),
+            )])
+            .build()
+            .unwrap();
+        Ok(Parameters::builder()
             .parameter(vec![
                 ParametersParameter::builder()
-                    .name("patient".into())
-                    .resource(patient.clone().into())
+                    .name("domain".into())
+                    .value(ParametersParameterValue::String(domain.clone()))
                     .build()
                     .ok(),
                 ParametersParameter::builder()
-                    .name("questionnaireResponse".into())
-                    .resource(
-                        QuestionnaireResponse::builder()
-                            .status(fhir_sdk::r4b::codes::QuestionnaireResponseStatus::Completed)
-                            .build()
-                            .unwrap()
-                            .into(),
-                    )
+                    .name("patient".into())
+                    .resource(signer.into())
                     .build()
                     .ok(),
                 ParametersParameter::builder()
-                    .name("domain".into())
-                    .value(ParametersParameterValue::String(
-                        self.gpas_domain.clone(),
-                    ))
+                    .name("questionnaireResponse".into())
+                    .resource(questionnaire_response.into())
                     .build()
                     .ok(),
-                // ParametersParameter::builder()
-                //     .name("documentReference".into())
-                //     .resource(
-                //         DocumentReference::builder()
-                //             // .content(vec![Some(DocumentReferenceContent::builder().attachment(a).build().unwrap())])
-                //             // .content(vec![None])
-                //             .contained(vec![consent.into()])
-                //             .status(fhir_sdk::r4b::codes::DocumentReferenceStatus::Current)
-                //             .build()
-                //             .unwrap()
-                //             .into(),
-                //     )
-                //     .build()
-                //     .ok(),
             ])
             .build()
-            .unwrap();
-        let res = CLIENT
-            .post(url)
-            .json(&params)
-            .add_auth(&self.ttp_auth)
-            .await?
-            .send()
-            .await?;
-        if let Err(e) = res.error_for_status_ref() {
-            ttp_bail!("Error while sending consent: {e:#}\nBody was: {}", res.text().await.unwrap_or_else(|e| e.to_string()));
-        }
-        let bundle = res
-            .json::<Bundle>()
-            .await?;
-        let Resource::Consent(c) = bundle
-            .0
-            .entry
-            .iter()
-            .flatten()
-            .next()
-            .unwrap()
-            .resource
-            .as_ref()
-            .cloned()
-            .unwrap()
-        else {
-            ttp_bail!("Bundle did not contain a consent resoucrce: {bundle:#?}")
-        };
-        Ok(c)
+            .unwrap())
     }
 
-    // https://www.ths-greifswald.de/wp-content/uploads/tools/fhirgw/ig/2024-3-0/ImplementationGuide-markdown-Pseudonymmanagement-Operations-pseudonymize.html
     pub(super) async fn request_project_pseudonym(
         &self,
         patient: Patient,
+        domain: &ProjectDomain,
         exchange_id_system: &str,
     ) -> Result<Patient, TtpError> {
-        let url = self.url.join("epix/epixService").unwrap();
-        let Self { epix_domain, source, .. } = self;
-        let patient_xml = patient_to_xml(&patient);
-        let soap_body = format!(r#"<?xml version="1.0" encoding="utf-8"?>
-            <soap:Envelope
-                xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/"
-                xmlns:ser="http://service.epix.ttp.icmvc.emau.org/">
-            <soap:Header/>
-            <soap:Body>
-                <ser:requestMPI>
-                    <domainName>{epix_domain}</domainName>
-                    <identity>
-                        {patient_xml}
-                    </identity>
-                    <sourceName>{source}</sourceName>
-                </ser:requestMPI>
-            </soap:Body>
-            </soap:Envelope>"#);
-        let res = CLIENT
-            .post(url)
-            .body(soap_body)
-            .add_auth(&self.ttp_auth)
-            .await?
-            .send()
-            .await?;
-        if let Err(e) = res.error_for_status_ref() {
-            ttp_bail!("Error while matching patient: {e:#}\nBody was: {}", res.text().await.unwrap_or_else(|e| e.to_string()));
+        let (match_status, mpi) = match self.mode {
+            GreifswaldMode::Soap => self.request_mpi_soap(&patient).await?,
+            GreifswaldMode::Fhir => self.request_mpi_fhir(&patient).await?,
+        };
+        if match_status.requires_clarification() {
+            return Err(TtpError::ClarificationRequired(format!(
+                "E-PIX reported {match_status} for the patient in domain {}, the identity has to be clarified in E-PIX",
+                self.epix_domain
+            )));
         }
-        let xml = res
-            .text()
-            .await?;
-        let Some(mpi) = extract_mpi(&xml) else {
-            ttp_bail!("Failed to get mpi from response: {xml}");
+        let Some(mpi) = mpi else {
+            ttp_bail!("E-PIX reported {match_status} but no mpi for the patient");
+        };
+        let psn = match self.mode {
+            GreifswaldMode::Soap => self.request_pseudonym_soap(&mpi, self.gpas_domain(domain)).await?,
+            GreifswaldMode::Fhir => self.request_pseudonym_fhir(&mpi, self.gpas_domain(domain)).await?,
         };
-        let psn = self.request_pseudonym(&mpi).await?;
         let patient = Patient::builder()
             .identifier(vec![
                 Some(Identifier::builder()
-                    .system(self.project_id_system.clone())
+                    .system(domain.project_id_system.clone())
                     .value(psn)
                     .build()
                     .unwrap()),
                 Some(Identifier::builder()
                     .system(exchange_id_system.to_owned())
-                    .value(mpi.into())
+                    .value(mpi)
                     .build()
                     .unwrap())
                 ])
@@ -188,126 +231,281 @@ impl GreifswaldConfig {
         Ok(patient)
     }
 
-    async fn request_pseudonym(&self, ident: &str) -> Result<String, TtpError> {
+    async fn request_mpi_soap(&self, patient: &Patient) -> Result<(MatchStatus, Option<String>), TtpError> {
+        let url = self.url.join("epix/epixService").unwrap();
+        let Self { epix_domain, source, .. } = self;
+        let request = soap::Request {
+            namespace: EPIX_NAMESPACE,
+            operation: "requestMPI",
+            parameters: vec![
+                Element::text("domainName", epix_domain),
+                Element::new("identity", patient_identity(patient)),
+                Element::text("sourceName", source),
+            ],
+        };
+        let response = request.send(url, &self.ttp_auth).await?;
+        Ok((match_status(&response)?, mpi(&response)?))
+    }
+
+    async fn request_pseudonym_soap(&self, ident: &str, gpas_domain: &str) -> Result<String, TtpError> {
         let url = self
             .gpas_url
+            .as_ref()
+            .unwrap_or(&self.url)
             .join("gpas/gpasService")
             .unwrap();
-        let Self { gpas_domain, .. } = self;
-        let xml_body = format!(r#"<?xml version="1.0" encoding="utf-8"?>
-            <soapenv:Envelope xmlns:soapenv="http://schemas.xmlsoap.org/soap/envelope/" xmlns:tns="http://psn.ttp.ganimed.icmvc.emau.org/">
-            <soapenv:Header/>
-            <soapenv:Body>
-                <tns:getOrCreatePseudonymFor>
-                    <!-- The parameters for the operation -->
-                    <value>{ident}</value>
-                    <domainName>{gpas_domain}</domainName>
-                </tns:getOrCreatePseudonymFor>
-            </soapenv:Body>
-            </soapenv:Envelope>
-        "#);
+        let request = soap::Request {
+            namespace: GPAS_NAMESPACE,
+            operation: "getOrCreatePseudonymFor",
+            parameters: vec![
+                Element::text("value", ident),
+                Element::text("domainName", gpas_domain),
+            ],
+        };
+        let response = request.send(url, &self.ttp_auth).await?;
+        pseudonym(&response)
+    }
+
+    // https://www.ths-greifswald.de/wp-content/uploads/tools/fhirgw/ig/2024-3-0/ImplementationGuide-markdown-Identitaetsmanagement-Operations-requestMPI.html
+    async fn request_mpi_fhir(&self, patient: &Patient) -> Result<(MatchStatus, Option<String>), TtpError> {
+        let url = self.url.join("ttp-fhir/fhir/epix/$requestMPI").unwrap();
+        // E-PIX only matches on the demographic data, identifiers of the data request are not part of the identity
+        let mut identity = Patient::builder().build().unwrap();
+        identity.name = patient.name.clone();
+        identity.gender = patient.gender;
+        identity.birth_date = patient.birth_date.clone();
+        identity.address = patient.address.clone();
+        let params = Parameters::builder()
+            .parameter(vec![
+                string_parameter("target", &self.epix_domain),
+                string_parameter("source", &self.source),
+                ParametersParameter::builder()
+                    .name("identity".into())
+                    .resource(identity.into())
+                    .build()
+                    .ok(),
+            ])
+            .build()
+            .unwrap();
+        let response = self.call_gateway(url, &params).await?;
+        gateway_match_result(&response)
+    }
+
+    // https://www.ths-greifswald.de/wp-content/uploads/tools/fhirgw/ig/2024-3-0/ImplementationGuide-markdown-Pseudonymmanagement-Operations-pseudonymize.html
+    async fn request_pseudonym_fhir(&self, ident: &str, gpas_domain: &str) -> Result<String, TtpError> {
+        let url = self.url.join("ttp-fhir/fhir/gpas/$pseudonymizeAllowCreate").unwrap();
+        let params = Parameters::builder()
+            .parameter(vec![
+                string_parameter("target", gpas_domain),
+                string_parameter("original", ident),
+            ])
+            .build()
+            .unwrap();
+        let response = self.call_gateway(url, &params).await?;
+        gateway_pseudonym(&response)
+    }
+
+    // The exchange id is the mpi the project pseudonym was created for in gPAS
+    pub(super) async fn resolve_pseudonym(&self, project_pseudonym: &str, domain: &ProjectDomain) -> Result<Option<String>, TtpError> {
+        match self.mode {
+            GreifswaldMode::Soap => self.resolve_pseudonym_soap(project_pseudonym, self.gpas_domain(domain)).await,
+            GreifswaldMode::Fhir => self.resolve_pseudonym_fhir(project_pseudonym, self.gpas_domain(domain)).await,
+        }
+    }
+
+    // Projects get their gPAS domain when they are loaded, domains without one are those of the configured project
+    fn gpas_domain<'a>(&'a self, domain: &'a ProjectDomain) -> &'a str {
+        domain.gpas_domain.as_deref().unwrap_or(&self.gpas_domain)
+    }
+
+    async fn resolve_pseudonym_soap(&self, project_pseudonym: &str, gpas_domain: &str) -> Result<Option<String>, TtpError> {
+        let url = self
+            .gpas_url
+            .as_ref()
+            .unwrap_or(&self.url)
+            .join("gpas/gpasService")
+            .unwrap();
+        let request = soap::Request {
+            namespace: GPAS_NAMESPACE,
+            operation: "getValueFor",
+            parameters: vec![
+                Element::text("psn", project_pseudonym),
+                Element::text("domainName", gpas_domain),
+            ],
+        };
+        match request.send(url, &self.ttp_auth).await {
+            Ok(response) => Ok(response.text(&["value"])?.filter(|value| !value.is_empty())),
+            Err(TtpError::SoapFault { exception: Some(exception), .. }) if exception == "UnknownValueException" => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // https://www.ths-greifswald.de/wp-content/uploads/tools/fhirgw/ig/2024-3-0/ImplementationGuide-markdown-Pseudonymmanagement-Operations-dePseudonymize.html
+    async fn resolve_pseudonym_fhir(&self, project_pseudonym: &str, gpas_domain: &str) -> Result<Option<String>, TtpError> {
+        let url = self.url.join("ttp-fhir/fhir/gpas/$dePseudonymize").unwrap();
+        let params = Parameters::builder()
+            .parameter(vec![
+                string_parameter("target", gpas_domain),
+                string_parameter("pseudonym", project_pseudonym),
+            ])
+            .build()
+            .unwrap();
+        let response = self.call_gateway(url, &params).await?;
+        Ok(gateway_original(&response))
+    }
+
+    async fn call_gateway(&self, url: Url, params: &Parameters) -> Result<Value, TtpError> {
         let res = CLIENT
             .post(url)
-            .body(xml_body)
+            .json(params)
             .add_auth(&self.ttp_auth)
             .await?
             .send()
             .await?;
         if let Err(e) = res.error_for_status_ref() {
-            ttp_bail!("Error requesting pseudonym: {e:#}\nBody was: {}", res.text().await.unwrap_or_else(|e| e.to_string()));
+            ttp_bail!("Error while calling the TTP-FHIR gateway: {e:#}\nBody was: {}", res.text().await.unwrap_or_else(|e| e.to_string()));
         }
-        let xml_res = res.text().await?;
-        let Some((_, psn_start)) = xml_res.split_once("<psn>") else {
-            ttp_bail!("Response did not contain a <psn> tag: {xml_res}");
-        };
-        let psn = psn_start.chars().take_while(|c| *c != '<').collect::<String>();
-        Ok(psn)
+        Ok(res.json().await?)
     }
 }
 
-fn extract_mpi(xml: &str) -> Option<&str> {
-    // 1. Find the start of the <mpiId> block and get everything after it.
-    xml.split_once("<mpiId>")?.1
-        // 2. From that remainder, find the end of the block and get everything before it.
-        .split_once("</mpiId>")?.0
-        // 3. In the isolated block, find the <value> tag and get everything after it.
-        .split_once("<value>")?.1
-        // 4. Finally, find the closing </value> tag and get the content before it.
-        .split_once("</value>")
-        .map(|(value, _)| value.trim())
+fn string_parameter(name: &str, value: &str) -> Option<ParametersParameter> {
+    ParametersParameter::builder()
+        .name(name.into())
+        .value(ParametersParameterValue::String(value.into()))
+        .build()
+        .ok()
 }
 
-fn patient_to_xml(patient: &Patient) -> impl Display {
-    struct PatientXml<'a> {
-        patient: &'a Patient,
-    }
-    impl Display for PatientXml<'_> {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            let patient = self.patient;
-            if let Some(name) = patient.name.first().and_then(Option::as_ref) {
-                if let Some(given) = name.given.first().and_then(Option::as_ref) {
-                    write!(f, "<firstName>{given}</firstName>")?;
-                }
-                if let Some(family) = &name.family {
-                    write!(f, "<lastName>{family}</lastName>")?;
-                }
-            }
-            if let Some(gender) = patient.gender {
-                let gender = match gender {
-                    AdministrativeGender::Female => 'F',
-                    AdministrativeGender::Male => 'M',
-                    AdministrativeGender::Other => 'O',
-                    AdministrativeGender::Unknown => 'U',
-                };
-                write!(f, "<gender>{gender}</gender>")?;
-            }
-            if let Some(birth_date) = patient.birth_date.as_ref() {
-                match birth_date {
-                    fhir_sdk::Date::Date(dt) => {
-                        write!(
-                            f,
-                            "<birthDate>{}-{:02}-{:02}T00:00:00</birthDate>",
-                            dt.year(),
-                            dt.month() as u8,
-                            dt.day()
-                        )?;
-                    }
-                    fhir_sdk::Date::YearMonth(year, month) => {
-                        write!(
-                            f,
-                            "<birthDate>{year}-{:02}-01T00:00:00</birthDate>",
-                            *month as u8
-                        )?;
-                    }
-                    fhir_sdk::Date::Year(year) => {
-                        write!(f, "<birthDate>{year}-01-01T00:00:00</birthDate>")?;
-                    }
-                }
-            }
-            if let Some(address) = patient.address.first().and_then(Option::as_ref) {
-                f.write_str("<contacts>")?;
-                if let Some(line) = address.line.first().and_then(Option::as_ref) {
-                    write!(f, "<street>{line}</street>")?;
-                }
-                if let Some(city) = &address.city {
-                    write!(f, "<city>{city}</city>")?;
-                }
-                if let Some(postal_code) = &address.postal_code {
-                    write!(f, "<zipCode>{postal_code}</zipCode>")?;
-                }
-                f.write_str("</contacts>")?;
-            }
-            if let Some(pat_id) = patient.get_identifier("pat-id").and_then(|i| i.value.as_ref()) {
-                write!(f, "<value1>{pat_id}</value1>")?;
-            }
-            Ok(())
+fn gateway_match_result(response: &Value) -> Result<(MatchStatus, Option<String>), TtpError> {
+    let match_status = parameter(response, "matchStatus")
+        .and_then(|match_status| match_status.pointer("/valueCoding/code"))
+        .and_then(Value::as_str)
+        .and_then(|code| code.parse().ok());
+    let Some(match_status) = match_status else {
+        ttp_bail!("Failed to get match status from response: {response}");
+    };
+    let mpi = parameter(response, "mpiId")
+        .and_then(|mpi| mpi.pointer("/valueIdentifier/value"))
+        .and_then(Value::as_str)
+        .map(ToOwned::to_owned);
+    Ok((match_status, mpi))
+}
+
+fn gateway_pseudonym(response: &Value) -> Result<String, TtpError> {
+    let psn = parameter(response, "pseudonym")
+        .and_then(|pseudonym| parameter(pseudonym, "pseudonym"))
+        .and_then(|pseudonym| pseudonym.pointer("/valueIdentifier/value"))
+        .and_then(Value::as_str);
+    match psn {
+        Some(psn) if !psn.is_empty() => Ok(psn.to_owned()),
+        _ => ttp_bail!("Response did not contain a pseudonym: {response}"),
+    }
+}
+
+// Unknown pseudonyms are returned without an original value
+fn gateway_original(response: &Value) -> Option<String> {
+    parameter(response, "pseudonym")
+        .and_then(|pseudonym| parameter(pseudonym, "original"))
+        .and_then(|original| original.pointer("/valueIdentifier/value"))
+        .and_then(Value::as_str)
+        .filter(|original| !original.is_empty())
+        .map(ToOwned::to_owned)
+}
+
+// Parameter (or part of a parameter) returned by an operation of the TTP-FHIR gateway
+fn parameter<'a>(parameters: &'a Value, name: &str) -> Option<&'a Value> {
+    parameters
+        .get("parameter")
+        .or_else(|| parameters.get("part"))
+        .and_then(Value::as_array)?
+        .iter()
+        .find(|parameter| parameter.get("name").and_then(Value::as_str) == Some(name))
+}
+
+// Policies coded in the provision and its nested provisions, nested provisions override their parents
+fn collect_policy_modules(provision: &ConsentProvision, policy_system: &str, active: bool, modules: &mut BTreeMap<String, bool>) {
+    let permit = active && provision.r#type != Some(ConsentProvisionType::Deny);
+    let policies = provision.code.iter().flatten()
+        .flat_map(|code| code.coding.iter().flatten())
+        .filter(|coding| coding.system.as_deref() == Some(policy_system))
+        .filter_map(|coding| coding.code.clone());
+    for policy in policies {
+        modules.insert(policy, permit);
+    }
+    for nested in provision.provision.iter().flatten() {
+        collect_policy_modules(nested, policy_system, active, modules);
+    }
+}
+
+fn match_status(response: &Response) -> Result<MatchStatus, TtpError> {
+    match response.text(&["return", "matchStatus"])?.map(|status| status.parse()) {
+        Some(Ok(match_status)) => Ok(match_status),
+        _ => ttp_bail!("Failed to get match status from response: {}", response.0),
+    }
+}
+
+// The mpi of the matched person, possible matches contain further mpis which must not be taken
+fn mpi(response: &Response) -> Result<Option<String>, TtpError> {
+    Ok(response.text(&["return", "person", "mpiId", "value"])?.filter(|mpi| !mpi.is_empty()))
+}
+
+fn pseudonym(response: &Response) -> Result<String, TtpError> {
+    match response.text(&["psn"])? {
+        Some(psn) if !psn.is_empty() => Ok(psn),
+        _ => ttp_bail!("Response did not contain a pseudonym: {}", response.0),
+    }
+}
+
+// Identity of the patient as E-PIX expects it in requestMPI
+fn patient_identity(patient: &Patient) -> Vec<Element> {
+    let mut identity = Vec::new();
+    if let Some(name) = patient.name.first().and_then(Option::as_ref) {
+        if let Some(given) = name.given.first().and_then(Option::as_ref) {
+            identity.push(Element::text("firstName", given));
+        }
+        if let Some(family) = &name.family {
+            identity.push(Element::text("lastName", family));
         }
     }
-    PatientXml { patient }
+    if let Some(gender) = patient.gender {
+        let gender = match gender {
+            AdministrativeGender::Female => 'F',
+            AdministrativeGender::Male => 'M',
+            AdministrativeGender::Other => 'O',
+            AdministrativeGender::Unknown => 'U',
+        };
+        identity.push(Element::text("gender", gender));
+    }
+    if let Some(birth_date) = patient.birth_date.as_ref() {
+        let birth_date = match birth_date {
+            fhir_sdk::Date::Date(dt) => format!("{}-{:02}-{:02}T00:00:00", dt.year(), dt.month() as u8, dt.day()),
+            fhir_sdk::Date::YearMonth(year, month) => format!("{year}-{:02}-01T00:00:00", *month as u8),
+            fhir_sdk::Date::Year(year) => format!("{year}-01-01T00:00:00"),
+        };
+        identity.push(Element::text("birthDate", birth_date));
+    }
+    if let Some(address) = patient.address.first().and_then(Option::as_ref) {
+        let mut contact = Vec::new();
+        if let Some(line) = address.line.first().and_then(Option::as_ref) {
+            contact.push(Element::text("street", line));
+        }
+        if let Some(city) = &address.city {
+            contact.push(Element::text("city", city));
+        }
+        if let Some(postal_code) = &address.postal_code {
+            contact.push(Element::text("zipCode", postal_code));
+        }
+        identity.push(Element::new("contacts", contact));
+    }
+    if let Some(pat_id) = patient.get_identifier("pat-id").and_then(|i| i.value.as_ref()) {
+        identity.push(Element::text("value1", pat_id));
+    }
+    identity
 }
 
 /// Taken from: https://simplifier.net/packages/ths-greifswald.ttp-fhir-gw/2024.1.1/files/2432769
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum MatchStatus {
     ExternalMatch,
     Match,
@@ -319,6 +517,32 @@ enum MatchStatus {
     PossibleMatch,
 }
 
+impl MatchStatus {
+    // E-PIX didn't link the identity to exactly one person, so taking the returned mpi could mix up patients
+    fn requires_clarification(self) -> bool {
+        matches!(self, Self::PossibleMatch | Self::MultipleMatch | Self::MatchError)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ExternalMatch => "EXTERNAL_MATCH",
+            Self::Match => "MATCH",
+            Self::MatchError => "MATCH_ERROR",
+            Self::MultipleMatch => "MULTIPLE_MATCH",
+            Self::NoMatch => "NO_MATCH",
+            Self::PerfectMatch => "PERFECT_MATCH",
+            Self::PerfectMatchWithUpdate => "PERFECT_MATCH_WITH_UPDATE",
+            Self::PossibleMatch => "POSSIBLE_MATCH",
+        }
+    }
+}
+
+impl Display for MatchStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl FromStr for MatchStatus {
     type Err = ();
 
@@ -349,48 +573,139 @@ mod tests {
         },
         time::Date,
     };
+    use serde_json::json;
 
-    #[tokio::test]
-    #[ignore = "Unclear how we proceed here as it does not seem to accept a Consent resource"]
-    async fn test_document_patient_consent() {
-        let ttp = GreifswaldConfig {
-            gpas_url: "https://demo.ths-greifswald.de".parse().unwrap(),
+    fn demo_ttp(project_id_system: &str, gpas_domain: &str) -> GreifswaldConfig {
+        GreifswaldConfig {
+            mode: GreifswaldMode::Soap,
+            gpas_url: None,
             base: TtpInner {
                 url: "https://demo.ths-greifswald.de".parse().unwrap(),
-                project_id_system: "MII".into(),
+                project_id_system: project_id_system.into(),
                 ttp_auth: Auth::None,
             },
             source: "dummy_safe_source".into(),
             epix_domain: "Demo".into(),
-            gpas_domain: "MII".into(),
-        };
+            gpas_domain: gpas_domain.into(),
+            gics_url: None,
+            gics_domain: Some("MII".into()),
+            gics_template: Some("https://ths-greifswald.de/fhir/gics/Questionnaire/MII|1.6.d".into()),
+            gics_policy_system: "urn:oid:2.16.840.1.113883.3.1937.777.24.5.3".into(),
+            gics_signer_id_type: "Pseudonym".into(),
+        }
+    }
+
+    fn mii_consent() -> Consent {
+        serde_json::from_value(json!({
+            "resourceType": "Consent",
+            "status": "active",
+            "scope": {"coding": [{"system": "http://terminology.hl7.org/CodeSystem/consentscope", "code": "research"}]},
+            "category": [{"coding": [{"system": "http://loinc.org", "code": "57016-8"}]}],
+            "dateTime": "2025-09-01",
+            "provision": {
+                "type": "deny",
+                "provision": [
+                    {"type": "permit", "code": [{"coding": [{"system": "urn:oid:2.16.840.1.113883.3.1937.777.24.5.3", "code": "2.16.840.1.113883.3.1937.777.24.5.3.8"}]}]},
+                    {"type": "deny", "code": [{"coding": [{"system": "urn:oid:2.16.840.1.113883.3.1937.777.24.5.3", "code": "2.16.840.1.113883.3.1937.777.24.5.3.14"}]}]}
+                ]
+            }
+        })).unwrap()
+    }
+
+    #[test]
+    fn consent_provisions_are_template_modules() {
+        let ttp = demo_ttp("MII", "MII");
+        let patient = Patient::builder()
+            .identifier(vec![Some(Identifier::builder().system("MII".into()).value("psn".into()).build().unwrap())])
+            .build()
+            .unwrap();
+        let params = serde_json::to_value(ttp.add_consent_parameters(&mii_consent(), &patient, "MII").unwrap()).unwrap();
+        let questionnaire_response = &params["parameter"][2]["resource"];
+        assert_eq!(params["parameter"][1]["resource"]["identifier"][0]["system"], "https://ths-greifswald.de/fhir/gics/identifiers/Pseudonym");
+        assert_eq!(questionnaire_response["questionnaire"], "https://ths-greifswald.de/fhir/gics/Questionnaire/MII|1.6.d");
+        assert_eq!(questionnaire_response["authored"], "2025-09-01");
+        let answers = questionnaire_response["item"].as_array().unwrap().iter()
+            .map(|item| (item["linkId"].as_str().unwrap(), item["answer"][0]["valueCoding"]["code"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(answers, [("2.16.840.1.113883.3.1937.777.24.5.3.14", "declined"), ("2.16.840.1.113883.3.1937.777.24.5.3.8", "accepted")]);
+
+        let mut withdrawn = mii_consent();
+        withdrawn.status = ConsentState::Inactive;
+        let params = serde_json::to_value(ttp.add_consent_parameters(&withdrawn, &patient, "MII").unwrap()).unwrap();
+        assert!(params["parameter"][2]["resource"]["item"].as_array().unwrap().iter()
+            .all(|item| item["answer"][0]["valueCoding"]["code"] == "declined"));
+    }
+
+    #[tokio::test]
+    #[ignore = "Requires the MII consent template in the gICS domain of the demo server"]
+    async fn test_document_patient_consent() {
+        let ttp = demo_ttp("MII", "MII");
+        let domain = ProjectDomain { project_id_system: "MII".into(), gpas_domain: None };
+        let patient = ttp.request_project_pseudonym(fake_patient(), &domain, "test").await.unwrap();
         ttp.document_patient_consent(
-            Consent::builder()
+            &Consent::builder()
                 .status(ConsentState::Active)
                 .scope(CodeableConcept::builder().build().unwrap())
                 .category(vec![])
+                .provision(mii_consent().provision.clone().unwrap())
                 .build()
                 .unwrap(),
-            &fake_patient(),
+            &patient,
+            &domain,
         )
         .await
         .unwrap();
     }
 
+    #[test]
+    fn match_status_of_epix_response() {
+        let response = Response(include_str!("testdata/epix_possible_match_response.xml").to_owned());
+        let match_status = match_status(&response).unwrap();
+        assert_eq!(match_status, MatchStatus::PossibleMatch);
+        assert!(match_status.requires_clarification());
+        assert!(!"PERFECT_MATCH".parse::<MatchStatus>().unwrap().requires_clarification());
+    }
+
+    #[test]
+    fn mpi_and_pseudonym_of_recorded_responses() {
+        let response = Response(include_str!("testdata/epix_request_mpi_response.xml").to_owned());
+        assert_eq!(match_status(&response).unwrap(), MatchStatus::PerfectMatch);
+        assert_eq!(mpi(&response).unwrap().as_deref(), Some("1001000000022"));
+        let response = Response(include_str!("testdata/gpas_get_or_create_pseudonym_response.xml").to_owned());
+        assert_eq!(pseudonym(&response).unwrap(), "TA_8XTM2K4P");
+        let response = Response(include_str!("testdata/gpas_get_value_for_response.xml").to_owned());
+        assert_eq!(response.text(&["value"]).unwrap().as_deref(), Some("1001000000022"));
+    }
+
+    #[test]
+    fn match_result_and_pseudonym_of_gateway_responses() {
+        let response = serde_json::from_str(include_str!("testdata/gateway_request_mpi_response.json")).unwrap();
+        let (match_status, mpi) = gateway_match_result(&response).unwrap();
+        assert_eq!(match_status, MatchStatus::Match);
+        assert_eq!(mpi.as_deref(), Some("1001000000022"));
+        let response = serde_json::from_str(include_str!("testdata/gateway_pseudonymize_allow_create_response.json")).unwrap();
+        assert_eq!(gateway_pseudonym(&response).unwrap(), "TA_8XTM2K4P");
+        let response = serde_json::from_str(include_str!("testdata/gateway_de_pseudonymize_response.json")).unwrap();
+        assert_eq!(gateway_original(&response).as_deref(), Some("1001000000022"));
+    }
+
+    #[test]
+    fn identity_is_escaped() {
+        let mut patient = fake_patient();
+        patient.name[0].as_mut().unwrap().family = Some("Müller & <Söhne>".into());
+        let identity = patient_identity(&patient).iter().map(ToString::to_string).collect::<String>();
+        assert_eq!(
+            identity,
+            "<firstName>Max</firstName><lastName>Müller &amp; &lt;Söhne&gt;</lastName><gender>M</gender><birthDate>2015-04-01T00:00:00</birthDate>\
+             <contacts><street>Foostr. 5</street><city>Speyer</city><zipCode>67346</zipCode></contacts>"
+        );
+    }
+
     #[tokio::test]
     async fn test_request_project_pseudonym() {
-        let ttp = GreifswaldConfig {
-            gpas_url: "https://demo.ths-greifswald.de".parse().unwrap(),
-            base: TtpInner {
-                url: "https://demo.ths-greifswald.de".parse().unwrap(),
-                project_id_system: "Transferstelle A".into(),
-                ttp_auth: Auth::None,
-            },
-            source: "dummy_safe_source".into(),
-            epix_domain: "Demo".into(),
-            gpas_domain: "Transferstelle A".into(),
-        };
-        dbg!(ttp.request_project_pseudonym(fake_patient(), "test")
+        let ttp = demo_ttp("Transferstelle A", "Transferstelle A");
+        let domain = ProjectDomain { project_id_system: "Transferstelle A".into(), gpas_domain: None };
+        dbg!(ttp.request_project_pseudonym(fake_patient(), &domain, "test")
             .await
             .unwrap());
     }
@@ -0,0 +1,270 @@
+//! Pseudonymization without an external TTP, pseudonyms are derived by transFAIR and kept in its database
+use std::{str::FromStr, sync::OnceLock};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use fhir_sdk::r4b::resources::{Consent, Patient};
+use fhir_sdk::r4b::types::Identifier;
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use serde_json::Value;
+use sha2::Sha256;
+use sqlx::{Pool, Sqlite};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{config::Secret, ttp_bail};
+
+use super::{ProjectDomain, TtpError};
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct LocalConfig {
+    // defines the identifier to safe in the project database
+    #[clap(long, env)]
+    pub project_id_system: String,
+
+    #[clap(long = "ttp-local-mode", env = "TTP_LOCAL_MODE", value_enum, default_value = "hmac")]
+    mode: LocalMode,
+
+    // Secret key of the HMACs of the identifying fields and of the pseudonyms, required in random mode as well. With
+    // another key, patients are no longer recognized and get new pseudonyms.
+    #[clap(long = "ttp-local-key", env = "TTP_LOCAL_KEY")]
+    key: Secret,
+
+    // Fields of the patient identifying it, e.g. "family,given,birthDate,gender" or "identifier:<system>"
+    #[clap(long = "ttp-local-fields", env = "TTP_LOCAL_FIELDS", value_delimiter = ',', default_value = "family,given,birthDate,gender")]
+    fields: Vec<PatientField>,
+
+    // the database of transFAIR, set once it is connected
+    #[clap(skip)]
+    database_pool: OnceLock<Pool<Sqlite>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LocalMode {
+    Hmac,
+    Random,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatientField {
+    Identifier(String),
+    Family,
+    Given,
+    BirthDate,
+    Gender,
+}
+
+impl FromStr for PatientField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "family" => Ok(Self::Family),
+            "given" => Ok(Self::Given),
+            "birthDate" => Ok(Self::BirthDate),
+            "gender" => Ok(Self::Gender),
+            _ => match s.strip_prefix("identifier:") {
+                Some(system) if !system.is_empty() => Ok(Self::Identifier(system.to_owned())),
+                _ => Err(anyhow!("Unknown patient field '{s}', expected one of family, given, birthDate, gender, identifier:<system>")),
+            },
+        }
+    }
+}
+
+impl PatientField {
+    fn value(&self, patient: &Value) -> Option<String> {
+        let value = match self {
+            Self::Identifier(system) => patient
+                .get("identifier")
+                .and_then(Value::as_array)?
+                .iter()
+                .find(|identifier| identifier.get("system").and_then(Value::as_str) == Some(system.as_str()))?
+                .get("value"),
+            Self::Family => patient.pointer("/name/0/family"),
+            Self::Given => patient.pointer("/name/0/given/0"),
+            Self::BirthDate => patient.get("birthDate"),
+            Self::Gender => patient.get("gender"),
+        };
+        // spelling of names differs between systems, e.g. in capitalization
+        value
+            .and_then(Value::as_str)
+            .map(|value| value.trim().to_lowercase())
+            .filter(|value| !value.is_empty())
+    }
+}
+
+impl LocalConfig {
+    pub(super) fn use_database(&self, database_pool: &Pool<Sqlite>) {
+        let _ = self.database_pool.set(database_pool.clone());
+    }
+
+    pub(super) async fn check_availability(&self) -> bool {
+        self.database_pool.get().is_some()
+    }
+
+    // Pseudonyms are derived for any domain
+    pub(super) async fn check_idtype_available(&self, _idtype: &str) -> bool {
+        true
+    }
+
+    pub(super) async fn document_patient_consent(
+        &self,
+        _consent: &Consent,
+        _patient: &Patient,
+    ) -> Result<Option<String>, (StatusCode, &'static str)> {
+        // the consent is still stored with the data request and checked for every delivery
+        warn!("Consent is not documented, there is no external ttp");
+        Ok(None)
+    }
+
+    pub(super) async fn request_project_pseudonym(
+        &self,
+        patient: Patient,
+        domain: &ProjectDomain,
+        exchange_id_system: &str,
+    ) -> Result<Patient, TtpError> {
+        let original = self.original(&patient)?;
+        let mut identifiers = Vec::new();
+        for system in [domain.project_id_system.as_str(), exchange_id_system] {
+            let pseudonym = self.pseudonym(system, &original).await?;
+            identifiers.push(Some(Identifier::builder().system(system.to_owned()).value(pseudonym).build().unwrap()));
+        }
+        Ok(Patient::builder().identifier(identifiers).build().unwrap())
+    }
+
+    pub(super) async fn resolve_pseudonym(
+        &self,
+        project_pseudonym: &str,
+        domain: &ProjectDomain,
+        exchange_id_system: &str,
+    ) -> Result<Option<String>, TtpError> {
+        let database_pool = self.database()?;
+        let exchange_id = sqlx::query_scalar!(
+            "SELECT exchange.pseudonym FROM local_pseudonyms project JOIN local_pseudonyms exchange ON exchange.original = project.original
+            WHERE project.domain = $1 AND project.pseudonym = $2 AND exchange.domain = $3",
+            domain.project_id_system, project_pseudonym, exchange_id_system
+        ).fetch_optional(database_pool).await.map_err(anyhow::Error::from)?;
+        Ok(exchange_id)
+    }
+
+    // HMAC-SHA256 of the identifying fields of the patient, kept as original in the local_pseudonyms table (whose
+    // migration still calls it a SHA-256 digest). It is keyed with TTP_LOCAL_KEY, as a plain digest of names and birth
+    // dates could be reversed by hashing candidates from a dictionary.
+    fn original(&self, patient: &Patient) -> Result<String, TtpError> {
+        let patient = serde_json::to_value(patient).expect("Resources can always be serialized");
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.expose().as_bytes()).expect("HMAC accepts keys of any length");
+        for field in &self.fields {
+            let Some(value) = field.value(&patient) else {
+                ttp_bail!("Patient has no {field:?} to derive pseudonyms from");
+            };
+            mac.update(value.as_bytes());
+            mac.update(&[0x1f]);
+        }
+        Ok(hex(&mac.finalize().into_bytes()))
+    }
+
+    // Pseudonyms are derived once per domain and original, later requests return the stored pseudonym
+    async fn pseudonym(&self, domain: &str, original: &str) -> Result<String, TtpError> {
+        let database_pool = self.database()?;
+        let pseudonym = match self.mode {
+            LocalMode::Hmac => hmac_pseudonym(&self.key.expose(), domain, original),
+            LocalMode::Random => Uuid::new_v4().to_string(),
+        };
+        let now = Utc::now().timestamp_millis();
+        sqlx::query!(
+            "INSERT INTO local_pseudonyms (domain, original, pseudonym, created) VALUES ($1, $2, $3, $4) ON CONFLICT (domain, original) DO NOTHING",
+            domain, original, pseudonym, now
+        ).execute(database_pool).await.map_err(anyhow::Error::from)?;
+        let pseudonym = sqlx::query_scalar!(
+            "SELECT pseudonym FROM local_pseudonyms WHERE domain = $1 AND original = $2",
+            domain, original
+        ).fetch_one(database_pool).await.map_err(anyhow::Error::from)?;
+        Ok(pseudonym)
+    }
+
+    fn database(&self) -> Result<&Pool<Sqlite>, TtpError> {
+        match self.database_pool.get() {
+            Some(database_pool) => Ok(database_pool),
+            None => ttp_bail!("Local ttp is not connected to the database"),
+        }
+    }
+}
+
+// The domain is part of the message, so the pseudonyms of a patient differ between domains
+fn hmac_pseudonym(key: &str, domain: &str, original: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(domain.as_bytes());
+    mac.update(&[0]);
+    mac.update(original.as_bytes());
+    // 128 bits are enough to avoid collisions
+    hex(&mac.finalize().into_bytes()[..16])
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use fhir_sdk::r4b::resources::Patient;
+    use serde_json::json;
+    use sqlx::SqlitePool;
+
+    use crate::fhir::PatientExt;
+
+    use crate::ttp::ProjectDomain;
+
+    use super::{LocalConfig, LocalMode};
+
+    fn local_ttp(mode: LocalMode) -> LocalConfig {
+        LocalConfig {
+            project_id_system: "PROJECT".into(),
+            mode,
+            key: "secret".parse().unwrap(),
+            fields: vec!["family".parse().unwrap(), "birthDate".parse().unwrap()],
+            database_pool: Default::default(),
+        }
+    }
+
+    fn patient(family: &str) -> Patient {
+        serde_json::from_value(json!({
+            "resourceType": "Patient",
+            "name": [{"family": family, "given": ["Max"]}],
+            "birthDate": "2015-04-01"
+        })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pseudonyms_are_stable_and_resolvable() {
+        let database_pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&database_pool).await.unwrap();
+        let domain = ProjectDomain { project_id_system: "PROJECT".into(), gpas_domain: None };
+        for mode in [LocalMode::Hmac, LocalMode::Random] {
+            let ttp = local_ttp(mode);
+            ttp.use_database(&database_pool);
+            let first = ttp.request_project_pseudonym(patient("Mustermann"), &domain, "EXCHANGE").await.unwrap();
+            // names are compared case insensitive
+            let second = ttp.request_project_pseudonym(patient(" mustermann"), &domain, "EXCHANGE").await.unwrap();
+            assert_eq!(first.identifier, second.identifier);
+            let other = ttp.request_project_pseudonym(patient("Musterfrau"), &domain, "EXCHANGE").await.unwrap();
+            assert_ne!(first.identifier, other.identifier);
+
+            let project_id = first.get_identifier("PROJECT").unwrap().value.as_deref().unwrap();
+            let exchange_id = first.get_identifier("EXCHANGE").unwrap().value.clone();
+            assert_ne!(Some(project_id), exchange_id.as_deref());
+            assert_eq!(ttp.resolve_pseudonym(project_id, &domain, "EXCHANGE").await.unwrap(), exchange_id);
+            assert_eq!(ttp.resolve_pseudonym("unknown", &domain, "EXCHANGE").await.unwrap(), None);
+            sqlx::query!("DELETE FROM local_pseudonyms").execute(&database_pool).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn originals_depend_on_key() {
+        let ttp = local_ttp(LocalMode::Random);
+        let other_key = LocalConfig { key: "other secret".parse().unwrap(), ..local_ttp(LocalMode::Random) };
+        let original = ttp.original(&patient("Mustermann")).unwrap();
+        assert_eq!(original.len(), 64);
+        assert_ne!(original, other_key.original(&patient("Mustermann")).unwrap());
+    }
+}
@@ -4,9 +4,9 @@ use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace, warn};
 
-use crate::{fhir::PatientExt, ttp_bail, CLIENT};
+use crate::{config::Secret, fhir::PatientExt, ttp_bail, CLIENT};
 
-use super::TtpError;
+use super::{ProjectDomain, TtpError};
 
 #[derive(Debug, clap::Args, Clone)]
 pub struct MlConfig {
@@ -17,7 +17,7 @@ pub struct MlConfig {
         long = "ttp-ml-api-key",
         env = "TTP_ML_API_KEY"
     )]
-    pub api_key: String,
+    pub api_key: Secret,
 }
 
 impl std::ops::Deref for MlConfig {
@@ -68,7 +68,7 @@ impl MlConfig {
 
         let supported_ids = CLIENT
             .get(idtypes_endpoint)
-            .header("mainzellisteApiKey", &self.api_key)
+            .header("mainzellisteApiKey", self.api_key.expose())
             .send()
             .await
             .map_err(|err| {
@@ -96,17 +96,18 @@ impl MlConfig {
     pub(super) async fn request_project_pseudonym(
         &self,
         patient: Patient,
+        domain: &ProjectDomain,
         exchange_id_system: &str,
     ) -> Result<Patient, TtpError> {
         let patient = patient
           .add_id_request(exchange_id_system.to_owned())
-          .add_id_request(self.project_id_system.clone());
+          .add_id_request(domain.project_id_system.clone());
         // TODO: Need to ensure request for project pseudonym is included
         let patients_endpoint = self.url.join("fhir/Patient").unwrap();
 
         let response = CLIENT
             .post(patients_endpoint)
-            .header("mainzellisteApiKey", &self.api_key)
+            .header("mainzellisteApiKey", self.api_key.expose())
             .json(&patient)
             .send()
             .await?;
@@ -121,53 +122,85 @@ impl MlConfig {
         Ok(patient)
     }
 
-    async fn create_mainzelliste_session(&self) -> Result<Session, (StatusCode, &'static str)> {
+    // Reads the patient with the project pseudonym using a readPatients token
+    pub(super) async fn resolve_pseudonym(
+        &self,
+        project_pseudonym: &str,
+        domain: &ProjectDomain,
+        exchange_id_system: &str,
+    ) -> Result<Option<String>, TtpError> {
+        let session = self.create_mainzelliste_session().await?;
+        let data = serde_json::json!({
+            "searchIds": [{"idType": domain.project_id_system, "idString": project_pseudonym}],
+            "resultIds": [exchange_id_system],
+        });
+        let token = self
+            .create_mainzelliste_token(session, TokenType::ReadPatients, Some(data))
+            .await?;
+        let mut patients_endpoint = self.url.join("patients").unwrap();
+        patients_endpoint.query_pairs_mut().append_pair("tokenId", &token.id);
+
+        let response = CLIENT
+            .get(patients_endpoint)
+            .header("mainzellisteApiKey", self.api_key.expose())
+            .send()
+            .await?;
+        // Mainzelliste rejects tokens searching for unknown ids
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if let Err(err) = response.error_for_status_ref() {
+            ttp_bail!("Error reading patient from Mainzelliste: {err:#}\n Got response: {}", response.text().await?);
+        }
+        let patients = response.json::<Vec<ReadPatient>>().await?;
+        Ok(patients
+            .into_iter()
+            .flat_map(|patient| patient.ids)
+            .find(|id| id.id_type == exchange_id_system)
+            .map(|id| id.id_string))
+    }
+
+    async fn create_mainzelliste_session(&self) -> Result<Session, TtpError> {
         let sessions_endpoint = self.url.join("sessions").unwrap();
         debug!("Requesting Session from Mainzelliste: {}", sessions_endpoint);
 
-        CLIENT
+        let response = CLIENT
             .post(sessions_endpoint)
-            .header("mainzellisteApiKey", &self.api_key)
+            .header("mainzellisteApiKey", self.api_key.expose())
             .send()
-            .await
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Unable to create mainzelliste session. Ensure configured apiKey is valid."))
-            .unwrap()
-            .json::<Session>()
-            .await
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Unable to parse mainzelliste session."))
+            .await?;
+        if let Err(err) = response.error_for_status_ref() {
+            ttp_bail!("Unable to create Mainzelliste session, ensure the configured api key is valid: {err:#}\n Got response: {}", response.text().await?);
+        }
+        Ok(response.json::<Session>().await?)
     }
 
-    async fn create_mainzelliste_token(&self, session: Session, token_type: TokenType) -> Result<Token, (StatusCode, &'static str)> {
+    async fn create_mainzelliste_token(&self, session: Session, token_type: TokenType, data: Option<serde_json::Value>) -> Result<Token, TtpError> {
         debug!("create_mainzelliste_token called with: session={:?} token_type={:?}", session, token_type);
         let tokens_endpoint = format!("{}tokens", session.uri);
-        debug!("Requesting addConsent Token from Mainzelliste: {}", tokens_endpoint);
+        debug!("Requesting {:?} Token from Mainzelliste: {}", token_type, tokens_endpoint);
         let token_request = TokenRequest {
-            token_type
+            token_type,
+            data
         };
-        CLIENT
+        let response = CLIENT
             .post(tokens_endpoint)
-            .header("mainzellisteApiKey", &self.api_key)
+            .header("mainzellisteApiKey", self.api_key.expose())
             .json(&token_request)
             .send()
-            .await
-            .map_err(|err| {
-                warn!("Unable to get token from mainzelliste: {}", err);
-                (StatusCode::INTERNAL_SERVER_ERROR, "Unable to get Token from Mainzelliste")
-            })
-            .unwrap()
-            .json::<Token>()
-            .await
-            .map_err(|err| {
-                warn!("Unable to parse token returned by mainzelliste: {}", err);
-                (StatusCode::INTERNAL_SERVER_ERROR, "Unable to Parse Token from Mainzelliste: {}")
-            }) 
+            .await?;
+        if let Err(err) = response.error_for_status_ref() {
+            ttp_bail!("Unable to get token from Mainzelliste: {err:#}\n Got response: {}", response.text().await?);
+        }
+        Ok(response.json::<Token>().await?)
     }
 
+    // Returns the id Mainzelliste assigned to the consent
     pub(super) async fn document_patient_consent(
         &self,
         consent: &Consent,
         patient: &Patient,
-    ) -> Result<(), (StatusCode, &'static str)> {
+    ) -> Result<Option<String>, (StatusCode, &'static str)> {
         if consent.patient.is_some() {
             warn!(
                 "Received request with consent that already contained patient identifiers: {:?}",
@@ -178,7 +211,12 @@ impl MlConfig {
                 "Given Consent Resource already contained identifiers.",
             ));
         }
+        self.add_consent(consent, patient)
+            .await
+            .map_err(|e| super::consent_error(e, "Unable to document consent in Mainzelliste"))
+    }
 
+    async fn add_consent(&self, consent: &Consent, patient: &Patient) -> Result<Option<String>, TtpError> {
         // TODO: Needs to be done outside of mainzelliste.rs
         let mut consent_with_identifiers = consent.clone(); 
         // TODO: Mainzelliste currently says the identifier don't have a proper system, maybe need to add the URL?
@@ -186,39 +224,53 @@ impl MlConfig {
 
         trace!("{:?}", consent_with_identifiers);
 
-        let session = self.create_mainzelliste_session().await?; 
-        
-        let token = self.create_mainzelliste_token(session, TokenType::AddConsent).await?;
+        let session = self.create_mainzelliste_session().await?;
+
+        let token = self.create_mainzelliste_token(session, TokenType::AddConsent, None).await?;
 
         let consent_endpoint = self.url.join("fhir/Consent").unwrap();
 
-        let response: reqwest::Response = CLIENT
+        let response = CLIENT
             .post(consent_endpoint)
             .header("Authorization", format!("MainzellisteToken {}", token.id))
             .header("Content-Type", "application/fhir+json")
             .json(&consent_with_identifiers)
             .send()
-            .await
-            .map_err(|err| {
-                warn!("Unable to add Consent to TTP: {}", err);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to add Consent to TTP",
-                );
-            })
-            .unwrap();
-
-        debug!("Response from TTP for Consent request: status={} text={}", response.status(), response.text().await.unwrap());
+            .await?;
 
-        Ok(())
+        let status = response.status();
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(ToOwned::to_owned);
+        let body = response.text().await?;
+        debug!("Response from TTP for Consent request: status={} text={}", status, body);
+        if !status.is_success() {
+            ttp_bail!("Mainzelliste refused consent with {status}: {body}");
+        }
+        Ok(consent_id(&body, location.as_deref()))
     }
 }
 
+// Id of the created consent, from the returned resource or else the location of the created resource
+fn consent_id(body: &str, location: Option<&str>) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|consent| consent.get("id")?.as_str().map(ToOwned::to_owned))
+        .or_else(|| {
+            // e.g. https://mainzelliste/fhir/Consent/123/_history/1
+            let path = location?.split("/_history/").next()?;
+            path.rsplit('/').next().filter(|id| !id.is_empty()).map(ToOwned::to_owned)
+        })
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all="camelCase")]
 enum TokenType {
     // #[serde(with = "TokenType")] 
-    AddConsent
+    AddConsent,
+    ReadPatients
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -230,10 +282,36 @@ struct Token {
 #[derive(Serialize, Deserialize, Debug)]
 struct TokenRequest {
     #[serde(rename = "type")]
-    token_type: TokenType
+    token_type: TokenType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>
+}
+
+#[derive(Deserialize, Debug)]
+struct ReadPatient {
+    ids: Vec<MlId>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct MlId {
+    id_type: String,
+    id_string: String
 }
 
 #[derive(Deserialize, Debug)]
 struct Session {
     uri: String 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::consent_id;
+
+    #[test]
+    fn consent_id_of_response() {
+        assert_eq!(consent_id(r#"{"resourceType": "Consent", "id": "42"}"#, None).as_deref(), Some("42"));
+        assert_eq!(consent_id("", Some("https://mainzelliste/fhir/Consent/42/_history/1")).as_deref(), Some("42"));
+        assert_eq!(consent_id("", None), None);
+    }
+}
@@ -0,0 +1,184 @@
+//! Minimal SOAP 1.1 client for the web services of the Greifswald tools (E-PIX and gPAS)
+use std::fmt::Display;
+
+use reqwest::{header, Url};
+
+use crate::{config::{Auth, ClientBuilderExt}, ttp_bail, CLIENT};
+
+use super::TtpError;
+
+const ENVELOPE_NAMESPACE: &str = "http://schemas.xmlsoap.org/soap/envelope/";
+
+// Element of a request, text is escaped when written
+pub struct Element {
+    name: &'static str,
+    content: Content,
+}
+
+enum Content {
+    Text(String),
+    Children(Vec<Element>),
+}
+
+impl Element {
+    pub fn text(name: &'static str, text: impl Display) -> Self {
+        Self { name, content: Content::Text(text.to_string()) }
+    }
+
+    pub fn new(name: &'static str, children: Vec<Element>) -> Self {
+        Self { name, content: Content::Children(children) }
+    }
+}
+
+impl Display for Element {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{}>", self.name)?;
+        match &self.content {
+            Content::Text(text) => write!(f, "{}", Escaped(text))?,
+            Content::Children(children) => {
+                for child in children {
+                    write!(f, "{child}")?;
+                }
+            }
+        }
+        write!(f, "</{}>", self.name)
+    }
+}
+
+struct Escaped<'a>(&'a str);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&apos;")?,
+                c => write!(f, "{c}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Call of a web service operation with its parameters
+pub struct Request {
+    pub namespace: &'static str,
+    pub operation: &'static str,
+    pub parameters: Vec<Element>,
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { namespace, operation, parameters } = self;
+        write!(
+            f,
+            r#"<?xml version="1.0" encoding="utf-8"?><soapenv:Envelope xmlns:soapenv="{ENVELOPE_NAMESPACE}" xmlns:op="{namespace}"><soapenv:Header/><soapenv:Body><op:{operation}>"#
+        )?;
+        for parameter in parameters {
+            write!(f, "{parameter}")?;
+        }
+        write!(f, "</op:{operation}></soapenv:Body></soapenv:Envelope>")
+    }
+}
+
+impl Request {
+    // Sends the request and returns the response envelope. Faults are returned as errors, even if sent with a success status.
+    pub async fn send(&self, url: Url, auth: &Auth) -> Result<Response, TtpError> {
+        let res = CLIENT
+            .post(url)
+            .header(header::CONTENT_TYPE, "text/xml; charset=utf-8")
+            .body(self.to_string())
+            .add_auth(auth)
+            .await?
+            .send()
+            .await?;
+        let status = res.status();
+        let response = Response(res.text().await?);
+        response.check_fault()?;
+        if !status.is_success() {
+            ttp_bail!("Error while calling {}: {status}\nBody was: {}", self.operation, response.0);
+        }
+        Ok(response)
+    }
+}
+
+pub struct Response(pub String);
+
+impl Response {
+    // Text of the element at the path (of local names) below the response element of the operation
+    pub fn text(&self, path: &[&str]) -> Result<Option<String>, TtpError> {
+        let document = self.parse()?;
+        let Some(mut node) = body(&document).and_then(|body| body.first_element_child()) else {
+            ttp_bail!("Response did not contain a body: {}", self.0);
+        };
+        for name in path {
+            match node.children().find(|child| child.tag_name().name() == *name) {
+                Some(child) => node = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(node.text().map(|text| text.trim().to_owned()))
+    }
+
+    fn check_fault(&self) -> Result<(), TtpError> {
+        // responses to failed requests aren't always xml, they are reported by their status
+        let Ok(document) = self.parse() else {
+            return Ok(());
+        };
+        let Some(fault) = body(&document).and_then(|body| body.children().find(|child| child.has_tag_name((ENVELOPE_NAMESPACE, "Fault")))) else {
+            return Ok(());
+        };
+        let text = |name: &str| fault
+            .children()
+            .find(|child| child.tag_name().name() == name)
+            .and_then(|child| child.text())
+            .map(|text| text.trim().to_owned())
+            .unwrap_or_default();
+        // the web services of the Greifswald tools name the exception in the detail, e.g. UnknownDomainException
+        let exception = fault
+            .children()
+            .find(|child| child.tag_name().name() == "detail")
+            .and_then(|detail| detail.first_element_child())
+            .map(|exception| exception.tag_name().name().to_owned());
+        Err(TtpError::SoapFault { code: text("faultcode"), message: text("faultstring"), exception })
+    }
+
+    fn parse(&self) -> Result<roxmltree::Document<'_>, TtpError> {
+        roxmltree::Document::parse(&self.0).map_err(|e| TtpError::Other(anyhow::anyhow!("Invalid xml in response: {e}\nBody was: {}", self.0)))
+    }
+}
+
+fn body<'a, 'input>(document: &'a roxmltree::Document<'input>) -> Option<roxmltree::Node<'a, 'input>> {
+    document.root_element().children().find(|child| child.has_tag_name((ENVELOPE_NAMESPACE, "Body")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ttp::TtpError;
+
+    use super::{Element, Request, Response};
+
+    #[test]
+    fn escape_text() {
+        let request = Request {
+            namespace: "http://service.epix.ttp.icmvc.emau.org/",
+            operation: "requestMPI",
+            parameters: vec![Element::new("identity", vec![Element::text("lastName", "Müller & <Söhne>")])],
+        };
+        assert!(request.to_string().contains("<op:requestMPI><identity><lastName>Müller &amp; &lt;Söhne&gt;</lastName></identity></op:requestMPI>"));
+    }
+
+    #[test]
+    fn parse_fault() {
+        let response = Response(include_str!("testdata/gpas_fault_response.xml").to_owned());
+        let Err(TtpError::SoapFault { code, message, exception }) = response.check_fault() else {
+            panic!("Fault was not recognized");
+        };
+        assert_eq!(code, "soap:Server");
+        assert_eq!(message, "domain Transferstelle B not found");
+        assert_eq!(exception.as_deref(), Some("UnknownDomainException"));
+    }
+}